@@ -0,0 +1,46 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// Hashes every file under `prompts/` so the binary knows which revision of each prompt it
+/// embeds. Writes `prompt_hashes.rs` to `OUT_DIR`, included by `src/prompts.rs`.
+fn main() {
+    println!("cargo:rerun-if-changed=prompts");
+
+    let root = Path::new("prompts");
+    let mut hashes = Vec::new();
+    collect_hashes(root, root, &mut hashes);
+    hashes.sort();
+
+    let entries: String = hashes
+        .iter()
+        .map(|(name, hash)| format!("    ({:?}, {:?}),\n", name, hash))
+        .collect();
+    let source = format!(
+        "/// Hex SHA-256 of every prompt file, by path relative to `prompts/`.\n\
+         static PROMPT_HASHES: &[(&str, &str)] = &[\n{}];\n",
+        entries
+    );
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR not set");
+    fs::write(Path::new(&out_dir).join("prompt_hashes.rs"), source)
+        .expect("Failed to write prompt hashes");
+}
+
+fn collect_hashes(root: &Path, dir: &Path, hashes: &mut Vec<(String, String)>) {
+    for entry in fs::read_dir(dir).expect("Failed to read prompts directory") {
+        let path = entry.expect("Failed to read prompts directory").path();
+        if path.is_dir() {
+            collect_hashes(root, &path, hashes);
+            continue;
+        }
+        let contents = fs::read(&path).expect("Failed to read prompt file");
+        let name = path
+            .strip_prefix(root)
+            .expect("Prompt file outside the prompts directory")
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        hashes.push((name, format!("{:x}", Sha256::digest(&contents))));
+    }
+}
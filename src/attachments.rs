@@ -0,0 +1,415 @@
+use crate::llm::Media;
+use crate::object_store::ObjectStore;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Most images a single tell may carry.
+pub const MAX_IMAGES: usize = 4;
+/// Default for `TEAL_MAX_IMAGE_BYTES`. Lambda caps request payloads at 6 MB, and base64 adds a
+/// third on top.
+const DEFAULT_MAX_IMAGE_BYTES: usize = 4 * 1024 * 1024;
+/// Default for `TEAL_MAX_AUDIO_BYTES`, bounded by the same payload limit.
+const DEFAULT_MAX_AUDIO_BYTES: usize = 4 * 1024 * 1024;
+
+/// Image types Gemini accepts as inline data, with their file extensions.
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/webp", "webp"),
+    ("image/heic", "heic"),
+    ("image/heif", "heif"),
+];
+
+/// Audio types Gemini accepts as inline data, with their file extensions.
+const AUDIO_TYPES: &[(&str, &str)] = &[
+    ("audio/wav", "wav"),
+    ("audio/mp3", "mp3"),
+    ("audio/aiff", "aiff"),
+    ("audio/aac", "aac"),
+    ("audio/ogg", "ogg"),
+    ("audio/flac", "flac"),
+];
+
+/// What an attachment holds. Each kind has its own accepted types.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediaKind {
+    Image,
+    Audio,
+}
+
+impl MediaKind {
+    /// Used in object keys and error messages.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Image => "image",
+            MediaKind::Audio => "audio",
+        }
+    }
+
+    fn types(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            MediaKind::Image => IMAGE_TYPES,
+            MediaKind::Audio => AUDIO_TYPES,
+        }
+    }
+
+    fn sniff(&self, data: &[u8]) -> Option<&'static str> {
+        match self {
+            MediaKind::Image => sniff_image_type(data),
+            MediaKind::Audio => sniff_audio_type(data),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AttachmentError {
+    TooMany {
+        max: usize,
+    },
+    TooLarge {
+        kind: MediaKind,
+        max_bytes: usize,
+    },
+    /// The data is not one of the supported types; holds the declared type, if any.
+    UnsupportedType {
+        kind: MediaKind,
+        declared: Option<String>,
+    },
+    /// The declared content type doesn't match the data.
+    TypeMismatch {
+        kind: MediaKind,
+        declared: String,
+        detected: String,
+    },
+}
+
+impl fmt::Display for AttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttachmentError::TooMany { max } => write!(f, "At most {} images per tell", max),
+            AttachmentError::TooLarge {
+                kind: MediaKind::Image,
+                max_bytes,
+            } => write!(f, "Images must be at most {} bytes", max_bytes),
+            AttachmentError::TooLarge {
+                kind: MediaKind::Audio,
+                max_bytes,
+            } => write!(f, "Audio clips must be at most {} bytes", max_bytes),
+            AttachmentError::UnsupportedType {
+                kind,
+                declared: Some(declared),
+            } => write!(f, "Unsupported {} type '{}'", kind.as_str(), declared),
+            AttachmentError::UnsupportedType {
+                kind,
+                declared: None,
+            } => write!(f, "Unsupported {} type", kind.as_str()),
+            AttachmentError::TypeMismatch {
+                kind,
+                declared,
+                detected,
+            } => write!(
+                f,
+                "{} declared as '{}' but contains '{}'",
+                match kind {
+                    MediaKind::Image => "Image",
+                    MediaKind::Audio => "Audio",
+                },
+                declared,
+                detected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AttachmentError {}
+
+/// A validated upload, held in memory until the tell is stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    pub kind: MediaKind,
+    pub content_type: String,
+    pub data: Bytes,
+}
+
+impl Attachment {
+    /// Validates an uploaded image. The type is detected from the data itself; a declared
+    /// `content_type` must agree with it.
+    pub fn image(
+        content_type: Option<&str>,
+        data: Bytes,
+        max_bytes: usize,
+    ) -> Result<Self, AttachmentError> {
+        Self::validate(MediaKind::Image, content_type, data, max_bytes)
+    }
+
+    /// Validates an uploaded voice clip, the same way as [`Attachment::image`].
+    pub fn audio(
+        content_type: Option<&str>,
+        data: Bytes,
+        max_bytes: usize,
+    ) -> Result<Self, AttachmentError> {
+        Self::validate(MediaKind::Audio, content_type, data, max_bytes)
+    }
+
+    fn validate(
+        kind: MediaKind,
+        content_type: Option<&str>,
+        data: Bytes,
+        max_bytes: usize,
+    ) -> Result<Self, AttachmentError> {
+        if data.len() > max_bytes {
+            return Err(AttachmentError::TooLarge { kind, max_bytes });
+        }
+        let declared = content_type.map(normalize_content_type);
+        let detected = kind
+            .sniff(&data)
+            .ok_or_else(|| AttachmentError::UnsupportedType {
+                kind,
+                declared: declared.clone(),
+            })?;
+        if let Some(declared) = declared {
+            if declared != detected {
+                return Err(AttachmentError::TypeMismatch {
+                    kind,
+                    declared,
+                    detected: detected.to_string(),
+                });
+            }
+        }
+        Ok(Self {
+            kind,
+            content_type: detected.to_string(),
+            data,
+        })
+    }
+
+    pub fn media(&self) -> Media {
+        Media {
+            mime_type: self.content_type.clone(),
+            data: self.data.clone(),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        self.kind
+            .types()
+            .iter()
+            .find(|(content_type, _)| *content_type == self.content_type)
+            .map(|(_, extension)| *extension)
+            .unwrap_or("bin")
+    }
+}
+
+/// Where an attachment was stored, kept on the tell.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttachmentRef {
+    pub key: String,
+    pub content_type: String,
+    pub size: usize,
+}
+
+/// Upper bound for a single image, from `TEAL_MAX_IMAGE_BYTES`.
+pub fn max_image_bytes() -> usize {
+    std::env::var("TEAL_MAX_IMAGE_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_IMAGE_BYTES)
+}
+
+/// Upper bound for a voice clip, from `TEAL_MAX_AUDIO_BYTES`.
+pub fn max_audio_bytes() -> usize {
+    std::env::var("TEAL_MAX_AUDIO_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_AUDIO_BYTES)
+}
+
+/// Lowercases and drops parameters, and maps common aliases onto the types Gemini expects.
+fn normalize_content_type(content_type: &str) -> String {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    match essence.as_str() {
+        "image/jpg" => "image/jpeg".to_string(),
+        "audio/mpeg" => "audio/mp3".to_string(),
+        "audio/x-wav" | "audio/wave" => "audio/wav".to_string(),
+        "audio/x-aiff" => "audio/aiff".to_string(),
+        "audio/x-flac" => "audio/flac".to_string(),
+        _ => essence,
+    }
+}
+
+/// Detects the image type from its leading magic bytes.
+fn sniff_image_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Some("image/jpeg");
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some("image/png");
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return match &data[8..12] {
+            b"heic" | b"heix" | b"heim" | b"heis" => Some("image/heic"),
+            b"mif1" | b"msf1" => Some("image/heif"),
+            _ => None,
+        };
+    }
+    None
+}
+
+/// Detects the audio type from its leading magic bytes. MPEG frames are told apart by their
+/// layer bits: AAC (ADTS) frames have none set.
+fn sniff_audio_type(data: &[u8]) -> Option<&'static str> {
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WAVE" {
+        return Some("audio/wav");
+    }
+    if data.len() >= 12 && &data[..4] == b"FORM" && matches!(&data[8..12], b"AIFF" | b"AIFC") {
+        return Some("audio/aiff");
+    }
+    if data.starts_with(b"OggS") {
+        return Some("audio/ogg");
+    }
+    if data.starts_with(b"fLaC") {
+        return Some("audio/flac");
+    }
+    if data.starts_with(b"ID3") {
+        return Some("audio/mp3");
+    }
+    match data {
+        [0xFF, b, ..] if b & 0xF6 == 0xF0 => Some("audio/aac"),
+        [0xFF, b, ..] if b & 0xE0 == 0xE0 && b & 0x06 != 0 => Some("audio/mp3"),
+        _ => None,
+    }
+}
+
+/// Stores the attachments of tell `tid` under `tells/<tid>/` and returns their references.
+pub async fn store_attachments(
+    store: &dyn ObjectStore,
+    tid: &str,
+    attachments: &[Attachment],
+) -> anyhow::Result<Vec<AttachmentRef>> {
+    let mut refs = Vec::with_capacity(attachments.len());
+    for (i, attachment) in attachments.iter().enumerate() {
+        let key = format!(
+            "tells/{}/{}-{}.{}",
+            tid,
+            attachment.kind.as_str(),
+            i,
+            attachment.extension()
+        );
+        store
+            .put(&key, &attachment.content_type, attachment.data.clone())
+            .await?;
+        refs.push(AttachmentRef {
+            key,
+            content_type: attachment.content_type.clone(),
+            size: attachment.data.len(),
+        });
+    }
+    Ok(refs)
+}
+
+#[cfg(test)]
+pub const PNG_BYTES: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+#[cfg(test)]
+pub const WAV_BYTES: &[u8] = b"RIFF\x24\0\0\0WAVEfmt ";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object_store::FsObjectStore;
+
+    #[test]
+    fn test_image_detects_type() {
+        let image = Attachment::image(None, Bytes::from_static(PNG_BYTES), 1024).unwrap();
+        assert_eq!(image.content_type, "image/png");
+
+        let jpeg = Attachment::image(
+            Some("image/JPG"),
+            Bytes::from_static(&[0xFF, 0xD8, 0xFF, 0xE0]),
+            1024,
+        )
+        .unwrap();
+        assert_eq!(jpeg.content_type, "image/jpeg");
+
+        let webp = Attachment::image(None, Bytes::from_static(b"RIFF\0\0\0\0WEBPVP8 "), 1024);
+        assert_eq!(webp.unwrap().content_type, "image/webp");
+    }
+
+    #[test]
+    fn test_image_validation_errors() {
+        assert_eq!(
+            Attachment::image(None, Bytes::from_static(PNG_BYTES), 4),
+            Err(AttachmentError::TooLarge {
+                kind: MediaKind::Image,
+                max_bytes: 4
+            })
+        );
+        assert_eq!(
+            Attachment::image(Some("image/gif"), Bytes::from_static(b"GIF89a"), 1024),
+            Err(AttachmentError::UnsupportedType {
+                kind: MediaKind::Image,
+                declared: Some("image/gif".to_string())
+            })
+        );
+        assert_eq!(
+            Attachment::image(Some("image/jpeg"), Bytes::from_static(PNG_BYTES), 1024),
+            Err(AttachmentError::TypeMismatch {
+                kind: MediaKind::Image,
+                declared: "image/jpeg".to_string(),
+                detected: "image/png".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_audio_detects_type() {
+        let wav = Attachment::audio(Some("audio/x-wav"), Bytes::from_static(WAV_BYTES), 1024);
+        assert_eq!(wav.unwrap().content_type, "audio/wav");
+
+        for (data, expected) in [
+            (&b"ID3\x04\0"[..], "audio/mp3"),
+            (&[0xFF, 0xFB, 0x90, 0x64][..], "audio/mp3"),
+            (&[0xFF, 0xF1, 0x50, 0x80][..], "audio/aac"),
+            (&b"OggS\0\x02"[..], "audio/ogg"),
+            (&b"fLaC\0\0"[..], "audio/flac"),
+        ] {
+            let audio = Attachment::audio(None, Bytes::copy_from_slice(data), 1024).unwrap();
+            assert_eq!(audio.content_type, expected);
+            assert_eq!(audio.kind, MediaKind::Audio);
+        }
+
+        let error = Attachment::audio(None, Bytes::from_static(PNG_BYTES), 1024).unwrap_err();
+        assert_eq!(error.to_string(), "Unsupported audio type");
+    }
+
+    #[tokio::test]
+    async fn test_store_attachments() {
+        let root = std::env::temp_dir().join(format!("teal-objects-{}", uuid::Uuid::new_v4()));
+        let store = FsObjectStore::new(&root);
+        let image = Attachment::image(None, Bytes::from_static(PNG_BYTES), 1024).unwrap();
+
+        let refs = store_attachments(&store, "t1", &[image]).await.unwrap();
+        assert_eq!(
+            refs,
+            vec![AttachmentRef {
+                key: "tells/t1/image-0.png".to_string(),
+                content_type: "image/png".to_string(),
+                size: PNG_BYTES.len(),
+            }]
+        );
+        assert_eq!(
+            store.get(&refs[0].key).await.unwrap(),
+            Some(Bytes::from_static(PNG_BYTES))
+        );
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
@@ -0,0 +1,174 @@
+/// Default for `TEAL_CONTEXT_TOKENS`: room for the mood, a dozen summaries and a handful of
+/// exchanges, well below any model's input limit.
+const DEFAULT_CONTEXT_TOKENS: usize = 1500;
+/// Tokens a provider adds around each conversation turn for its role markers.
+pub const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+/// Shortest useful truncation; below this a text is dropped rather than cut.
+const MIN_TRUNCATED_TOKENS: usize = 8;
+/// Appended to truncated text.
+const ELLIPSIS: &str = "…";
+
+/// Upper bound for the past conversations sent with a tell, from `TEAL_CONTEXT_TOKENS`.
+pub fn context_tokens() -> usize {
+    std::env::var("TEAL_CONTEXT_TOKENS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CONTEXT_TOKENS)
+}
+
+fn word_tokens(word: &str) -> usize {
+    let ascii = word.chars().filter(char::is_ascii).count();
+    let other = word.chars().count() - ascii;
+    (ascii.div_ceil(4) + other).max(1)
+}
+
+/// Estimates the tokens `text` takes without calling a tokenizer: a token per 4 ASCII characters
+/// of each word, and one per other character. Subword tokenizers do better on common words, so
+/// this errs on the high side, which is the safe one for a budget.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.split_whitespace().map(word_tokens).sum()
+}
+
+/// `text` if it takes at most `max_tokens`, or else its longest start that does, cut between words
+/// and ended with an ellipsis. `None` when that would be too short to be useful.
+pub fn truncate_to_tokens(text: &str, max_tokens: usize) -> Option<String> {
+    if estimate_tokens(text) <= max_tokens {
+        return Some(text.to_string());
+    }
+    // The ellipsis is glued to the last word, adding a token to it.
+    let mut available = max_tokens.checked_sub(1)?;
+    let mut words = Vec::new();
+    for word in text.split_whitespace() {
+        let tokens = word_tokens(word);
+        if tokens > available {
+            break;
+        }
+        available -= tokens;
+        words.push(word);
+    }
+    if max_tokens - 1 - available < MIN_TRUNCATED_TOKENS {
+        return None;
+    }
+    Some(format!("{}{}", words.join(" "), ELLIPSIS))
+}
+
+/// What is left of a token budget while sections are filled by priority.
+#[derive(Debug)]
+pub struct TokenBudget {
+    remaining: usize,
+}
+
+impl TokenBudget {
+    pub fn new(max_tokens: usize) -> Self {
+        Self {
+            remaining: max_tokens,
+        }
+    }
+
+    /// Sets aside `tokens` for fixed text, such as the wording around the values.
+    pub fn reserve(&mut self, tokens: usize) {
+        self.remaining = self.remaining.saturating_sub(tokens);
+    }
+
+    /// Takes `text` whole if it fits, plus `overhead` tokens. Text that must not be cut, like a
+    /// single word, goes through here.
+    pub fn take_whole(&mut self, text: &str, overhead: usize) -> Option<String> {
+        let tokens = estimate_tokens(text) + overhead;
+        if tokens > self.remaining {
+            return None;
+        }
+        self.remaining -= tokens;
+        Some(text.to_string())
+    }
+
+    /// Takes `text` whole if it fits, plus `overhead` tokens, and otherwise as much of it as
+    /// fits. `None` once the budget is used up.
+    pub fn take(&mut self, text: &str, overhead: usize) -> Option<String> {
+        if let Some(text) = self.take_whole(text, overhead) {
+            return Some(text);
+        }
+        let truncated = truncate_to_tokens(text, self.remaining.checked_sub(overhead)?)?;
+        self.remaining -= estimate_tokens(&truncated) + overhead;
+        Some(truncated)
+    }
+
+    /// Takes a pair of texts that only make sense together, such as a tell and its answer, each
+    /// with `overhead` tokens. When both don't fit whole, each gets half of what remains.
+    pub fn take_pair(
+        &mut self,
+        first: &str,
+        second: &str,
+        overhead: usize,
+    ) -> Option<(String, String)> {
+        let tokens = estimate_tokens(first) + estimate_tokens(second) + 2 * overhead;
+        if tokens <= self.remaining {
+            self.remaining -= tokens;
+            return Some((first.to_string(), second.to_string()));
+        }
+        let mut half = TokenBudget::new(self.remaining / 2);
+        let first = half.take(first, overhead)?;
+        let mut other_half = TokenBudget::new(self.remaining - self.remaining / 2);
+        let second = other_half.take(second, overhead)?;
+        self.remaining = half.remaining + other_half.remaining;
+        Some((first, second))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("I ran a marathon today"), 1 + 1 + 1 + 2 + 2);
+        assert_eq!(estimate_tokens("Hoy corrí"), 1 + 2);
+        assert_eq!(estimate_tokens("今日は走った"), 6);
+    }
+
+    #[test]
+    fn test_truncate_to_tokens() {
+        let text = "a b c d e f g h i j";
+        assert_eq!(truncate_to_tokens(text, 9).unwrap(), "a b c d e f g h…");
+        assert_eq!(estimate_tokens("a b c d e f g h…"), 9);
+        assert_eq!(truncate_to_tokens(text, 8), None);
+        assert_eq!(truncate_to_tokens(text, 0), None);
+        assert_eq!(truncate_to_tokens(text, 10).unwrap(), text);
+    }
+
+    #[test]
+    fn test_take_fills_by_priority() {
+        let mut budget = TokenBudget::new(20);
+        budget.reserve(2);
+        assert_eq!(budget.take_whole("calm", 0).unwrap(), "calm");
+        assert_eq!(budget.remaining, 17);
+        assert_eq!(
+            budget.take_whole("too long to take whole at all now", 10),
+            None
+        );
+
+        let text = "a b c d e f g h i j k l m n o p q r s t";
+        assert_eq!(
+            budget.take(text, 1).unwrap(),
+            "a b c d e f g h i j k l m n o…"
+        );
+        assert_eq!(budget.remaining, 0);
+        assert_eq!(budget.take(text, 1), None);
+    }
+
+    #[test]
+    fn test_take_pair_splits_what_remains() {
+        let mut budget = TokenBudget::new(40);
+        let (tell, answer) = budget
+            .take_pair(&"word ".repeat(30), &"fine ".repeat(30), 4)
+            .unwrap();
+        assert!(tell.starts_with("word") && tell.ends_with('…'));
+        assert!(answer.starts_with("fine") && answer.ends_with('…'));
+        assert_eq!(estimate_tokens(&tell), 16);
+        assert_eq!(estimate_tokens(&answer), 16);
+        assert_eq!(budget.remaining, 0);
+
+        let mut budget = TokenBudget::new(20);
+        assert_eq!(budget.take_pair(&"word ".repeat(30), "reply", 4), None);
+    }
+}
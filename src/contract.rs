@@ -0,0 +1,243 @@
+use crate::gemini::GeminiTellResponse;
+use crate::metrics;
+
+/// Word limit for `summary` and `user_state`, as stated in `prompts/tell.md`.
+const MAX_SUMMARY_WORDS: usize = 12;
+
+/// A rule of the `prompts/tell.md` response contract that a reply broke.
+#[derive(Debug, PartialEq)]
+pub enum Violation {
+    SummaryTooLong { words: usize },
+    UserStateTooLong { words: usize },
+    MoodNotSingleWord,
+    QuestionInAnswer,
+}
+
+impl Violation {
+    /// Stable identifier, used as the metric dimension.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Violation::SummaryTooLong { .. } => "summary_too_long",
+            Violation::UserStateTooLong { .. } => "user_state_too_long",
+            Violation::MoodNotSingleWord => "mood_not_single_word",
+            Violation::QuestionInAnswer => "question_in_answer",
+        }
+    }
+
+    /// What the rule asks for, stated against the reply that broke it.
+    pub fn instruction(&self) -> String {
+        match self {
+            Violation::SummaryTooLong { words } => format!(
+                "`summary` has {} words; use at most {}.",
+                words, MAX_SUMMARY_WORDS
+            ),
+            Violation::UserStateTooLong { words } => format!(
+                "`user_state` has {} words; use at most {}.",
+                words, MAX_SUMMARY_WORDS
+            ),
+            Violation::MoodNotSingleWord => "`mood` must be one single word.".to_string(),
+            Violation::QuestionInAnswer => "`answer` must not ask any questions.".to_string(),
+        }
+    }
+}
+
+fn word_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+fn is_single_word(text: &str) -> bool {
+    let mut words = text.split_whitespace();
+    words
+        .next()
+        .is_some_and(|w| w.chars().all(|c| c.is_alphabetic() || c == '-'))
+        && words.next().is_none()
+}
+
+/// Lists every contract rule `response` breaks.
+pub fn check(response: &GeminiTellResponse) -> Vec<Violation> {
+    let mut violations = Vec::new();
+
+    let words = word_count(&response.summary);
+    if words > MAX_SUMMARY_WORDS {
+        violations.push(Violation::SummaryTooLong { words });
+    }
+    let words = word_count(&response.user_state);
+    if words > MAX_SUMMARY_WORDS {
+        violations.push(Violation::UserStateTooLong { words });
+    }
+    if !is_single_word(&response.mood) {
+        violations.push(Violation::MoodNotSingleWord);
+    }
+    if response.answer.contains('?') {
+        violations.push(Violation::QuestionInAnswer);
+    }
+    violations
+}
+
+/// The follow-up turn asking the model to fix its previous reply.
+pub fn correction_prompt(violations: &[Violation]) -> String {
+    let rules: Vec<String> = violations
+        .iter()
+        .map(|v| format!("- {}", v.instruction()))
+        .collect();
+    format!(
+        "Your previous response broke the response format:\n{}\n\nReply again with the corrected JSON object only.",
+        rules.join("\n")
+    )
+}
+
+/// Logs one `ContractViolation` metric per broken rule.
+pub fn record(violations: &[Violation]) {
+    for violation in violations {
+        eprintln!("Response contract violated: {:?}", violation);
+        metrics::count("ContractViolation", &[("Rule", violation.code())]);
+    }
+}
+
+fn truncate_words(text: &str, max: usize) -> String {
+    if word_count(text) <= max {
+        return text.to_string();
+    }
+    let truncated: Vec<&str> = text.split_whitespace().take(max).collect();
+    let truncated = truncated.join(" ");
+    format!(
+        "{}.",
+        truncated.trim_end_matches(|c: char| c.is_ascii_punctuation())
+    )
+}
+
+/// Drops the sentences that ask something. If every sentence is a question, they are turned into
+/// statements instead.
+fn remove_questions(answer: &str) -> String {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    for (i, c) in answer.char_indices() {
+        if matches!(c, '.' | '!' | '?') {
+            sentences.push(&answer[start..i + c.len_utf8()]);
+            start = i + c.len_utf8();
+        }
+    }
+    sentences.push(&answer[start..]);
+
+    let kept: Vec<&str> = sentences
+        .iter()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty() && !s.contains('?'))
+        .collect();
+    if kept.is_empty() {
+        return answer.replace('?', ".");
+    }
+    kept.join(" ")
+}
+
+fn first_word(mood: &str) -> String {
+    let word: String = mood
+        .split_whitespace()
+        .map(|w| {
+            w.chars()
+                .filter(|c| c.is_alphabetic() || *c == '-')
+                .collect::<String>()
+        })
+        .find(|w| !w.is_empty())
+        .unwrap_or_default();
+    if word.is_empty() {
+        "Unknown".to_string()
+    } else {
+        word
+    }
+}
+
+/// Deterministically brings a reply within the contract, for when regeneration didn't help.
+pub fn repair(mut response: GeminiTellResponse) -> GeminiTellResponse {
+    response.summary = truncate_words(&response.summary, MAX_SUMMARY_WORDS);
+    response.user_state = truncate_words(&response.user_state, MAX_SUMMARY_WORDS);
+    if !is_single_word(&response.mood) {
+        response.mood = first_word(&response.mood);
+    }
+    if response.answer.contains('?') {
+        response.answer = remove_questions(&response.answer);
+    }
+    metrics::count("ContractRepaired", &[]);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(answer: &str, summary: &str, user_state: &str, mood: &str) -> GeminiTellResponse {
+        GeminiTellResponse {
+            answer: answer.to_string(),
+            summary: summary.to_string(),
+            user_state: user_state.to_string(),
+            mood: mood.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_check_valid_response() {
+        let valid = response(
+            "You handled it well.",
+            "User got a job offer.",
+            "Excited and relieved.",
+            "Happy",
+        );
+        assert!(check(&valid).is_empty());
+    }
+
+    #[test]
+    fn test_check_lists_every_violation() {
+        let invalid = response(
+            "That sounds hard. How did it make you feel?",
+            "User talked at great length about a very long day at work with many meetings",
+            "Tired.",
+            "tired but hopeful",
+        );
+        assert_eq!(
+            check(&invalid),
+            vec![
+                Violation::SummaryTooLong { words: 15 },
+                Violation::MoodNotSingleWord,
+                Violation::QuestionInAnswer,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_correction_prompt() {
+        let prompt = correction_prompt(&[
+            Violation::UserStateTooLong { words: 14 },
+            Violation::QuestionInAnswer,
+        ]);
+        assert!(prompt.contains("`user_state` has 14 words; use at most 12."));
+        assert!(prompt.contains("`answer` must not ask any questions."));
+    }
+
+    #[test]
+    fn test_repair() {
+        let repaired = repair(response(
+            "That sounds hard. How did it make you feel? You are doing your best.",
+            "User talked at great length about a very long day at work, with many meetings",
+            "Tired.",
+            "tired, but hopeful",
+        ));
+        assert_eq!(
+            repaired.answer,
+            "That sounds hard. You are doing your best."
+        );
+        assert_eq!(
+            repaired.summary,
+            "User talked at great length about a very long day at work."
+        );
+        assert_eq!(repaired.user_state, "Tired.");
+        assert_eq!(repaired.mood, "tired");
+        assert!(check(&repaired).is_empty());
+    }
+
+    #[test]
+    fn test_repair_answer_made_only_of_questions() {
+        let repaired = repair(response("Why not rest?", "Summary.", "State.", "?!"));
+        assert_eq!(repaired.answer, "Why not rest.");
+        assert_eq!(repaired.mood, "Unknown");
+    }
+}
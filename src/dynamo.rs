@@ -16,6 +16,13 @@ pub struct DynamoClient {
 
 pub const USERS_TABLE_NAME: &str = "teal-users";
 pub const TELLS_TABLE_NAME: &str = "teal-tells";
+pub const EMBEDDINGS_TABLE_NAME: &str = "teal-embeddings";
+pub const TRACES_TABLE_NAME: &str = "teal-llm-traces";
+pub const FEEDBACK_TABLE_NAME: &str = "teal-feedback";
+pub const PROMPT_OVERRIDES_TABLE_NAME: &str = "teal-prompt-overrides";
+pub const EXPERIMENT_OUTCOMES_TABLE_NAME: &str = "teal-experiment-outcomes";
+pub const USAGE_TABLE_NAME: &str = "teal-usage";
+pub const MEMORIES_TABLE_NAME: &str = "teal-memories";
 pub const KEY: &str = "tid";
 
 static DB_CLIENT: OnceLock<Arc<DynamoClient>> = OnceLock::new();
@@ -132,6 +139,14 @@ pub async fn initialize_db() -> anyhow::Result<bool> {
     //   calling them one by one.
     db.check_create_table(USERS_TABLE_NAME).await?;
     db.check_create_table(TELLS_TABLE_NAME).await?;
+    db.check_create_table(EMBEDDINGS_TABLE_NAME).await?;
+    db.check_create_table(TRACES_TABLE_NAME).await?;
+    db.check_create_table(FEEDBACK_TABLE_NAME).await?;
+    db.check_create_table(PROMPT_OVERRIDES_TABLE_NAME).await?;
+    db.check_create_table(EXPERIMENT_OUTCOMES_TABLE_NAME)
+        .await?;
+    db.check_create_table(USAGE_TABLE_NAME).await?;
+    db.check_create_table(MEMORIES_TABLE_NAME).await?;
 
     match db.ping().await {
         Ok(_) => println!("Successfully connected to DynamoDB!"),
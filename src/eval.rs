@@ -0,0 +1,474 @@
+use crate::contract::{self, Violation};
+use crate::gemini::GeminiTellResponse;
+use crate::llm::{provider_from_env, strip_code_block, LlmProvider, LlmRequest, LlmResponse};
+use crate::locale::Locale;
+use crate::mood::{is_known_mood, normalize_mood};
+use crate::prompt_overrides::initialize_overrides;
+use crate::prompts::{create_prompt, Persona, PromptData, TellReplacements};
+use crate::secrets::{initialize_secrets, use_secrets};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::sync::Arc;
+
+/// Dataset scored when `teal-lambda eval` is not given one.
+const DEFAULT_DATASET: &str = "evals/golden.yaml";
+
+/// A golden dataset: tells with the properties their answers must have. Written in YAML, or in
+/// JSON, which YAML reads as well.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dataset {
+    /// Phrases no answer may contain, whatever the case, e.g. `As an AI`.
+    #[serde(default)]
+    pub banned_phrases: Vec<String>,
+    pub cases: Vec<EvalCase>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalCase {
+    pub name: String,
+    pub tell: String,
+    #[serde(default = "default_username")]
+    pub username: String,
+    #[serde(default)]
+    pub locale: Locale,
+    #[serde(default)]
+    pub persona: Persona,
+    /// The rendered context of past conversations; empty for a first conversation.
+    #[serde(default)]
+    pub context: String,
+    /// Moods the answer may be tagged with. Any mood Teal knows when empty.
+    #[serde(default)]
+    pub expected_moods: Vec<String>,
+    #[serde(default)]
+    pub max_answer_words: Option<usize>,
+    /// Phrases banned for this case only, on top of the dataset's.
+    #[serde(default)]
+    pub banned_phrases: Vec<String>,
+    /// What the mock provider answers: a JSON object, or a string sent as it is.
+    #[serde(default)]
+    pub mock_reply: Option<serde_json::Value>,
+}
+
+fn default_username() -> String {
+    "jane".to_string()
+}
+
+/// A property every answer is scored on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Check {
+    /// The answer is a JSON object with exactly the keys of `tell.md`.
+    Schema,
+    /// `summary` and `user_state` keep to their limit, and `answer` to the case's.
+    WordLimits,
+    NoQuestions,
+    /// `mood` is a single word Teal knows, and one of the case's expected moods.
+    MoodTaxonomy,
+    BannedPhrases,
+}
+
+const CHECKS: [Check; 5] = [
+    Check::Schema,
+    Check::WordLimits,
+    Check::NoQuestions,
+    Check::MoodTaxonomy,
+    Check::BannedPhrases,
+];
+
+impl Check {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Check::Schema => "schema",
+            Check::WordLimits => "word_limits",
+            Check::NoQuestions => "no_questions",
+            Check::MoodTaxonomy => "mood_taxonomy",
+            Check::BannedPhrases => "banned_phrases",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// Not scored, because the answer could not be parsed.
+    Skip,
+}
+
+impl Outcome {
+    fn label(&self) -> &'static str {
+        match self {
+            Outcome::Pass => "pass",
+            Outcome::Fail(_) => "FAIL",
+            Outcome::Skip => "skip",
+        }
+    }
+}
+
+/// Outcomes in the order of [`CHECKS`] for an answer that could not be parsed: the schema check
+/// fails for `reason` and the others are skipped.
+fn unparsed(reason: String) -> Vec<(Check, Outcome)> {
+    CHECKS
+        .iter()
+        .map(|check| match check {
+            Check::Schema => (*check, Outcome::Fail(reason.clone())),
+            _ => (*check, Outcome::Skip),
+        })
+        .collect()
+}
+
+fn outcome(failures: Vec<String>) -> Outcome {
+    if failures.is_empty() {
+        Outcome::Pass
+    } else {
+        Outcome::Fail(failures.join(" "))
+    }
+}
+
+/// Scores the raw text of an answer to `case`, in the order of [`CHECKS`].
+pub fn score_reply(
+    case: &EvalCase,
+    banned_phrases: &[String],
+    text: &str,
+) -> Vec<(Check, Outcome)> {
+    let response: GeminiTellResponse = match serde_json::from_str(strip_code_block(text)) {
+        Ok(response) => response,
+        Err(e) => return unparsed(e.to_string()),
+    };
+
+    let mut word_limits = Vec::new();
+    let mut questions = Vec::new();
+    let mut mood = Vec::new();
+    for violation in contract::check(&response) {
+        match violation {
+            Violation::SummaryTooLong { .. } | Violation::UserStateTooLong { .. } => {
+                word_limits.push(violation.instruction())
+            }
+            Violation::QuestionInAnswer => questions.push(violation.instruction()),
+            Violation::MoodNotSingleWord => mood.push(violation.instruction()),
+        }
+    }
+    let answer_words = response.answer.split_whitespace().count();
+    if let Some(max) = case.max_answer_words.filter(|max| answer_words > *max) {
+        word_limits.push(format!(
+            "`answer` has {} words; use at most {}.",
+            answer_words, max
+        ));
+    }
+
+    let normalized = normalize_mood(&response.mood);
+    if mood.is_empty() && !is_known_mood(&normalized) {
+        mood.push(format!("`mood` '{}' is not a known mood.", response.mood));
+    } else if mood.is_empty()
+        && !case.expected_moods.is_empty()
+        && !case.expected_moods.contains(&normalized)
+    {
+        mood.push(format!(
+            "`mood` is '{}', expected one of {}.",
+            normalized,
+            case.expected_moods.join(", ")
+        ));
+    }
+
+    let answer = response.answer.to_lowercase();
+    let banned: Vec<String> = banned_phrases
+        .iter()
+        .chain(&case.banned_phrases)
+        .filter(|phrase| answer.contains(&phrase.to_lowercase()))
+        .map(|phrase| format!("`answer` says '{}'.", phrase))
+        .collect();
+
+    vec![
+        (Check::Schema, Outcome::Pass),
+        (Check::WordLimits, outcome(word_limits)),
+        (Check::NoQuestions, outcome(questions)),
+        (Check::MoodTaxonomy, outcome(mood)),
+        (Check::BannedPhrases, outcome(banned)),
+    ]
+}
+
+/// Answers with the `mock_reply` of the case being scored, so the harness runs offline.
+struct MockProvider {
+    reply: Option<String>,
+}
+
+#[async_trait]
+impl LlmProvider for MockProvider {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    async fn generate(&self, _request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+        let text = self
+            .reply
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("The case has no mock_reply"))?;
+        Ok(LlmResponse {
+            text,
+            provider: "mock".to_string(),
+            model: "mock".to_string(),
+            usage: None,
+            finish_reason: None,
+            tool_calls: Vec::new(),
+        })
+    }
+}
+
+/// Where answers come from: each case's `mock_reply`, or the provider configured in the
+/// environment, which covers a local model (`LLM_PROVIDER=openai`) and recorded fixtures
+/// (`TEAL_HTTP_MODE=replay`).
+pub enum EvalProvider {
+    Mock,
+    Env(Arc<dyn LlmProvider>),
+}
+
+impl EvalProvider {
+    fn name(&self) -> &str {
+        match self {
+            EvalProvider::Mock => "mock",
+            EvalProvider::Env(provider) => provider.name(),
+        }
+    }
+}
+
+pub struct CaseReport {
+    pub name: String,
+    /// Version label of the prompt the case was rendered with, e.g. `v2-1a2b3c4d`.
+    pub prompt: String,
+    pub outcomes: Vec<(Check, Outcome)>,
+}
+
+pub struct EvalReport {
+    pub dataset: String,
+    pub provider: String,
+    pub cases: Vec<CaseReport>,
+}
+
+impl EvalReport {
+    pub fn failed_checks(&self) -> usize {
+        self.cases
+            .iter()
+            .flat_map(|c| &c.outcomes)
+            .filter(|(_, outcome)| matches!(outcome, Outcome::Fail(_)))
+            .count()
+    }
+
+    /// The report as Markdown. It holds nothing that changes from run to run, like times or
+    /// ids, so two reports diff to what the prompt change did.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "# Prompt evaluation\n").unwrap();
+        writeln!(out, "Dataset: `{}`  ", self.dataset).unwrap();
+        writeln!(out, "Provider: `{}`\n", self.provider).unwrap();
+
+        let codes: Vec<&str> = CHECKS.iter().map(Check::code).collect();
+        writeln!(out, "| Case | Prompt | {} |", codes.join(" | ")).unwrap();
+        writeln!(out, "| --- | --- |{}", " --- |".repeat(CHECKS.len())).unwrap();
+        for case in &self.cases {
+            let labels: Vec<&str> = case.outcomes.iter().map(|(_, o)| o.label()).collect();
+            writeln!(
+                out,
+                "| {} | {} | {} |",
+                case.name,
+                case.prompt,
+                labels.join(" | ")
+            )
+            .unwrap();
+        }
+
+        let failures: Vec<String> = self
+            .cases
+            .iter()
+            .flat_map(|case| {
+                case.outcomes
+                    .iter()
+                    .filter_map(move |(check, outcome)| match outcome {
+                        Outcome::Fail(reason) => {
+                            Some(format!("- `{}` {}: {}", case.name, check.code(), reason))
+                        }
+                        _ => None,
+                    })
+            })
+            .collect();
+        if !failures.is_empty() {
+            writeln!(out, "\n## Failures\n\n{}", failures.join("\n")).unwrap();
+        }
+
+        let passed = self
+            .cases
+            .iter()
+            .filter(|c| c.outcomes.iter().all(|(_, o)| *o == Outcome::Pass))
+            .count();
+        let checks = self.cases.iter().map(|c| c.outcomes.len()).sum::<usize>();
+        writeln!(
+            out,
+            "\nPassed {} of {} cases, {} of {} checks.",
+            passed,
+            self.cases.len(),
+            checks - self.failed_checks(),
+            checks
+        )
+        .unwrap();
+        out
+    }
+}
+
+/// Renders `tell.md` for each case, asks the provider once and scores the raw answer. Answers are
+/// scored as the model gave them, before any correction or repair.
+pub async fn run_eval(
+    dataset: &Dataset,
+    dataset_name: &str,
+    provider: &EvalProvider,
+) -> anyhow::Result<EvalReport> {
+    let mut cases = Vec::with_capacity(dataset.cases.len());
+    for case in &dataset.cases {
+        let prompt = create_prompt(
+            &case.locale,
+            PromptData::Tell(TellReplacements {
+                username: &case.username,
+                context: &case.context,
+                tell: &case.tell,
+            }),
+        )
+        .await?;
+        let request = LlmRequest::from_prompt(&prompt.instruction_for(case.persona)?, &[], &prompt);
+        let res = match provider {
+            EvalProvider::Mock => {
+                let reply = case.mock_reply.as_ref().map(|reply| match reply {
+                    serde_json::Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                });
+                MockProvider { reply }.generate(&request).await
+            }
+            EvalProvider::Env(provider) => provider.generate(&request).await,
+        };
+        let outcomes = match res {
+            Ok(res) => score_reply(case, &dataset.banned_phrases, &res.text),
+            Err(e) => unparsed(format!("No answer: {}", e)),
+        };
+        cases.push(CaseReport {
+            name: case.name.clone(),
+            prompt: format!("{} {}", case.locale, prompt.version.label()),
+            outcomes,
+        });
+    }
+    Ok(EvalReport {
+        dataset: dataset_name.to_string(),
+        provider: provider.name().to_string(),
+        cases,
+    })
+}
+
+/// `teal-lambda eval [--provider mock|env] [dataset]`: scores the dataset and prints the report.
+/// Fails when any check does, so it can gate CI. Prompt overrides are applied, so a candidate
+/// prompt can be scored from a directory before it is deployed.
+pub async fn run_cli(args: &[String]) -> anyhow::Result<()> {
+    let mut provider = "mock";
+    let mut path = DEFAULT_DATASET;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--provider" => {
+                provider = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--provider needs a value"))?
+            }
+            other => path = other,
+        }
+    }
+
+    let dataset: Dataset = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
+    let provider = match provider {
+        "mock" => EvalProvider::Mock,
+        "env" => {
+            initialize_secrets().await?;
+            EvalProvider::Env(provider_from_env(use_secrets().clone()).await?)
+        }
+        other => return Err(anyhow::anyhow!("Unknown eval provider '{}'", other)),
+    };
+    initialize_overrides().await?;
+
+    let report = run_eval(&dataset, path, &provider).await?;
+    print!("{}", report.render());
+    match report.failed_checks() {
+        0 => Ok(()),
+        failed => Err(anyhow::anyhow!("{} checks failed", failed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn case(yaml: &str) -> EvalCase {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn failures(outcomes: &[(Check, Outcome)]) -> Vec<&'static str> {
+        outcomes
+            .iter()
+            .filter(|(_, o)| matches!(o, Outcome::Fail(_)))
+            .map(|(check, _)| check.code())
+            .collect()
+    }
+
+    #[test]
+    fn test_score_reply() {
+        let plain = case("{name: plain, tell: Hi}");
+        let good = r#"{"answer": "Well done.", "summary": "User ran.", "user_state": "Proud.", "mood": "Proud"}"#;
+        assert!(failures(&score_reply(&plain, &[], good)).is_empty());
+
+        let outcomes = score_reply(&plain, &[], r#"{"answer": "Arr", "persona": "pirate"}"#);
+        assert_eq!(failures(&outcomes), vec!["schema"]);
+        assert_eq!(outcomes[1].1, Outcome::Skip);
+
+        let bad = r#"{"answer": "As an AI, I am proud. Are you?", "summary": "one two three four five six seven eight nine ten eleven twelve thirteen", "user_state": "Fine.", "mood": "nostalgic"}"#;
+        assert_eq!(
+            failures(&score_reply(&plain, &["as an ai".to_string()], bad)),
+            vec![
+                "word_limits",
+                "no_questions",
+                "mood_taxonomy",
+                "banned_phrases"
+            ]
+        );
+
+        let strict = case(
+            "{name: strict, tell: Hi, max_answer_words: 1, expected_moods: [tired], banned_phrases: [well]}",
+        );
+        let outcomes = score_reply(&strict, &[], good);
+        assert_eq!(
+            failures(&outcomes),
+            vec!["word_limits", "mood_taxonomy", "banned_phrases"]
+        );
+        assert_eq!(
+            outcomes[3].1,
+            Outcome::Fail("`mood` is 'proud', expected one of tired.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_datasets_can_be_json() {
+        let dataset: Dataset =
+            serde_yaml::from_str(r#"{"cases": [{"name": "a", "tell": "Hi", "locale": "es"}]}"#)
+                .unwrap();
+        assert_eq!(dataset.cases[0].username, "jane");
+        assert_eq!(dataset.cases[0].locale, Locale::parse("es").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_golden_report_is_up_to_date() {
+        let dataset: Dataset = serde_yaml::from_str(include_str!("../evals/golden.yaml")).unwrap();
+        let report = run_eval(&dataset, DEFAULT_DATASET, &EvalProvider::Mock)
+            .await
+            .unwrap();
+        assert_eq!(
+            report.render(),
+            include_str!("../evals/report.md"),
+            "Regenerate with `cargo run -- eval > evals/report.md` and review the diff"
+        );
+        assert_eq!(report.failed_checks(), 0);
+    }
+}
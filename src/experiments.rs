@@ -0,0 +1,527 @@
+use crate::dynamo::{use_db, EXPERIMENT_OUTCOMES_TABLE_NAME, FEEDBACK_TABLE_NAME};
+use crate::feedback::FeedbackItem;
+use crate::llm::{LlmError, TellReply};
+use crate::prompts::{check_variant, PromptName};
+use crate::traces::ParseOutcome;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+/// Prompts whose outcomes are logged, and so the only ones experiments can run on.
+const EXPERIMENT_PROMPTS: [PromptName; 1] = [PromptName::Tell];
+
+/// A prompt A/B test, declared in `prompts/experiments.yaml`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub id: String,
+    /// File name of the prompt under test, e.g. `tell.md`.
+    pub prompt: String,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    pub name: String,
+    /// Path of the variant's template below `prompts/`. `None` keeps the regular prompt, for the
+    /// control group.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Share of users, relative to the other variants.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// The variant a user was assigned, as carried by prompts and replies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    pub experiment: String,
+    pub variant: String,
+    pub template: Option<String>,
+}
+
+impl Experiment {
+    /// The user's variant. The same user always lands in the same variant of an experiment, while
+    /// different experiments split users independently.
+    pub fn assign(&self, username: &str) -> Assignment {
+        let digest = Sha256::digest(format!("{}/{}", self.id, username));
+        let total: u64 = self.variants.iter().map(|v| v.weight as u64).sum();
+        let mut bucket = u64::from_be_bytes(digest[..8].try_into().expect("8 bytes")) % total;
+        let variant = self
+            .variants
+            .iter()
+            .find(|v| {
+                if bucket < v.weight as u64 {
+                    return true;
+                }
+                bucket -= v.weight as u64;
+                false
+            })
+            .expect("Buckets are below the total weight");
+        Assignment {
+            experiment: self.id.clone(),
+            variant: variant.name.clone(),
+            template: variant.template.clone(),
+        }
+    }
+}
+
+/// Checks the definitions: unique ids, one experiment per prompt, and at least two uniquely named
+/// variants whose templates can stand in for the prompt.
+pub fn check_experiments(experiments: &[Experiment]) -> anyhow::Result<()> {
+    let mut ids = HashSet::new();
+    let mut prompts = HashSet::new();
+    for experiment in experiments {
+        let id = &experiment.id;
+        if id.is_empty() || !ids.insert(id.as_str()) {
+            return Err(anyhow::anyhow!("Experiment ids must be unique: '{}'", id));
+        }
+        if !EXPERIMENT_PROMPTS
+            .iter()
+            .any(|p| p.as_str() == experiment.prompt)
+        {
+            return Err(anyhow::anyhow!(
+                "Experiment '{}' runs on '{}', whose outcomes are not logged",
+                id,
+                experiment.prompt
+            ));
+        }
+        if !prompts.insert(experiment.prompt.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Experiment '{}' runs on '{}' alongside another experiment",
+                id,
+                experiment.prompt
+            ));
+        }
+
+        let names: HashSet<&str> = experiment
+            .variants
+            .iter()
+            .map(|v| v.name.as_str())
+            .collect();
+        if experiment.variants.len() < 2 || names.len() != experiment.variants.len() {
+            return Err(anyhow::anyhow!(
+                "Experiment '{}' needs at least two uniquely named variants",
+                id
+            ));
+        }
+        if experiment.variants.iter().all(|v| v.weight == 0) {
+            return Err(anyhow::anyhow!("Experiment '{}' has no weight", id));
+        }
+        for variant in &experiment.variants {
+            if let Some(template) = &variant.template {
+                check_variant(&experiment.prompt, template).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Variant '{}' of experiment '{}' is invalid: {}",
+                        variant.name,
+                        id,
+                        e
+                    )
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The variant the user is assigned in the experiment running on `prompt`, if there is one.
+pub fn assign(
+    experiments: &[Experiment],
+    prompt: PromptName,
+    username: &str,
+) -> Option<Assignment> {
+    experiments
+        .iter()
+        .find(|e| e.prompt == prompt.as_str())
+        .map(|e| e.assign(username))
+}
+
+static EXPERIMENTS: OnceLock<Vec<Experiment>> = OnceLock::new();
+
+pub fn init_global_experiments(experiments: Vec<Experiment>) {
+    EXPERIMENTS.set(experiments).ok();
+}
+
+/// The running experiments; none until they are initialized.
+pub fn use_experiments() -> &'static [Experiment] {
+    EXPERIMENTS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Parses the experiments embedded from `prompts/experiments.yaml`.
+fn embedded_experiments() -> anyhow::Result<Vec<Experiment>> {
+    serde_yaml::from_str(include_str!("../prompts/experiments.yaml"))
+        .map_err(|e| anyhow::anyhow!("Invalid prompts/experiments.yaml: {}", e))
+}
+
+/// Loads and checks the experiments, unless disabled with `TEAL_EXPERIMENTS=off`.
+pub fn initialize_experiments() -> anyhow::Result<()> {
+    if std::env::var("TEAL_EXPERIMENTS").as_deref() == Ok("off") {
+        return Ok(());
+    }
+    let experiments = embedded_experiments()?;
+    check_experiments(&experiments)?;
+    for experiment in &experiments {
+        println!(
+            "Running experiment '{}' on {}",
+            experiment.id, experiment.prompt
+        );
+    }
+    init_global_experiments(experiments);
+    Ok(())
+}
+
+/// What came of one request made with an experiment variant, stored in the
+/// `teal-experiment-outcomes` table.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExperimentOutcome {
+    pub tid: String,
+    pub experiment: String,
+    pub variant: String,
+    pub prompt: String,
+    pub username: String,
+    /// The tell answered; `None` when no reply could be parsed.
+    pub tell_tid: Option<String>,
+    pub parse_failed: bool,
+    pub answer_words: Option<u32>,
+    /// Replies that broke the response contract before being corrected or repaired.
+    pub contract_violations: u32,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+impl ExperimentOutcome {
+    fn new(assignment: &Assignment, prompt: PromptName, username: &str) -> Self {
+        Self {
+            tid: Uuid::new_v4().to_string(),
+            experiment: assignment.experiment.clone(),
+            variant: assignment.variant.clone(),
+            prompt: prompt.as_str().to_string(),
+            username: username.to_string(),
+            tell_tid: None,
+            parse_failed: false,
+            answer_words: None,
+            contract_violations: 0,
+            created_at: Utc::now(),
+        }
+    }
+
+    /// The outcome of a stored tell.
+    pub fn answered(
+        assignment: &Assignment,
+        username: &str,
+        tell_tid: &str,
+        reply: &TellReply,
+    ) -> Self {
+        Self {
+            tell_tid: Some(tell_tid.to_string()),
+            answer_words: Some(reply.response.answer.split_whitespace().count() as u32),
+            contract_violations: reply
+                .traces
+                .iter()
+                .filter(|t| t.outcome == ParseOutcome::ContractViolation)
+                .count() as u32,
+            ..Self::new(assignment, PromptName::Tell, username)
+        }
+    }
+
+    /// The outcome of a tell that failed, if it failed because the reply could not be parsed.
+    /// Other errors, such as an unreachable provider, say nothing about the prompt.
+    pub fn failed(assignment: &Assignment, username: &str, error: &anyhow::Error) -> Option<Self> {
+        matches!(
+            error.downcast_ref::<LlmError>(),
+            Some(LlmError::InvalidOutput { .. })
+        )
+        .then(|| Self {
+            parse_failed: true,
+            ..Self::new(assignment, PromptName::Tell, username)
+        })
+    }
+}
+
+/// Stores the outcome. Like tracing, this is best-effort: failures are logged and the request
+/// carries on.
+pub async fn log_outcome(outcome: ExperimentOutcome) {
+    let item = match to_value(outcome) {
+        Ok(item) => item,
+        Err(e) => {
+            eprintln!("Failed to serialize experiment outcome: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = use_db().put(EXPERIMENT_OUTCOMES_TABLE_NAME, item).await {
+        eprintln!("Failed to store experiment outcome: {:?}", e);
+    }
+}
+
+/// How one variant of an experiment fared.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VariantReport {
+    pub variant: String,
+    /// Requests made with the variant.
+    pub requests: u32,
+    pub parse_failures: u32,
+    pub parse_failure_rate: f64,
+    /// Over the parsed replies.
+    pub average_answer_words: Option<f64>,
+    /// Parsed replies that broke the response contract at least once.
+    pub contract_violations: u32,
+    pub contract_violation_rate: Option<f64>,
+    pub ratings: u32,
+    pub average_rating: Option<f64>,
+}
+
+fn average(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Compares the variants, in name order, joining the ratings given to their tells.
+pub fn summarize_experiment(
+    outcomes: &[ExperimentOutcome],
+    feedback: &[FeedbackItem],
+) -> Vec<VariantReport> {
+    let ratings: HashMap<&str, u8> = feedback
+        .iter()
+        .map(|f| (f.tid.as_str(), f.rating))
+        .collect();
+    let mut variants: BTreeMap<&str, Vec<&ExperimentOutcome>> = BTreeMap::new();
+    for outcome in outcomes {
+        variants.entry(&outcome.variant).or_default().push(outcome);
+    }
+
+    variants
+        .into_iter()
+        .map(|(variant, outcomes)| {
+            let parsed: Vec<&ExperimentOutcome> = outcomes
+                .iter()
+                .copied()
+                .filter(|o| !o.parse_failed)
+                .collect();
+            let parse_failures = (outcomes.len() - parsed.len()) as u32;
+            let words: Vec<f64> = parsed
+                .iter()
+                .filter_map(|o| o.answer_words)
+                .map(f64::from)
+                .collect();
+            let violations = parsed.iter().filter(|o| o.contract_violations > 0).count() as u32;
+            let rated: Vec<f64> = parsed
+                .iter()
+                .filter_map(|o| o.tell_tid.as_deref().and_then(|tid| ratings.get(tid)))
+                .map(|r| f64::from(*r))
+                .collect();
+            VariantReport {
+                variant: variant.to_string(),
+                requests: outcomes.len() as u32,
+                parse_failures,
+                parse_failure_rate: parse_failures as f64 / outcomes.len() as f64,
+                average_answer_words: average(&words),
+                contract_violations: violations,
+                contract_violation_rate: (!parsed.is_empty())
+                    .then(|| violations as f64 / parsed.len() as f64),
+                ratings: rated.len() as u32,
+                average_rating: average(&rated),
+            }
+        })
+        .collect()
+}
+
+/// The report of an experiment, by id. Empty if nothing was logged for it.
+pub async fn get_experiment_report(experiment: &str) -> anyhow::Result<Vec<VariantReport>> {
+    let db = use_db();
+    let outcomes: Vec<ExperimentOutcome> = db
+        .scan(EXPERIMENT_OUTCOMES_TABLE_NAME, "experiment", experiment)
+        .await?;
+    let Some(prompt) = outcomes.first().map(|o| o.prompt.clone()) else {
+        return Ok(Vec::new());
+    };
+    let feedback: Vec<FeedbackItem> = db.scan(FEEDBACK_TABLE_NAME, "prompt", &prompt).await?;
+    Ok(summarize_experiment(&outcomes, &feedback))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn experiment(variants: &[(&str, Option<&str>, u32)]) -> Experiment {
+        Experiment {
+            id: "shorter-answers".to_string(),
+            prompt: "tell.md".to_string(),
+            variants: variants
+                .iter()
+                .map(|(name, template, weight)| Variant {
+                    name: name.to_string(),
+                    template: template.map(str::to_string),
+                    weight: *weight,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_embedded_experiments_are_valid() {
+        check_experiments(&embedded_experiments().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_assignment_is_deterministic() {
+        let experiment = experiment(&[("control", None, 1), ("shorter", None, 3)]);
+        let first = experiment.assign("jane");
+        for _ in 0..10 {
+            assert_eq!(experiment.assign("jane"), first);
+        }
+
+        let shorter = (0..4000)
+            .filter(|i| experiment.assign(&format!("user-{}", i)).variant == "shorter")
+            .count();
+        assert!((2800..3200).contains(&shorter), "{} of 4000", shorter);
+
+        // A variant without weight gets nobody.
+        let experiment = self::experiment(&[("control", None, 1), ("paused", None, 0)]);
+        assert!((0..100).all(|i| experiment.assign(&i.to_string()).variant == "control"));
+    }
+
+    #[test]
+    fn test_assign_finds_the_experiment_on_the_prompt() {
+        let experiments = [experiment(&[("control", None, 1), ("shorter", None, 1)])];
+        let assignment = assign(&experiments, PromptName::Tell, "jane").unwrap();
+        assert_eq!(assignment.experiment, "shorter-answers");
+        assert!(assign(&experiments, PromptName::TellTitle, "jane").is_none());
+    }
+
+    #[test]
+    fn test_check_experiments() {
+        let valid = experiment(&[("control", None, 1), ("shorter", None, 1)]);
+        assert!(check_experiments(std::slice::from_ref(&valid)).is_ok());
+        assert!(check_experiments(&[valid.clone(), valid.clone()]).is_err());
+
+        let mut title = valid.clone();
+        title.prompt = "tell_title.md".to_string();
+        assert!(check_experiments(&[title]).is_err());
+
+        assert!(check_experiments(&[experiment(&[("control", None, 1)])]).is_err());
+        assert!(check_experiments(&[experiment(&[("a", None, 1), ("a", None, 1)])]).is_err());
+        assert!(check_experiments(&[experiment(&[("a", None, 0), ("b", None, 0)])]).is_err());
+
+        // A variant template must take the placeholders of the prompt it stands in for.
+        let err = check_experiments(&[experiment(&[
+            ("control", None, 1),
+            ("title", Some("tell_title.md"), 1),
+        ])])
+        .unwrap_err();
+        assert!(err.to_string().contains("Variant 'title'"), "{}", err);
+        assert!(check_experiments(&[experiment(&[
+            ("control", None, 1),
+            ("spanish", Some("es/tell.md"), 1),
+        ])])
+        .is_ok());
+        assert!(check_experiments(&[experiment(&[
+            ("control", None, 1),
+            ("shorter", Some("experiments/tell_shorter.md"), 1),
+        ])])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_only_parse_failures_are_outcomes() {
+        let assignment = experiment(&[("control", None, 1), ("shorter", None, 1)]).assign("jane");
+        let parse_error: anyhow::Error = LlmError::InvalidOutput {
+            prompt: "tell.md".to_string(),
+            reason: "missing field `mood`".to_string(),
+        }
+        .into();
+        let outcome = ExperimentOutcome::failed(&assignment, "jane", &parse_error).unwrap();
+        assert!(outcome.parse_failed);
+        assert_eq!(outcome.tell_tid, None);
+        assert_eq!(outcome.prompt, "tell.md");
+
+        let network_error = anyhow::anyhow!("connection reset");
+        assert!(ExperimentOutcome::failed(&assignment, "jane", &network_error).is_none());
+    }
+
+    fn outcome(
+        variant: &str,
+        tell_tid: Option<&str>,
+        words: u32,
+        violations: u32,
+    ) -> ExperimentOutcome {
+        ExperimentOutcome {
+            tid: Uuid::new_v4().to_string(),
+            experiment: "shorter-answers".to_string(),
+            variant: variant.to_string(),
+            prompt: "tell.md".to_string(),
+            username: "jane".to_string(),
+            tell_tid: tell_tid.map(str::to_string),
+            parse_failed: tell_tid.is_none(),
+            answer_words: tell_tid.map(|_| words),
+            contract_violations: violations,
+            created_at: Utc::now(),
+        }
+    }
+
+    fn rating(tid: &str, rating: u8) -> FeedbackItem {
+        FeedbackItem {
+            tid: tid.to_string(),
+            username: "jane".to_string(),
+            prompt: "tell.md".to_string(),
+            prompt_version: None,
+            prompt_hash: None,
+            rating,
+            comment: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_summarize_experiment() {
+        let report = summarize_experiment(
+            &[
+                outcome("shorter", Some("a"), 20, 0),
+                outcome("control", Some("b"), 60, 1),
+                outcome("control", None, 0, 0),
+                outcome("shorter", Some("c"), 30, 0),
+                outcome("control", Some("d"), 80, 0),
+            ],
+            &[
+                rating("a", 5),
+                rating("c", 3),
+                rating("d", 2),
+                rating("z", 1),
+            ],
+        );
+
+        assert_eq!(
+            report,
+            vec![
+                VariantReport {
+                    variant: "control".to_string(),
+                    requests: 3,
+                    parse_failures: 1,
+                    parse_failure_rate: 1.0 / 3.0,
+                    average_answer_words: Some(70.0),
+                    contract_violations: 1,
+                    contract_violation_rate: Some(0.5),
+                    ratings: 1,
+                    average_rating: Some(2.0),
+                },
+                VariantReport {
+                    variant: "shorter".to_string(),
+                    requests: 2,
+                    parse_failures: 0,
+                    parse_failure_rate: 0.0,
+                    average_answer_words: Some(25.0),
+                    contract_violations: 0,
+                    contract_violation_rate: Some(0.0),
+                    ratings: 2,
+                    average_rating: Some(4.0),
+                },
+            ]
+        );
+    }
+}
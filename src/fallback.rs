@@ -0,0 +1,369 @@
+use crate::llm::{LlmProvider, LlmRequest, LlmResponse};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops calling a provider after `failure_threshold` consecutive failures. Once `cooldown` has
+/// passed the breaker is half-open: a single trial call is let through while other calls are
+/// still refused. A success closes the breaker again, a failure reopens it for another cooldown.
+/// A trial that never reports back is replaced by a new one after a further cooldown.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    pub fn allows(&self) -> bool {
+        self.allows_at(Instant::now())
+    }
+
+    /// Whether a call may go through. When the cooldown is over this claims the trial call,
+    /// keeping the breaker open for everyone else until the trial's outcome is recorded.
+    fn allows_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        match state.open_until {
+            None => true,
+            Some(until) if now < until => false,
+            Some(_) => {
+                state.open_until = Some(now + self.cooldown);
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().expect("Circuit breaker lock poisoned") = BreakerState::default();
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut state = self.state.lock().expect("Circuit breaker lock poisoned");
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+struct Link {
+    provider: Arc<dyn LlmProvider>,
+    breaker: CircuitBreaker,
+}
+
+/// Tries an ordered chain of providers, e.g. Gemini flash, then a second Gemini model, then a
+/// local OpenAI-compatible server. A provider whose breaker is open is skipped until its cooldown
+/// ends. Only errors fall through to the next provider; blocked or truncated answers are handled
+/// by the caller as usual. The answering provider is reported in [`LlmResponse::provider`].
+pub struct FallbackProvider {
+    links: Vec<Link>,
+}
+
+impl FallbackProvider {
+    pub fn new(
+        providers: Vec<Arc<dyn LlmProvider>>,
+        failure_threshold: u32,
+        cooldown: Duration,
+    ) -> Self {
+        Self {
+            links: providers
+                .into_iter()
+                .map(|provider| Link {
+                    provider,
+                    breaker: CircuitBreaker::new(failure_threshold, cooldown),
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads the breaker settings from `LLM_BREAKER_FAILURES` (default 3) and
+    /// `LLM_BREAKER_COOLDOWN_SECS` (default 60).
+    pub fn from_env(providers: Vec<Arc<dyn LlmProvider>>) -> anyhow::Result<Self> {
+        let failure_threshold = match std::env::var("LLM_BREAKER_FAILURES") {
+            Ok(value) => value.parse()?,
+            Err(_) => DEFAULT_FAILURE_THRESHOLD,
+        };
+        let cooldown = match std::env::var("LLM_BREAKER_COOLDOWN_SECS") {
+            Ok(value) => Duration::from_secs(value.parse()?),
+            Err(_) => DEFAULT_COOLDOWN,
+        };
+        Ok(Self::new(providers, failure_threshold, cooldown))
+    }
+
+    fn available(&self) -> impl Iterator<Item = &Link> {
+        self.links.iter().filter(|link| {
+            let allowed = link.breaker.allows();
+            if !allowed {
+                println!(
+                    "Skipping LLM provider {}: circuit open",
+                    link.provider.name()
+                );
+            }
+            allowed
+        })
+    }
+}
+
+fn unavailable(last_error: Option<anyhow::Error>) -> anyhow::Error {
+    last_error.unwrap_or_else(|| anyhow::anyhow!("All LLM providers are unavailable"))
+}
+
+#[async_trait]
+impl LlmProvider for FallbackProvider {
+    fn name(&self) -> &str {
+        "fallback"
+    }
+
+    async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+        let mut last_error = None;
+        for link in self.available() {
+            match link.provider.generate(request).await {
+                Ok(response) => {
+                    link.breaker.record_success();
+                    return Ok(response);
+                }
+                Err(e) => {
+                    eprintln!("LLM provider {} failed: {:?}", link.provider.name(), e);
+                    link.breaker.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(unavailable(last_error))
+    }
+
+    /// Falls back only while opening the stream; once a provider has started answering, errors
+    /// in the middle of the stream are passed on to the caller.
+    async fn generate_stream(
+        &self,
+        request: &LlmRequest,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<LlmResponse>>> {
+        let mut last_error = None;
+        for link in self.available() {
+            match link.provider.generate_stream(request).await {
+                Ok(stream) => {
+                    link.breaker.record_success();
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    eprintln!("LLM provider {} failed: {:?}", link.provider.name(), e);
+                    link.breaker.record_failure();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(unavailable(last_error))
+    }
+
+    /// Always embeds with the primary provider: vectors from different embedding models are not
+    /// comparable, so falling back would silently corrupt the index. While the primary's breaker
+    /// is open this fails right away. Embedding errors don't count towards the breaker, as they
+    /// may only mean the provider has no embedding model.
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let link = self.links.first().ok_or_else(|| unavailable(None))?;
+        if !link.breaker.allows() {
+            return Err(anyhow::anyhow!(
+                "LLM provider {} is unavailable: circuit open",
+                link.provider.name()
+            ));
+        }
+        let vector = link.provider.embed(text).await?;
+        link.breaker.record_success();
+        Ok(vector)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ask_llm, FinishReason};
+    use crate::prompts::Prompt;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Answers with a fixed tell, or fails every call, counting the calls it receives.
+    struct CountingProvider {
+        name: &'static str,
+        fails: bool,
+        calls: AtomicU32,
+    }
+
+    impl CountingProvider {
+        fn new(name: &'static str, fails: bool) -> Arc<Self> {
+            Arc::new(Self {
+                name,
+                fails,
+                calls: AtomicU32::new(0),
+            })
+        }
+
+        fn calls(&self) -> u32 {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait]
+    impl LlmProvider for CountingProvider {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        async fn generate(&self, _request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fails {
+                return Err(anyhow::anyhow!("{} is down", self.name));
+            }
+            Ok(LlmResponse {
+                text: "{\"answer\":\"Hello\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}".to_string(),
+                provider: self.name.to_string(),
+                model: format!("{}-model", self.name),
+                usage: None,
+                finish_reason: Some(FinishReason::Stop),
+                tool_calls: Vec::new(),
+            })
+        }
+
+        async fn embed(&self, _text: &str) -> anyhow::Result<Vec<f32>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fails {
+                return Err(anyhow::anyhow!("{} is down", self.name));
+            }
+            Ok(vec![1.0, 0.0])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_in_order() {
+        let primary = CountingProvider::new("primary", true);
+        let secondary = CountingProvider::new("secondary", false);
+        let local = CountingProvider::new("local", false);
+        let chain = FallbackProvider::new(
+            vec![primary.clone(), secondary.clone(), local.clone()],
+            3,
+            DEFAULT_COOLDOWN,
+        );
+
+        let reply = ask_llm(&chain, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        assert_eq!(reply.provider, "secondary");
+        assert_eq!(reply.model, "secondary-model");
+        assert_eq!(
+            (primary.calls(), secondary.calls(), local.calls()),
+            (1, 1, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_breaker_skips_provider() {
+        let primary = CountingProvider::new("primary", true);
+        let secondary = CountingProvider::new("secondary", false);
+        let chain = FallbackProvider::new(
+            vec![primary.clone(), secondary.clone()],
+            2,
+            DEFAULT_COOLDOWN,
+        );
+
+        for _ in 0..4 {
+            ask_llm(&chain, None, "Be kind.", &[], &Prompt::json("hi"))
+                .await
+                .unwrap();
+        }
+        assert_eq!(primary.calls(), 2);
+        assert_eq!(secondary.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_embed_fails_fast_while_primary_breaker_is_open() {
+        let primary = CountingProvider::new("primary", true);
+        let secondary = CountingProvider::new("secondary", false);
+        let chain = FallbackProvider::new(
+            vec![primary.clone(), secondary.clone()],
+            1,
+            DEFAULT_COOLDOWN,
+        );
+
+        ask_llm(&chain, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        let err = chain.embed("hi").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "LLM provider primary is unavailable: circuit open"
+        );
+        assert_eq!((primary.calls(), secondary.calls()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_all_providers_failing_returns_last_error() {
+        let chain = FallbackProvider::new(
+            vec![
+                CountingProvider::new("primary", true),
+                CountingProvider::new("secondary", true),
+            ],
+            1,
+            DEFAULT_COOLDOWN,
+        );
+
+        let err = ask_llm(&chain, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "secondary is down");
+
+        let err = ask_llm(&chain, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "All LLM providers are unavailable");
+    }
+
+    #[test]
+    fn test_circuit_breaker_cooldown() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        breaker.record_failure_at(now);
+        assert!(breaker.allows_at(now));
+        breaker.record_failure_at(now);
+        assert!(!breaker.allows_at(now));
+        assert!(!breaker.allows_at(now + Duration::from_secs(59)));
+
+        // Half-open: one trial call is allowed, and a further failure reopens the breaker.
+        let later = now + Duration::from_secs(60);
+        assert!(breaker.allows_at(later));
+        assert!(!breaker.allows_at(later));
+        breaker.record_failure_at(later);
+        assert!(!breaker.allows_at(later + Duration::from_secs(1)));
+
+        // A trial that never reports back is replaced after another cooldown.
+        let much_later = later + Duration::from_secs(60);
+        assert!(breaker.allows_at(much_later));
+        assert!(!breaker.allows_at(much_later + Duration::from_secs(59)));
+        assert!(breaker.allows_at(much_later + Duration::from_secs(60)));
+
+        breaker.record_success();
+        assert!(breaker.allows_at(much_later + Duration::from_secs(61)));
+        assert!(breaker.allows_at(much_later + Duration::from_secs(61)));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::dynamo::{use_db, FEEDBACK_TABLE_NAME};
+use crate::prompts::{PromptName, PromptVersion};
+use crate::tell::{get_user_tells, TellItem};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+use std::collections::BTreeMap;
+
+pub const MIN_RATING: u8 = 1;
+pub const MAX_RATING: u8 = 5;
+
+/// A user's rating of an answer. Keyed on the tid of the rated tell, so rating a tell again
+/// replaces the earlier rating.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FeedbackItem {
+    pub tid: String,
+    pub username: String,
+    /// File name of the prompt the answer came from, e.g. `tell.md`.
+    pub prompt: String,
+    pub prompt_version: Option<u32>,
+    pub prompt_hash: Option<String>,
+    /// From `MIN_RATING` (unhelpful) to `MAX_RATING` (very helpful).
+    pub rating: u8,
+    pub comment: Option<String>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+impl FeedbackItem {
+    /// Feedback on `tell`, copying the prompt revision stamped on it.
+    pub fn for_tell(tell: &TellItem, rating: u8, comment: Option<String>) -> Self {
+        Self {
+            tid: tell.tid.clone(),
+            username: tell.username.clone(),
+            prompt: PromptName::Tell.as_str().to_string(),
+            prompt_version: tell.prompt_version,
+            prompt_hash: tell.prompt_hash.clone(),
+            rating,
+            comment,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Stores the user's rating of one of their tells. Returns `false` if the user has no tell with
+/// that tid.
+pub async fn rate_tell(
+    username: &str,
+    tell_tid: &str,
+    rating: u8,
+    comment: Option<String>,
+) -> anyhow::Result<bool> {
+    let tells = get_user_tells(username).await?;
+    let Some(tell) = tells.iter().find(|t| t.tid == tell_tid) else {
+        return Ok(false);
+    };
+    let item = FeedbackItem::for_tell(tell, rating, comment);
+    use_db().put(FEEDBACK_TABLE_NAME, to_value(item)?).await
+}
+
+/// Ratings of the answers produced by one revision of a prompt.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct VersionFeedback {
+    /// `None` for tells stored before prompts were versioned.
+    pub version: Option<PromptVersion>,
+    /// [`PromptVersion::label`], or `unversioned`.
+    pub label: String,
+    pub ratings: u32,
+    pub average_rating: f64,
+    /// How many ratings there were of each value, from `MIN_RATING` up.
+    pub distribution: Vec<u32>,
+}
+
+/// Groups ratings by prompt revision, newest version first.
+pub fn summarize_feedback(items: &[FeedbackItem]) -> Vec<VersionFeedback> {
+    let mut groups: BTreeMap<(Option<u32>, Option<&str>), Vec<u8>> = BTreeMap::new();
+    for item in items {
+        groups
+            .entry((item.prompt_version, item.prompt_hash.as_deref()))
+            .or_default()
+            .push(item.rating);
+    }
+
+    groups
+        .into_iter()
+        .rev()
+        .map(|((version, hash), ratings)| {
+            let version = version.zip(hash).map(|(version, hash)| PromptVersion {
+                version,
+                hash: hash.to_string(),
+            });
+            let mut distribution = vec![0; (MAX_RATING - MIN_RATING + 1) as usize];
+            for rating in &ratings {
+                if let Some(count) =
+                    distribution.get_mut(rating.saturating_sub(MIN_RATING) as usize)
+                {
+                    *count += 1;
+                }
+            }
+            VersionFeedback {
+                label: version
+                    .as_ref()
+                    .map(PromptVersion::label)
+                    .unwrap_or_else(|| "unversioned".to_string()),
+                version,
+                ratings: ratings.len() as u32,
+                average_rating: ratings.iter().map(|r| *r as f64).sum::<f64>()
+                    / ratings.len() as f64,
+                distribution,
+            }
+        })
+        .collect()
+}
+
+/// The feedback report of a prompt, e.g. `tell.md`.
+pub async fn get_feedback_report(prompt: &str) -> anyhow::Result<Vec<VersionFeedback>> {
+    let items: Vec<FeedbackItem> = use_db().scan(FEEDBACK_TABLE_NAME, "prompt", prompt).await?;
+    Ok(summarize_feedback(&items))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feedback(version: Option<u32>, hash: Option<&str>, rating: u8) -> FeedbackItem {
+        FeedbackItem {
+            tid: "tid".to_string(),
+            username: "testuser".to_string(),
+            prompt: "tell.md".to_string(),
+            prompt_version: version,
+            prompt_hash: hash.map(str::to_string),
+            rating,
+            comment: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_summarize_feedback() {
+        let report = summarize_feedback(&[
+            feedback(Some(1), Some("aaaaaaaaaaaa"), 2),
+            feedback(Some(2), Some("bbbbbbbbbbbb"), 5),
+            feedback(None, None, 3),
+            feedback(Some(1), Some("aaaaaaaaaaaa"), 4),
+            feedback(Some(2), Some("bbbbbbbbbbbb"), 4),
+        ]);
+
+        let summary: Vec<(&str, u32, f64, &[u32])> = report
+            .iter()
+            .map(|r| {
+                (
+                    r.label.as_str(),
+                    r.ratings,
+                    r.average_rating,
+                    r.distribution.as_slice(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("v2-bbbbbbbb", 2, 4.5, &[0, 0, 0, 1, 1][..]),
+                ("v1-aaaaaaaa", 2, 3.0, &[0, 1, 0, 1, 0][..]),
+                ("unversioned", 1, 3.0, &[0, 0, 1, 0, 0][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_edits_without_a_version_bump_are_kept_apart() {
+        let report = summarize_feedback(&[
+            feedback(Some(1), Some("aaaaaaaa"), 5),
+            feedback(Some(1), Some("cccccccc"), 1),
+        ]);
+        assert_eq!(report.len(), 2);
+    }
+
+    #[test]
+    fn test_feedback_copies_the_prompt_revision_of_the_tell() {
+        let mut tell = crate::tell::build_tell_record(
+            "testuser",
+            "I ran a marathon",
+            &crate::gemini::GeminiTellResponse {
+                answer: "Well done.".to_string(),
+                summary: "Ran a marathon.".to_string(),
+                user_state: "Proud.".to_string(),
+                mood: "proud".to_string(),
+            },
+        );
+        tell.prompt_version = Some(3);
+        tell.prompt_hash = Some("abc".to_string());
+
+        let item = FeedbackItem::for_tell(&tell, 4, Some("Spot on".to_string()));
+        assert_eq!(item.tid, tell.tid);
+        assert_eq!(item.prompt, "tell.md");
+        assert_eq!(item.prompt_version, Some(3));
+        assert_eq!(item.prompt_hash, Some("abc".to_string()));
+    }
+}
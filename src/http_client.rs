@@ -0,0 +1,369 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Query parameters whose values are replaced before a request is recorded or matched.
+const SECRET_PARAMS: &[&str] = &["key", "api_key", "access_token"];
+const REDACTED: &str = "REDACTED";
+
+/// A JSON `POST`, the only kind of request the LLM providers and AWS JSON APIs need.
+pub struct HttpRequest {
+    pub url: String,
+    pub bearer_token: Option<String>,
+    /// Extra headers, e.g. AWS signatures. They are never recorded.
+    pub headers: Vec<(String, String)>,
+    pub body: serde_json::Value,
+}
+
+impl HttpRequest {
+    pub fn post(url: impl Into<String>, body: serde_json::Value) -> Self {
+        Self {
+            url: url.into(),
+            bearer_token: None,
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn bearer_auth(mut self, token: Option<&str>) -> Self {
+        self.bearer_token = token.map(str::to_string);
+        self
+    }
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Bytes,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    pub fn error_for_status(self) -> anyhow::Result<Self> {
+        if self.is_success() {
+            return Ok(self);
+        }
+        Err(anyhow::anyhow!(
+            "HTTP status {}: {}",
+            self.status,
+            String::from_utf8_lossy(&self.body)
+        ))
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// The transport under the LLM providers. Swapping it lets tests replay recorded traffic
+/// instead of calling the network.
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    async fn send(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse>;
+
+    /// Sends the request and streams the body of a successful response. Non-2xx responses are
+    /// returned as errors. Clients without native streaming yield the whole body as one chunk.
+    async fn send_stream(
+        &self,
+        request: &HttpRequest,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<Bytes>>> {
+        let response = self.send(request).await?.error_for_status()?;
+        Ok(stream::once(async { Ok(response.body) }).boxed())
+    }
+}
+
+/// Calls the network with a shared `reqwest` client.
+#[derive(Default)]
+pub struct ReqwestClient {
+    client: reqwest::Client,
+}
+
+impl ReqwestClient {
+    fn request(&self, request: &HttpRequest) -> reqwest::RequestBuilder {
+        // Headers go first so that an explicit `content-type` wins over the JSON default.
+        let builder = request
+            .headers
+            .iter()
+            .fold(self.client.post(&request.url), |builder, (name, value)| {
+                builder.header(name, value)
+            })
+            .json(&request.body);
+        match &request.bearer_token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+}
+
+#[async_trait]
+impl HttpClient for ReqwestClient {
+    async fn send(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+        let res = self.request(request).send().await?;
+        Ok(HttpResponse {
+            status: res.status().as_u16(),
+            body: res.bytes().await?,
+        })
+    }
+
+    async fn send_stream(
+        &self,
+        request: &HttpRequest,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<Bytes>>> {
+        let res = self.request(request).send().await?.error_for_status()?;
+        Ok(res.bytes_stream().map(|b| b.map_err(Into::into)).boxed())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    /// The URL with secret query parameters redacted. Replay matches on it.
+    pub url: String,
+    /// Kept for reading the fixture; replay does not match on it, so prompt changes don't
+    /// invalidate recordings.
+    pub body: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+/// An ordered list of recorded interactions, stored as one JSON file.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read cassette {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Replaces the values of API key query parameters, e.g. Gemini's `key=`.
+pub fn redact_url(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let params: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name) => format!("{}={}", name, REDACTED),
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, params.join("&"))
+}
+
+/// Forwards requests to `inner` and appends every exchange to the cassette at `path`. Headers
+/// and bearer tokens are never written and API keys in the URL are redacted. Streamed responses are
+/// buffered in full before they are recorded and passed on.
+pub struct RecordingClient {
+    inner: Arc<dyn HttpClient>,
+    path: PathBuf,
+    cassette: Mutex<Cassette>,
+}
+
+impl RecordingClient {
+    pub fn new(inner: Arc<dyn HttpClient>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            path: path.into(),
+            cassette: Mutex::new(Cassette::default()),
+        }
+    }
+
+    fn record(&self, request: &HttpRequest, response: &HttpResponse) -> anyhow::Result<()> {
+        let mut cassette = self.cassette.lock().expect("Cassette lock poisoned");
+        cassette.interactions.push(Interaction {
+            request: RecordedRequest {
+                url: redact_url(&request.url),
+                body: request.body.clone(),
+            },
+            response: RecordedResponse {
+                status: response.status,
+                body: String::from_utf8_lossy(&response.body).into_owned(),
+            },
+        });
+        cassette.save(&self.path)
+    }
+}
+
+#[async_trait]
+impl HttpClient for RecordingClient {
+    async fn send(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+        let response = self.inner.send(request).await?;
+        self.record(request, &response)?;
+        Ok(response)
+    }
+}
+
+/// Serves responses from a cassette without touching the network. Each interaction is used
+/// once, in recorded order, for the first request with the same redacted URL.
+pub struct ReplayClient {
+    remaining: Mutex<Vec<Interaction>>,
+}
+
+impl ReplayClient {
+    pub fn new(cassette: Cassette) -> Self {
+        Self {
+            remaining: Mutex::new(cassette.interactions),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::new(Cassette::load(path)?))
+    }
+}
+
+#[async_trait]
+impl HttpClient for ReplayClient {
+    async fn send(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+        let url = redact_url(&request.url);
+        let mut remaining = self.remaining.lock().expect("Cassette lock poisoned");
+        let position = remaining
+            .iter()
+            .position(|i| i.request.url == url)
+            .ok_or_else(|| anyhow::anyhow!("No recorded interaction for POST {}", url))?;
+        let interaction = remaining.remove(position);
+
+        Ok(HttpResponse {
+            status: interaction.response.status,
+            body: Bytes::from(interaction.response.body),
+        })
+    }
+}
+
+/// Builds the client selected by `TEAL_HTTP_MODE`: `live` (default), `record` or `replay`. The
+/// last two read and write the cassette at `TEAL_HTTP_CASSETTE`.
+pub fn http_client_from_env() -> anyhow::Result<Arc<dyn HttpClient>> {
+    let cassette = || {
+        std::env::var("TEAL_HTTP_CASSETTE").map_err(|_| {
+            anyhow::anyhow!("TEAL_HTTP_CASSETTE must be set to record or replay HTTP traffic")
+        })
+    };
+
+    let client: Arc<dyn HttpClient> =
+        match std::env::var("TEAL_HTTP_MODE").unwrap_or_default().as_str() {
+            "" | "live" => Arc::new(ReqwestClient::default()),
+            "record" => Arc::new(RecordingClient::new(
+                Arc::new(ReqwestClient::default()),
+                cassette()?,
+            )),
+            "replay" => Arc::new(ReplayClient::load(Path::new(&cassette()?))?),
+            other => return Err(anyhow::anyhow!("Unknown HTTP mode '{}'", other)),
+        };
+    Ok(client)
+}
+
+/// Path of a cassette checked in under `fixtures/http`.
+#[cfg(test)]
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures/http")
+        .join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct EchoClient;
+
+    #[async_trait]
+    impl HttpClient for EchoClient {
+        async fn send(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+            Ok(HttpResponse {
+                status: 200,
+                body: Bytes::from(request.body.to_string()),
+            })
+        }
+    }
+
+    #[test]
+    fn test_redact_url() {
+        assert_eq!(
+            redact_url("https://example.com/m:generateContent?alt=sse&key=secret"),
+            "https://example.com/m:generateContent?alt=sse&key=REDACTED"
+        );
+        assert_eq!(
+            redact_url("https://example.com/v1/chat/completions"),
+            "https://example.com/v1/chat/completions"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_then_replay() {
+        let path =
+            std::env::temp_dir().join(format!("teal-cassette-{}.json", uuid::Uuid::new_v4()));
+        let recorder = RecordingClient::new(Arc::new(EchoClient), &path);
+        let request = HttpRequest::post("https://example.com/a?key=secret", json!({ "n": 1 }))
+            .bearer_auth(Some("token"));
+        recorder.send(&request).await.unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("secret"));
+        assert!(!saved.contains("token"));
+
+        let replay = ReplayClient::load(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let other_key = HttpRequest::post("https://example.com/a?key=other", json!({}));
+        let response = replay.send(&other_key).await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.json::<serde_json::Value>().unwrap(),
+            json!({ "n": 1 })
+        );
+
+        // Every interaction is served once.
+        assert!(replay.send(&other_key).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay_stream_rejects_error_status() {
+        let replay = ReplayClient::new(Cassette {
+            interactions: vec![Interaction {
+                request: RecordedRequest {
+                    url: "https://example.com/a".to_string(),
+                    body: json!({}),
+                },
+                response: RecordedResponse {
+                    status: 503,
+                    body: "unavailable".to_string(),
+                },
+            }],
+        });
+
+        let request = HttpRequest::post("https://example.com/a", json!({}));
+        let err = replay.send_stream(&request).await.err().unwrap();
+        assert_eq!(err.to_string(), "HTTP status 503: unavailable");
+    }
+}
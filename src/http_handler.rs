@@ -1,17 +1,52 @@
-use crate::tell::{get_user_tells, tell, TellItem};
-use crate::users::{create_user, User};
+use crate::attachments::{
+    max_audio_bytes, max_image_bytes, Attachment, AttachmentError, MAX_IMAGES,
+};
+use crate::experiments::{get_experiment_report, VariantReport};
+use crate::feedback::{get_feedback_report, rate_tell, VersionFeedback, MAX_RATING, MIN_RATING};
+use crate::insights::{
+    get_daily_suggestion, get_memory, get_weekly_reflection, DailySuggestion, MemoryRollup,
+    ReflectionLetter,
+};
+use crate::locale::Locale;
+use crate::object_store::use_store;
+use crate::prompts::{Persona, PromptName};
+use crate::secrets::{use_secrets, SecretSource};
+use crate::tell::{get_user_tells, tell, TellInput, TellItem};
+use crate::traces::{get_traces, LlmTrace, TraceFilter};
+use crate::usage::{get_monthly_usage, is_over_quota, Month, MonthlyUsage};
+use crate::users::{create_user, set_user_locale, set_user_persona, User};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use chrono::{NaiveDate, Utc};
 use lambda_http::{http, Body, Error, Request, RequestExt, Response};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
-struct ResponseBody {
-    success: bool,
-    error_message: Option<String>,
+pub(crate) struct ResponseBody {
+    pub(crate) success: bool,
+    pub(crate) error_message: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct RequestBodyTell {
-    text: String,
+pub(crate) struct RequestBodyTell {
+    /// May be empty when `audio` is given.
+    #[serde(default)]
+    pub(crate) text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) images: Vec<RequestBodyAttachment>,
+    /// A voice clip, transcribed into the tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) audio: Option<RequestBodyAttachment>,
+}
+
+/// An image or voice clip attached to a JSON tell.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RequestBodyAttachment {
+    /// Optional; the type is detected from the data and must match when given.
+    #[serde(default)]
+    pub(crate) content_type: Option<String>,
+    /// Base64-encoded bytes.
+    pub(crate) data: String,
 }
 
 #[derive(Serialize)]
@@ -26,10 +61,75 @@ struct ResponseBodyTells {
     tells: Option<Vec<TellItem>>,
 }
 
+#[derive(Serialize)]
+struct ResponseBodyUsage {
+    base: ResponseBody,
+    usage: Option<MonthlyUsage>,
+}
+
+#[derive(Serialize)]
+struct ResponseBodyTraces {
+    base: ResponseBody,
+    traces: Option<Vec<LlmTrace>>,
+}
+
+#[derive(Serialize)]
+struct ResponseBodyMemory {
+    base: ResponseBody,
+    memory: Option<MemoryRollup>,
+}
+
+#[derive(Serialize)]
+struct ResponseBodyReflection {
+    base: ResponseBody,
+    reflection: Option<ReflectionLetter>,
+}
+
+#[derive(Serialize)]
+struct ResponseBodySuggestion {
+    base: ResponseBody,
+    suggestion: Option<DailySuggestion>,
+}
+
+#[derive(Serialize)]
+struct ResponseBodyFeedbackReport {
+    base: ResponseBody,
+    report: Option<Vec<VersionFeedback>>,
+}
+
+#[derive(Serialize)]
+struct ResponseBodyExperimentReport {
+    base: ResponseBody,
+    report: Option<Vec<VariantReport>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RequestBodyTellFeedback {
+    /// The rated tell.
+    tid: String,
+    rating: u8,
+    #[serde(default)]
+    comment: Option<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct RequestBodyPostUserCreate {
     name: String,
     email: String,
+    #[serde(default)]
+    persona: Option<Persona>,
+    #[serde(default)]
+    locale: Option<Locale>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RequestBodyUserPersona {
+    persona: Persona,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RequestBodyUserLocale {
+    locale: Locale,
 }
 
 // #[derive(Serialize)]
@@ -43,8 +143,19 @@ pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, E
 
     match (method, path) {
         (&http::Method::POST, "/tell") => post_tell(event).await,
+        (&http::Method::POST, "/tell/feedback") => post_tell_feedback(event).await,
         (&http::Method::POST, "/user/create") => post_user_create(event).await,
+        (&http::Method::POST, "/user/persona") => post_user_persona(event).await,
+        (&http::Method::POST, "/user/locale") => post_user_locale(event).await,
         (&http::Method::GET, "/tells") => get_tells_by_user(event).await,
+        (&http::Method::GET, "/usage") => get_usage_by_user(event).await,
+        (&http::Method::GET, "/attachment") => get_attachment(event).await,
+        (&http::Method::GET, "/memory") => get_memory_by_user(event).await,
+        (&http::Method::GET, "/reflection") => get_reflection_by_user(event).await,
+        (&http::Method::GET, "/suggestion") => get_suggestion_by_user(event).await,
+        (&http::Method::GET, "/admin/traces") => get_traces_admin(event).await,
+        (&http::Method::GET, "/admin/feedback") => get_feedback_admin(event).await,
+        (&http::Method::GET, "/admin/experiments") => get_experiments_admin(event).await,
         _ => {
             let data = ResponseBody {
                 success: false,
@@ -54,37 +165,813 @@ pub(crate) async fn function_handler(event: Request) -> Result<Response<Body>, E
                 .status(http::StatusCode::NOT_FOUND)
                 .header("content-type", "application/json")
                 .body(serde_json::to_string(&data)?.into())
-                .map_err(Box::new)?)
+                .map_err(Box::new)?)
+        }
+    }
+}
+
+/// Reads a tell from either a JSON body, with images and audio as base64, or a
+/// `multipart/form-data` body with a `text` field, `image` file fields and an `audio` file field.
+/// The text may only be left out of voice tells.
+pub(crate) async fn parse_tell_request(event: &Request) -> Result<(String, TellInput), String> {
+    if event.body().is_empty() {
+        return Err("Request body required".to_string());
+    }
+
+    let content_type = event
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let input = if content_type.starts_with("multipart/form-data") {
+        parse_multipart_tell(content_type, Bytes::copy_from_slice(event.body())).await?
+    } else {
+        parse_json_tell(event.body())?
+    };
+    if input.text.trim().is_empty() && input.audio.is_none() {
+        return Err("text cannot be an empty string".to_string());
+    }
+
+    let username = event
+        .query_string_parameters_ref()
+        .and_then(|p| p.first("username"))
+        .ok_or("missing username query param")?
+        .to_string();
+
+    Ok((username, input))
+}
+
+fn parse_json_tell(body: &[u8]) -> Result<TellInput, String> {
+    let body: RequestBodyTell = serde_json::from_slice(body).map_err(|_| "Invalid JSON body")?;
+    if body.images.len() > MAX_IMAGES {
+        return Err(AttachmentError::TooMany { max: MAX_IMAGES }.to_string());
+    }
+
+    let max_bytes = max_image_bytes();
+    let images = body
+        .images
+        .iter()
+        .map(|image| {
+            let data = BASE64
+                .decode(&image.data)
+                .map_err(|_| "Invalid base64 image data".to_string())?;
+            Attachment::image(image.content_type.as_deref(), data.into(), max_bytes)
+                .map_err(|e| e.to_string())
+        })
+        .collect::<Result<_, _>>()?;
+    let audio = body
+        .audio
+        .map(|audio| {
+            let data = BASE64
+                .decode(&audio.data)
+                .map_err(|_| "Invalid base64 audio data".to_string())?;
+            Attachment::audio(
+                audio.content_type.as_deref(),
+                data.into(),
+                max_audio_bytes(),
+            )
+            .map_err(|e| e.to_string())
+        })
+        .transpose()?;
+    Ok(TellInput {
+        text: body.text,
+        images,
+        audio,
+        ..TellInput::default()
+    })
+}
+
+async fn parse_multipart_tell(content_type: &str, body: Bytes) -> Result<TellInput, String> {
+    let boundary = multer::parse_boundary(content_type).map_err(|_| "Invalid multipart body")?;
+    let stream = futures::stream::once(async move { Ok::<_, std::convert::Infallible>(body) });
+    let mut multipart = multer::Multipart::new(stream, boundary);
+
+    let max_bytes = max_image_bytes();
+    let mut input = TellInput::default();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|_| "Invalid multipart body")?
+    {
+        match field.name() {
+            Some("text") => {
+                input.text = field.text().await.map_err(|_| "Invalid multipart body")?;
+            }
+            Some("image") => {
+                if input.images.len() == MAX_IMAGES {
+                    return Err(AttachmentError::TooMany { max: MAX_IMAGES }.to_string());
+                }
+                let declared = field.content_type().map(|m| m.to_string());
+                let data = field.bytes().await.map_err(|_| "Invalid multipart body")?;
+                let image = Attachment::image(declared.as_deref(), data, max_bytes)
+                    .map_err(|e| e.to_string())?;
+                input.images.push(image);
+            }
+            Some("audio") => {
+                if input.audio.is_some() {
+                    return Err("At most one audio clip per tell".to_string());
+                }
+                let declared = field.content_type().map(|m| m.to_string());
+                let data = field.bytes().await.map_err(|_| "Invalid multipart body")?;
+                let audio = Attachment::audio(declared.as_deref(), data, max_audio_bytes())
+                    .map_err(|e| e.to_string())?;
+                input.audio = Some(audio);
+            }
+            _ => {}
+        }
+    }
+    Ok(input)
+}
+
+// TODO: Validate if user exists
+async fn post_tell(event: Request) -> Result<Response<Body>, Error> {
+    let (username, input) = match parse_tell_request(&event).await {
+        Ok(data) => data,
+        Err(msg) => {
+            // Is there a way to not include None keys?
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    if let Some(res) = quota_exceeded_response(&username).await? {
+        return Ok(res);
+    }
+
+    // NOTE: This is commented due to bad logging/error display. Find a way to
+    //   better log errors and uncomment to allow for better client experience.
+    // let answer = match tell(&username, input, None).await {
+    //     Ok(data) => data,
+    //     Err(_) => {
+    //         // TODO: Refactor duplicate return error logic
+    //         let data = ResponseBody {
+    //             success: false,
+    //             error_message: Some("Oops! An error occurred when telling your story.".to_string()),
+    //         };
+    //         return Ok(Response::builder()
+    //             .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+    //             .header("content-type", "application/json")
+    //             .body(serde_json::to_string(&data)?.into())
+    //             .map_err(Box::new)?);
+    //     }
+    // };
+    let answer = tell(&username, input, None).await?;
+
+    let data = ResponseBodyTell {
+        base: ResponseBody {
+            success: true,
+            error_message: None,
+        },
+        tell: Some(answer),
+    };
+
+    let res = Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Creates a new user.
+/// TODO: Handle errors gracefully.
+async fn post_user_create(event: Request) -> Result<Response<Body>, Error> {
+    // TODO: Determine what should be here!
+    // let path_param = event.path_parameters_ref();
+    // if path_param.map_or(true, |p| p.is_empty()) {
+    //     return Err("Invalid path parameter".into());
+    // }
+    let data: RequestBodyPostUserCreate =
+        serde_json::from_slice(event.body()).map_err(|_| "Invalid JSON body")?;
+
+    let data = User {
+        tid: uuid::Uuid::new_v4().to_string(),
+        name: data.name,
+        email: data.email,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        current_mood: None,
+        persona: data.persona,
+        locale: data.locale,
+    };
+
+    create_user(&data).await?;
+
+    // TODO: Correct response format
+    let res = Response::builder()
+        .status(http::StatusCode::CREATED)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Changes the persona Teal answers the user in.
+async fn post_user_persona(event: Request) -> Result<Response<Body>, Error> {
+    fn parse_request(event: &Request) -> Result<(String, Persona), String> {
+        let username = event
+            .query_string_parameters_ref()
+            .and_then(|p| p.first("username"))
+            .ok_or("missing username query param")?
+            .to_string();
+        let body: RequestBodyUserPersona =
+            serde_json::from_slice(event.body()).map_err(|_| "Invalid persona")?;
+        Ok((username, body.persona))
+    }
+
+    let (username, persona) = match parse_request(&event) {
+        Ok(data) => data,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let (status, data) = if set_user_persona(&username, persona).await? {
+        (
+            http::StatusCode::OK,
+            ResponseBody {
+                success: true,
+                error_message: None,
+            },
+        )
+    } else {
+        (
+            http::StatusCode::NOT_FOUND,
+            ResponseBody {
+                success: false,
+                error_message: Some("User not found".to_string()),
+            },
+        )
+    };
+
+    let res = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Changes the language Teal answers the user in, e.g. `{"locale": "es"}`.
+async fn post_user_locale(event: Request) -> Result<Response<Body>, Error> {
+    fn parse_request(event: &Request) -> Result<(String, Locale), String> {
+        let username = event
+            .query_string_parameters_ref()
+            .and_then(|p| p.first("username"))
+            .ok_or("missing username query param")?
+            .to_string();
+        let body: RequestBodyUserLocale =
+            serde_json::from_slice(event.body()).map_err(|_| "Invalid locale")?;
+        Ok((username, body.locale))
+    }
+
+    let (username, locale) = match parse_request(&event) {
+        Ok(data) => data,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let (status, data) = if set_user_locale(&username, locale).await? {
+        (
+            http::StatusCode::OK,
+            ResponseBody {
+                success: true,
+                error_message: None,
+            },
+        )
+    } else {
+        (
+            http::StatusCode::NOT_FOUND,
+            ResponseBody {
+                success: false,
+                error_message: Some("User not found".to_string()),
+            },
+        )
+    };
+
+    let res = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+fn parse_tell_feedback(event: &Request) -> Result<(String, RequestBodyTellFeedback), String> {
+    let username = event
+        .query_string_parameters_ref()
+        .and_then(|p| p.first("username"))
+        .ok_or("missing username query param")?
+        .to_string();
+    let body: RequestBodyTellFeedback =
+        serde_json::from_slice(event.body()).map_err(|_| "Invalid feedback")?;
+    if !(MIN_RATING..=MAX_RATING).contains(&body.rating) {
+        return Err(format!(
+            "rating must be between {} and {}",
+            MIN_RATING, MAX_RATING
+        ));
+    }
+    Ok((username, body))
+}
+
+/// Rates the answer to one of the user's tells. Rating it again replaces the earlier rating.
+async fn post_tell_feedback(event: Request) -> Result<Response<Body>, Error> {
+    let (username, body) = match parse_tell_feedback(&event) {
+        Ok(data) => data,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let (status, data) = if rate_tell(&username, &body.tid, body.rating, body.comment).await? {
+        (
+            http::StatusCode::OK,
+            ResponseBody {
+                success: true,
+                error_message: None,
+            },
+        )
+    } else {
+        (
+            http::StatusCode::NOT_FOUND,
+            ResponseBody {
+                success: false,
+                error_message: Some("Tell not found".to_string()),
+            },
+        )
+    };
+
+    let res = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+async fn get_tells_by_user(event: Request) -> Result<Response<Body>, Error> {
+    let username = match event
+        .query_string_parameters_ref()
+        .and_then(|p| p.first("username"))
+    {
+        Some(u) => u.to_string(),
+        None => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some("missing username query param".to_string()),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let tells = match get_user_tells(&username).await {
+        Ok(t) => t,
+        Err(e) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(format!("Failed to retrieve tells: {}", e)),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let data = ResponseBodyTells {
+        base: ResponseBody {
+            success: true,
+            error_message: None,
+        },
+        tells: Some(tells),
+    };
+
+    let res = Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Builds the 429 response for a user over the monthly token quota, if they are.
+async fn quota_exceeded_response(username: &str) -> Result<Option<Response<Body>>, Error> {
+    if !is_over_quota(username).await? {
+        return Ok(None);
+    }
+    let data = ResponseBody {
+        success: false,
+        error_message: Some("Monthly token quota exceeded".to_string()),
+    };
+    let res = Response::builder()
+        .status(http::StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+    Ok(Some(res))
+}
+
+/// Reads the required `username` query param.
+fn parse_username(event: &Request) -> Result<String, String> {
+    event
+        .query_string_parameters_ref()
+        .and_then(|p| p.first("username"))
+        .map(str::to_string)
+        .ok_or_else(|| "missing username query param".to_string())
+}
+
+/// Rolls up every stored tell summary of the user into a long-term memory paragraph.
+async fn get_memory_by_user(event: Request) -> Result<Response<Body>, Error> {
+    let username = match parse_username(&event) {
+        Ok(username) => username,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    if let Some(res) = quota_exceeded_response(&username).await? {
+        return Ok(res);
+    }
+
+    let memory = match get_memory(&username).await {
+        Ok(memory) => memory,
+        Err(e) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(format!("Failed to roll up memory: {}", e)),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let data = ResponseBodyMemory {
+        base: ResponseBody {
+            success: true,
+            error_message: None,
+        },
+        memory: Some(memory),
+    };
+
+    let res = Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// The week to reflect on: any day of it as `?week=YYYY-MM-DD`, the current week by default.
+fn parse_reflection_request(event: &Request) -> Result<(String, NaiveDate), String> {
+    let username = parse_username(event)?;
+    let date = match event
+        .query_string_parameters_ref()
+        .and_then(|p| p.first("week"))
+    {
+        Some(week) => NaiveDate::parse_from_str(week, "%Y-%m-%d")
+            .map_err(|_| "week must be formatted as YYYY-MM-DD".to_string())?,
+        None => Utc::now().date_naive(),
+    };
+    Ok((username, date))
+}
+
+/// A letter looking back on a week of the user's tells.
+async fn get_reflection_by_user(event: Request) -> Result<Response<Body>, Error> {
+    let (username, date) = match parse_reflection_request(&event) {
+        Ok(data) => data,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    if let Some(res) = quota_exceeded_response(&username).await? {
+        return Ok(res);
+    }
+
+    let (status, data) = match get_weekly_reflection(&username, date).await {
+        Ok(Some(letter)) => (
+            http::StatusCode::OK,
+            ResponseBodyReflection {
+                base: ResponseBody {
+                    success: true,
+                    error_message: None,
+                },
+                reflection: Some(letter),
+            },
+        ),
+        Ok(None) => (
+            http::StatusCode::NOT_FOUND,
+            ResponseBodyReflection {
+                base: ResponseBody {
+                    success: false,
+                    error_message: Some("No tells in that week".to_string()),
+                },
+                reflection: None,
+            },
+        ),
+        Err(e) => (
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            ResponseBodyReflection {
+                base: ResponseBody {
+                    success: false,
+                    error_message: Some(format!("Failed to write reflection: {}", e)),
+                },
+                reflection: None,
+            },
+        ),
+    };
+
+    let res = Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Something for the user to write about today.
+async fn get_suggestion_by_user(event: Request) -> Result<Response<Body>, Error> {
+    let username = match parse_username(&event) {
+        Ok(username) => username,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    if let Some(res) = quota_exceeded_response(&username).await? {
+        return Ok(res);
+    }
+
+    let suggestion = match get_daily_suggestion(&username).await {
+        Ok(suggestion) => suggestion,
+        Err(e) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(format!("Failed to suggest a theme: {}", e)),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let data = ResponseBodySuggestion {
+        base: ResponseBody {
+            success: true,
+            error_message: None,
+        },
+        suggestion: Some(suggestion),
+    };
+
+    let res = Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Returns a user's token usage and cost for `month` (`YYYY-MM`, defaults to the current month).
+async fn get_usage_by_user(event: Request) -> Result<Response<Body>, Error> {
+    fn parse_request(event: &Request) -> Result<(String, Month), String> {
+        let params = event.query_string_parameters_ref();
+        let username = params
+            .and_then(|p| p.first("username"))
+            .ok_or("missing username query param")?
+            .to_string();
+        let month = match params.and_then(|p| p.first("month")) {
+            Some(m) => Month::parse(m).map_err(|e| e.to_string())?,
+            None => Month::current(),
+        };
+        Ok((username, month))
+    }
+
+    let (username, month) = match parse_request(&event) {
+        Ok(data) => data,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let usage = match get_monthly_usage(&username, month).await {
+        Ok(u) => u,
+        Err(e) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(format!("Failed to retrieve usage: {}", e)),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
+
+    let data = ResponseBodyUsage {
+        base: ResponseBody {
+            success: true,
+            error_message: None,
+        },
+        usage: Some(usage),
+    };
+
+    let res = Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&data)?.into())
+        .map_err(Box::new)?;
+
+    Ok(res)
+}
+
+/// Returns a stored attachment of one of the user's tells.
+async fn get_attachment(event: Request) -> Result<Response<Body>, Error> {
+    fn parse_request(event: &Request) -> Result<(String, String), String> {
+        let params = event.query_string_parameters_ref();
+        let username = params
+            .and_then(|p| p.first("username"))
+            .ok_or("missing username query param")?
+            .to_string();
+        let key = params
+            .and_then(|p| p.first("key"))
+            .ok_or("missing key query param")?
+            .to_string();
+        Ok((username, key))
+    }
+
+    let (username, key) = match parse_request(&event) {
+        Ok(data) => data,
+        Err(msg) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(msg),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
         }
-    }
-}
+    };
 
-// TODO: Validate if user exists
-async fn post_tell(event: Request) -> Result<Response<Body>, Error> {
-    fn parse_request(event: &Request) -> Result<(String, RequestBodyTell), String> {
-        if event.body().is_empty() {
-            return Err("Request body required".to_string());
-        }
+    // Only keys recorded on the user's own tells are served.
+    let attachment = get_user_tells(&username)
+        .await?
+        .into_iter()
+        .flat_map(|t| t.attachments.into_iter().chain(t.audio))
+        .find(|a| a.key == key);
+    let data = match &attachment {
+        Some(attachment) => use_store().get(&attachment.key).await?,
+        None => None,
+    };
+    let (Some(attachment), Some(data)) = (attachment, data) else {
+        let data = ResponseBody {
+            success: false,
+            error_message: Some("Attachment not found".to_string()),
+        };
+        return Ok(Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&data)?.into())
+            .map_err(Box::new)?);
+    };
 
-        let body: RequestBodyTell =
-            serde_json::from_slice(event.body()).map_err(|_| "Invalid JSON body")?;
-        if body.text.trim().is_empty() {
-            return Err("text cannot be an empty string".to_string());
-        }
+    let res = Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", attachment.content_type)
+        .body(Body::Binary(data.to_vec()))
+        .map_err(Box::new)?;
 
-        let username = event
-            .query_string_parameters_ref()
-            .and_then(|p| p.first("username"))
-            .ok_or("missing username query param")?
-            .to_string();
+    Ok(res)
+}
+
+/// Admin routes require an `x-admin-token` header matching the `TEAL_ADMIN_TOKEN` secret, and are
+/// disabled while that secret is unset.
+async fn is_admin(secrets: &dyn SecretSource, event: &Request) -> anyhow::Result<bool> {
+    let Some(token) = secrets.get("TEAL_ADMIN_TOKEN").await? else {
+        return Ok(false);
+    };
+    Ok(!token.is_empty()
+        && event
+            .headers()
+            .get("x-admin-token")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v == token))
+}
 
-        Ok((username, body))
+fn parse_trace_filter(event: &Request) -> Result<TraceFilter, String> {
+    let params = event.query_string_parameters_ref();
+    match (
+        params.and_then(|p| p.first("username")),
+        params.and_then(|p| p.first("tid")),
+    ) {
+        (Some(username), None) => Ok(TraceFilter::User(username.to_string())),
+        (None, Some(tid)) => Ok(TraceFilter::Tell(tid.to_string())),
+        (Some(_), Some(_)) => Err("pass either a username or a tid query param".to_string()),
+        (None, None) => Err("missing username or tid query param".to_string()),
     }
+}
 
-    let (username, body) = match parse_request(&event) {
-        Ok(data) => data,
+/// Lists the LLM call traces of a user (`?username=`) or of a single tell (`?tid=`).
+async fn get_traces_admin(event: Request) -> Result<Response<Body>, Error> {
+    if !is_admin(use_secrets().as_ref(), &event).await? {
+        let data = ResponseBody {
+            success: false,
+            error_message: Some("Forbidden".to_string()),
+        };
+        return Ok(Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&data)?.into())
+            .map_err(Box::new)?);
+    }
+
+    let filter = match parse_trace_filter(&event) {
+        Ok(filter) => filter,
         Err(msg) => {
-            // Is there a way to not include None keys?
             let data = ResponseBody {
                 success: false,
                 error_message: Some(msg),
@@ -97,31 +984,27 @@ async fn post_tell(event: Request) -> Result<Response<Body>, Error> {
         }
     };
 
-    // NOTE: This is commented due to bad logging/error display. Find a way to
-    //   better log errors and uncomment to allow for better client experience.
-    // let answer = match tell(&username, &body.text, None).await {
-    //     Ok(data) => data,
-    //     Err(_) => {
-    //         // TODO: Refactor duplicate return error logic
-    //         let data = ResponseBody {
-    //             success: false,
-    //             error_message: Some("Oops! An error occurred when telling your story.".to_string()),
-    //         };
-    //         return Ok(Response::builder()
-    //             .status(http::StatusCode::UNPROCESSABLE_ENTITY)
-    //             .header("content-type", "application/json")
-    //             .body(serde_json::to_string(&data)?.into())
-    //             .map_err(Box::new)?);
-    //     }
-    // };
-    let answer = tell(&username, &body.text, None).await?;
+    let traces = match get_traces(&filter).await {
+        Ok(traces) => traces,
+        Err(e) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(format!("Failed to retrieve traces: {}", e)),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
+    };
 
-    let data = ResponseBodyTell {
+    let data = ResponseBodyTraces {
         base: ResponseBody {
             success: true,
             error_message: None,
         },
-        tell: Some(answer),
+        traces: Some(traces),
     };
 
     let res = Response::builder()
@@ -133,30 +1016,50 @@ async fn post_tell(event: Request) -> Result<Response<Body>, Error> {
     Ok(res)
 }
 
-/// Creates a new user.
-/// TODO: Handle errors gracefully.
-async fn post_user_create(event: Request) -> Result<Response<Body>, Error> {
-    // TODO: Determine what should be here!
-    // let path_param = event.path_parameters_ref();
-    // if path_param.map_or(true, |p| p.is_empty()) {
-    //     return Err("Invalid path parameter".into());
-    // }
-    let data: RequestBodyPostUserCreate =
-        serde_json::from_slice(event.body()).map_err(|_| "Invalid JSON body")?;
+/// Answer ratings grouped by prompt version, for `?prompt=` (defaults to `tell.md`).
+async fn get_feedback_admin(event: Request) -> Result<Response<Body>, Error> {
+    if !is_admin(use_secrets().as_ref(), &event).await? {
+        let data = ResponseBody {
+            success: false,
+            error_message: Some("Forbidden".to_string()),
+        };
+        return Ok(Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&data)?.into())
+            .map_err(Box::new)?);
+    }
 
-    let data = User {
-        tid: uuid::Uuid::new_v4().to_string(),
-        name: data.name,
-        email: data.email,
-        created_at: chrono::Utc::now().to_rfc3339(),
-        current_mood: None,
+    let prompt = event
+        .query_string_parameters_ref()
+        .and_then(|p| p.first("prompt"))
+        .unwrap_or(PromptName::Tell.as_str())
+        .to_string();
+    let report = match get_feedback_report(&prompt).await {
+        Ok(report) => report,
+        Err(e) => {
+            let data = ResponseBody {
+                success: false,
+                error_message: Some(format!("Failed to retrieve feedback: {}", e)),
+            };
+            return Ok(Response::builder()
+                .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                .header("content-type", "application/json")
+                .body(serde_json::to_string(&data)?.into())
+                .map_err(Box::new)?);
+        }
     };
 
-    create_user(&data).await?;
+    let data = ResponseBodyFeedbackReport {
+        base: ResponseBody {
+            success: true,
+            error_message: None,
+        },
+        report: Some(report),
+    };
 
-    // TODO: Correct response format
     let res = Response::builder()
-        .status(http::StatusCode::CREATED)
+        .status(http::StatusCode::OK)
         .header("content-type", "application/json")
         .body(serde_json::to_string(&data)?.into())
         .map_err(Box::new)?;
@@ -164,31 +1067,41 @@ async fn post_user_create(event: Request) -> Result<Response<Body>, Error> {
     Ok(res)
 }
 
-async fn get_tells_by_user(event: Request) -> Result<Response<Body>, Error> {
-    let username = match event
+/// Compares the variants of a prompt experiment, e.g. `?experiment=shorter-answers`. Admins only.
+async fn get_experiments_admin(event: Request) -> Result<Response<Body>, Error> {
+    if !is_admin(use_secrets().as_ref(), &event).await? {
+        let data = ResponseBody {
+            success: false,
+            error_message: Some("Forbidden".to_string()),
+        };
+        return Ok(Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&data)?.into())
+            .map_err(Box::new)?);
+    }
+
+    let Some(experiment) = event
         .query_string_parameters_ref()
-        .and_then(|p| p.first("username"))
-    {
-        Some(u) => u.to_string(),
-        None => {
-            let data = ResponseBody {
-                success: false,
-                error_message: Some("missing username query param".to_string()),
-            };
-            return Ok(Response::builder()
-                .status(http::StatusCode::UNPROCESSABLE_ENTITY)
-                .header("content-type", "application/json")
-                .body(serde_json::to_string(&data)?.into())
-                .map_err(Box::new)?);
-        }
+        .and_then(|p| p.first("experiment"))
+        .map(str::to_string)
+    else {
+        let data = ResponseBody {
+            success: false,
+            error_message: Some("missing experiment query param".to_string()),
+        };
+        return Ok(Response::builder()
+            .status(http::StatusCode::UNPROCESSABLE_ENTITY)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&data)?.into())
+            .map_err(Box::new)?);
     };
-
-    let tells = match get_user_tells(&username).await {
-        Ok(t) => t,
+    let report = match get_experiment_report(&experiment).await {
+        Ok(report) => report,
         Err(e) => {
             let data = ResponseBody {
                 success: false,
-                error_message: Some(format!("Failed to retrieve tells: {}", e)),
+                error_message: Some(format!("Failed to retrieve experiment: {}", e)),
             };
             return Ok(Response::builder()
                 .status(http::StatusCode::INTERNAL_SERVER_ERROR)
@@ -198,12 +1111,12 @@ async fn get_tells_by_user(event: Request) -> Result<Response<Body>, Error> {
         }
     };
 
-    let data = ResponseBodyTells {
+    let data = ResponseBodyExperimentReport {
         base: ResponseBody {
             success: true,
             error_message: None,
         },
-        tells: Some(tells),
+        report: Some(report),
     };
 
     let res = Response::builder()
@@ -219,6 +1132,8 @@ async fn get_tells_by_user(event: Request) -> Result<Response<Body>, Error> {
 mod tests {
     use super::*;
     use lambda_http::{http::Method, Body, Request};
+    use serde_json::json;
+    use std::collections::HashMap;
 
     #[tokio::test]
     async fn test_route_not_found() {
@@ -273,6 +1188,8 @@ mod tests {
     async fn test_post_tell_empty_text() {
         let request_body = RequestBodyTell {
             text: "".to_string(),
+            images: Vec::new(),
+            audio: None,
         };
         let json = serde_json::to_string(&request_body).unwrap();
 
@@ -294,6 +1211,8 @@ mod tests {
     async fn test_post_tell_missing_username() {
         let request_body = RequestBodyTell {
             text: "I'm feeling great!".to_string(),
+            images: Vec::new(),
+            audio: None,
         };
         let json = serde_json::to_string(&request_body).unwrap();
 
@@ -310,10 +1229,250 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_get_usage_missing_username() {
+        let request = create_test_request(Method::GET, "/usage", Body::Empty);
+
+        let response = function_handler(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body.error_message,
+            Some("missing username query param".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_usage_invalid_month() {
+        let request = create_test_request(Method::GET, "/usage", Body::Empty)
+            .with_query_string_parameters(HashMap::from([
+                ("username".to_string(), "testuser".to_string()),
+                ("month".to_string(), "2025-13".to_string()),
+            ]));
+
+        let response = function_handler(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body.error_message,
+            Some("Month must be between 01 and 12".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_user_persona_invalid_persona() {
+        let request = create_test_request(
+            Method::POST,
+            "/user/persona",
+            Body::Text("{\"persona\": \"pirate\"}".to_string()),
+        )
+        .with_query_string_parameters(HashMap::from([(
+            "username".to_string(),
+            "testuser".to_string(),
+        )]));
+
+        let response = function_handler(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.error_message, Some("Invalid persona".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_post_user_locale_invalid_locale() {
+        let request = create_test_request(
+            Method::POST,
+            "/user/locale",
+            Body::Text("{\"locale\": \"Spanish\"}".to_string()),
+        )
+        .with_query_string_parameters(HashMap::from([(
+            "username".to_string(),
+            "testuser".to_string(),
+        )]));
+
+        let response = function_handler(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body.error_message, Some("Invalid locale".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_get_attachment_missing_key() {
+        let request = create_test_request(Method::GET, "/attachment", Body::Empty)
+            .with_query_string_parameters(HashMap::from([(
+                "username".to_string(),
+                "testuser".to_string(),
+            )]));
+
+        let response = function_handler(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(
+            body.error_message,
+            Some("missing key query param".to_string())
+        );
+    }
+
+    #[test]
+    fn test_request_body_user_create_with_persona() {
+        let parsed: RequestBodyPostUserCreate = serde_json::from_str(
+            r#"{"name": "Jane", "email": "jane@example.com", "persona": "concise"}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.persona, Some(Persona::Concise));
+        assert_eq!(parsed.locale, None);
+
+        let parsed: RequestBodyPostUserCreate = serde_json::from_str(
+            r#"{"name": "Lucía", "email": "lucia@example.com", "locale": "es-MX"}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.locale, Some(Locale::parse("es-mx").unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_parse_tell_with_base64_image() {
+        use crate::attachments::PNG_BYTES;
+
+        let body = json!({
+            "text": "This view made my day",
+            "images": [{ "content_type": "image/png", "data": BASE64.encode(PNG_BYTES) }],
+        });
+        let request = create_test_request(Method::POST, "/tell", Body::Text(body.to_string()))
+            .with_query_string_parameters(HashMap::from([(
+                "username".to_string(),
+                "testuser".to_string(),
+            )]));
+
+        let (username, input) = parse_tell_request(&request).await.unwrap();
+        assert_eq!(username, "testuser");
+        assert_eq!(input.text, "This view made my day");
+        assert_eq!(input.images[0].content_type, "image/png");
+        assert_eq!(input.images[0].data, PNG_BYTES);
+    }
+
+    #[tokio::test]
+    async fn test_post_tell_rejects_invalid_images() {
+        for (images, error) in [
+            (
+                json!([{ "data": "not base64!" }]),
+                "Invalid base64 image data",
+            ),
+            (
+                json!([{ "content_type": "image/gif", "data": BASE64.encode(b"GIF89a") }]),
+                "Unsupported image type 'image/gif'",
+            ),
+            (
+                json!(vec![json!({ "data": "" }); MAX_IMAGES + 1]),
+                "At most 4 images per tell",
+            ),
+        ] {
+            let body = json!({ "text": "Look at this", "images": images });
+            let request = create_test_request(
+                Method::POST,
+                "/tell?username=testuser",
+                Body::Text(body.to_string()),
+            );
+
+            let response = function_handler(request).await.unwrap();
+            assert_eq!(response.status(), 422);
+            let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body.error_message, Some(error.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_voice_tell() {
+        use crate::attachments::WAV_BYTES;
+
+        let body = json!({
+            "audio": { "content_type": "audio/wav", "data": BASE64.encode(WAV_BYTES) },
+        });
+        let request = create_test_request(Method::POST, "/tell", Body::Text(body.to_string()))
+            .with_query_string_parameters(HashMap::from([(
+                "username".to_string(),
+                "testuser".to_string(),
+            )]));
+
+        let (_, input) = parse_tell_request(&request).await.unwrap();
+        assert_eq!(input.text, "");
+        let audio = input.audio.unwrap();
+        assert_eq!(audio.content_type, "audio/wav");
+        assert_eq!(audio.data, WAV_BYTES);
+    }
+
+    #[tokio::test]
+    async fn test_post_tell_rejects_invalid_audio() {
+        for (audio, error) in [
+            (
+                json!({ "data": "not base64!" }),
+                "Invalid base64 audio data",
+            ),
+            (
+                json!({ "content_type": "audio/webm", "data": BASE64.encode(b"\x1a\x45\xdf\xa3") }),
+                "Unsupported audio type 'audio/webm'",
+            ),
+        ] {
+            let body = json!({ "audio": audio });
+            let request = create_test_request(
+                Method::POST,
+                "/tell?username=testuser",
+                Body::Text(body.to_string()),
+            );
+
+            let response = function_handler(request).await.unwrap();
+            assert_eq!(response.status(), 422);
+            let body: ResponseBody = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(body.error_message, Some(error.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_multipart_tell() {
+        use crate::attachments::{PNG_BYTES, WAV_BYTES};
+
+        let mut body = b"--XYZ\r\n\
+            Content-Disposition: form-data; name=\"text\"\r\n\r\n\
+            This view made my day\r\n\
+            --XYZ\r\n\
+            Content-Disposition: form-data; name=\"image\"; filename=\"view.png\"\r\n\
+            Content-Type: image/png\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(PNG_BYTES);
+        body.extend_from_slice(
+            b"\r\n--XYZ\r\n\
+            Content-Disposition: form-data; name=\"audio\"; filename=\"note.wav\"\r\n\
+            Content-Type: audio/wav\r\n\r\n",
+        );
+        body.extend_from_slice(WAV_BYTES);
+        body.extend_from_slice(b"\r\n--XYZ--\r\n");
+
+        let mut request = create_test_request(Method::POST, "/tell", Body::Binary(body))
+            .with_query_string_parameters(HashMap::from([(
+                "username".to_string(),
+                "testuser".to_string(),
+            )]));
+        request.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            "multipart/form-data; boundary=XYZ".parse().unwrap(),
+        );
+
+        let (_, input) = parse_tell_request(&request).await.unwrap();
+        assert_eq!(input.text, "This view made my day");
+        assert_eq!(input.images.len(), 1);
+        assert_eq!(input.images[0].data, PNG_BYTES);
+        assert_eq!(input.audio.unwrap().data, WAV_BYTES);
+    }
+
     #[test]
     fn test_request_body_tell_serialization() {
         let body = RequestBodyTell {
             text: "Hello world".to_string(),
+            images: Vec::new(),
+            audio: None,
         };
         let json = serde_json::to_string(&body).unwrap();
         assert!(json.contains("Hello world"));
@@ -327,6 +1486,8 @@ mod tests {
         let body = RequestBodyPostUserCreate {
             name: "Jane Doe".to_string(),
             email: "jane@example.com".to_string(),
+            persona: None,
+            locale: None,
         };
         let json = serde_json::to_string(&body).unwrap();
         assert!(json.contains("Jane Doe"));
@@ -354,4 +1515,114 @@ mod tests {
         assert!(json.contains("false"));
         assert!(json.contains("Error occurred"));
     }
+
+    #[tokio::test]
+    async fn test_is_admin() {
+        use crate::secrets::StaticSecrets;
+
+        let secrets = StaticSecrets::with("TEAL_ADMIN_TOKEN", "s3cret");
+        let mut request = create_test_request(Method::GET, "/admin/traces", Body::Empty);
+        assert!(!is_admin(secrets.as_ref(), &request).await.unwrap());
+
+        request
+            .headers_mut()
+            .insert("x-admin-token", "wrong".parse().unwrap());
+        assert!(!is_admin(secrets.as_ref(), &request).await.unwrap());
+
+        request
+            .headers_mut()
+            .insert("x-admin-token", "s3cret".parse().unwrap());
+        assert!(is_admin(secrets.as_ref(), &request).await.unwrap());
+
+        let unset = StaticSecrets::with("OTHER", "s3cret");
+        assert!(!is_admin(unset.as_ref(), &request).await.unwrap());
+    }
+
+    #[test]
+    fn test_parse_trace_filter() {
+        let request = |params: &[(&str, &str)]| {
+            create_test_request(Method::GET, "/admin/traces", Body::Empty)
+                .with_query_string_parameters(
+                    params
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect::<HashMap<_, _>>(),
+                )
+        };
+
+        assert_eq!(
+            parse_trace_filter(&request(&[("username", "jane")])),
+            Ok(TraceFilter::User("jane".to_string()))
+        );
+        assert_eq!(
+            parse_trace_filter(&request(&[("tid", "t1")])),
+            Ok(TraceFilter::Tell("t1".to_string()))
+        );
+        assert!(parse_trace_filter(&request(&[("username", "jane"), ("tid", "t1")])).is_err());
+        assert_eq!(
+            parse_trace_filter(&request(&[])),
+            Err("missing username or tid query param".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_tell_feedback() {
+        let request = |body: &str| {
+            create_test_request(Method::POST, "/tell/feedback", Body::Text(body.to_string()))
+                .with_query_string_parameters(HashMap::from([(
+                    "username".to_string(),
+                    "jane".to_string(),
+                )]))
+        };
+
+        let (username, body) =
+            parse_tell_feedback(&request(r#"{"tid": "t1", "rating": 4, "comment": "Kind"}"#))
+                .unwrap();
+        assert_eq!(username, "jane");
+        assert_eq!(body.tid, "t1");
+        assert_eq!(body.rating, 4);
+        assert_eq!(body.comment, Some("Kind".to_string()));
+
+        assert_eq!(
+            parse_tell_feedback(&request(r#"{"tid": "t1", "rating": 6}"#)).err(),
+            Some("rating must be between 1 and 5".to_string())
+        );
+        assert_eq!(
+            parse_tell_feedback(&request(r#"{"tid": "t1"}"#)).err(),
+            Some("Invalid feedback".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_reflection_request() {
+        let request = |params: &[(&str, &str)]| {
+            create_test_request(Method::GET, "/reflection", Body::Empty)
+                .with_query_string_parameters(
+                    params
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect::<HashMap<_, _>>(),
+                )
+        };
+
+        assert_eq!(
+            parse_reflection_request(&request(&[("username", "jane"), ("week", "2024-05-08")])),
+            Ok((
+                "jane".to_string(),
+                NaiveDate::from_ymd_opt(2024, 5, 8).unwrap()
+            ))
+        );
+        assert_eq!(
+            parse_reflection_request(&request(&[("username", "jane")])).map(|(_, d)| d),
+            Ok(Utc::now().date_naive())
+        );
+        assert_eq!(
+            parse_reflection_request(&request(&[("username", "jane"), ("week", "May 8")])),
+            Err("week must be formatted as YYYY-MM-DD".to_string())
+        );
+        assert_eq!(
+            parse_reflection_request(&request(&[])),
+            Err("missing username query param".to_string())
+        );
+    }
 }
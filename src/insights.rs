@@ -0,0 +1,598 @@
+use crate::dynamo::{use_db, MEMORIES_TABLE_NAME};
+use crate::llm::{ask_for, use_llm, LlmFailure, LlmProvider};
+use crate::locale::Locale;
+use crate::prompts::{
+    create_prompt, DailySuggestionReplacements, Entry, MemoryRollupReplacements, Persona,
+    PromptData, PromptName, TellTitleReplacements, WeeklyReflectionReplacements,
+};
+use crate::tell::{get_user_tells, user_preferences, TellItem};
+use crate::traces::{save_traces, LlmTrace};
+use crate::usage::record_trace_usage;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+
+/// Entries folded into the memory per call; longer histories are rolled up in several calls.
+const ROLLUP_CHUNK: usize = 50;
+/// Number of latest tells a daily suggestion builds on.
+const SUGGESTION_TELLS: usize = 7;
+
+/// The answer type of a prompt, parsed from the JSON fields its front-matter declares.
+pub trait PromptOutput: DeserializeOwned {
+    const PROMPT: PromptName;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemoryRollup {
+    pub memory: String,
+}
+
+impl PromptOutput for MemoryRollup {
+    const PROMPT: PromptName = PromptName::MemoryRollup;
+}
+
+/// The memory of a user as last rolled up, so that later calls only fold in newer tells.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredMemory {
+    /// The username, so each user has a single stored memory.
+    pub tid: String,
+    pub username: String,
+    pub memory: String,
+    /// Creation time of the newest tell folded into the memory.
+    pub folded_until: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReflectionLetter {
+    pub letter: String,
+}
+
+impl PromptOutput for ReflectionLetter {
+    const PROMPT: PromptName = PromptName::WeeklyReflection;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TellTitle {
+    pub title: String,
+}
+
+impl PromptOutput for TellTitle {
+    const PROMPT: PromptName = PromptName::TellTitle;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DailySuggestion {
+    pub suggestion: String,
+}
+
+impl PromptOutput for DailySuggestion {
+    const PROMPT: PromptName = PromptName::DailySuggestion;
+}
+
+/// Renders the prompt of `T` with `data` in `locale` and asks the provider for its answer.
+/// `persona` is used unless the prompt declares its own instruction.
+async fn generate<T: PromptOutput>(
+    provider: &dyn LlmProvider,
+    persona: Persona,
+    locale: &Locale,
+    data: PromptData<'_>,
+) -> Result<(T, LlmTrace), LlmFailure> {
+    if data.prompt_name() != T::PROMPT {
+        return Err(anyhow::anyhow!(
+            "Replacements for '{}' passed to prompt '{}'",
+            data.prompt_name().as_str(),
+            T::PROMPT.as_str()
+        )
+        .into());
+    }
+    let prompt = create_prompt(locale, data).await?;
+    ask_for(provider, &prompt.instruction_for(persona)?, &prompt).await
+}
+
+/// Stores the traces of a failed insight and records their usage like those of one that
+/// succeeded, then passes its error on.
+async fn save_failure(username: &str, failure: LlmFailure) -> anyhow::Error {
+    save_insight_traces(username, failure.traces).await;
+    failure.error
+}
+
+/// Stores the traces of an insight and records their usage against the user's month, as
+/// insights are not part of any tell.
+async fn save_insight_traces(username: &str, traces: Vec<LlmTrace>) {
+    record_trace_usage(username, &traces).await;
+    save_traces(username, None, traces).await;
+}
+
+/// The tells as prompt entries, oldest first. Tells stored without a summary show their text.
+fn entries(tells: &[TellItem]) -> Vec<Entry<'_>> {
+    let mut entries: Vec<Entry> = tells
+        .iter()
+        .map(|t| Entry {
+            date: t.created_at.date_naive(),
+            mood: &t.mood,
+            summary: t.summary.as_deref().unwrap_or(&t.tell),
+        })
+        .collect();
+    entries.sort_by_key(|e| e.date);
+    entries
+}
+
+/// Folds the entries into the memory paragraph `rollup`, `ROLLUP_CHUNK` entries at a time. A
+/// failure carries the traces of the chunks folded before it too.
+pub async fn roll_up_memory(
+    provider: &dyn LlmProvider,
+    username: &str,
+    locale: &Locale,
+    mut rollup: MemoryRollup,
+    entries: &[Entry<'_>],
+) -> Result<(MemoryRollup, Vec<LlmTrace>), LlmFailure> {
+    let mut traces = Vec::new();
+    for chunk in entries.chunks(ROLLUP_CHUNK) {
+        let next = generate(
+            provider,
+            Persona::default(),
+            locale,
+            PromptData::MemoryRollup(MemoryRollupReplacements {
+                username,
+                memory: &rollup.memory,
+                entries: chunk,
+            }),
+        )
+        .await;
+        match next {
+            Ok((next, trace)) => {
+                rollup = next;
+                traces.push(trace);
+            }
+            Err(mut failure) => {
+                traces.append(&mut failure.traces);
+                failure.traces = traces;
+                return Err(failure);
+            }
+        }
+    }
+    Ok((rollup, traces))
+}
+
+/// The tells created after `folded_until`, i.e. those a stored memory doesn't hold yet.
+fn unfolded(tells: Vec<TellItem>, folded_until: Option<DateTime<Utc>>) -> Vec<TellItem> {
+    match folded_until {
+        Some(folded_until) => tells
+            .into_iter()
+            .filter(|t| t.created_at > folded_until)
+            .collect(),
+        None => tells,
+    }
+}
+
+async fn load_memory(username: &str) -> anyhow::Result<Option<StoredMemory>> {
+    let memories: Vec<StoredMemory> = use_db().scan(MEMORIES_TABLE_NAME, "tid", username).await?;
+    Ok(memories.into_iter().next())
+}
+
+/// Stores the memory in place of the user's previous one. A failure is only logged, as the
+/// next call rolls up the same tells again.
+async fn store_memory(username: &str, rollup: &MemoryRollup, folded_until: DateTime<Utc>) {
+    let memory = StoredMemory {
+        tid: username.to_string(),
+        username: username.to_string(),
+        memory: rollup.memory.clone(),
+        folded_until,
+        updated_at: Utc::now(),
+    };
+    let item = match to_value(&memory) {
+        Ok(item) => item,
+        Err(e) => {
+            eprintln!("Failed to serialize memory: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = use_db().put(MEMORIES_TABLE_NAME, item).await {
+        eprintln!("Failed to store memory: {:?}", e);
+    }
+}
+
+/// The long-term memory of everything the user has told so far. Only the tells told since the
+/// stored memory was rolled up are folded into it.
+pub async fn get_memory(username: &str) -> anyhow::Result<MemoryRollup> {
+    let stored = load_memory(username).await?;
+    let tells = unfolded(
+        get_user_tells(username).await?,
+        stored.as_ref().map(|m| m.folded_until),
+    );
+    let memory = MemoryRollup {
+        memory: stored.map(|m| m.memory).unwrap_or_default(),
+    };
+    let folded_until = match tells.iter().map(|t| t.created_at).max() {
+        Some(folded_until) => folded_until,
+        None => return Ok(memory),
+    };
+    let locale = user_preferences(username).await.locale;
+    let (rollup, traces) = match roll_up_memory(
+        use_llm().as_ref(),
+        username,
+        &locale,
+        memory,
+        &entries(&tells),
+    )
+    .await
+    {
+        Ok(rollup) => rollup,
+        Err(failure) => return Err(save_failure(username, failure).await),
+    };
+    save_insight_traces(username, traces).await;
+    store_memory(username, &rollup, folded_until).await;
+    Ok(rollup)
+}
+
+/// Monday of the week `date` falls in.
+pub fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+/// A letter looking back on the week `date` falls in, or `None` if the user told nothing that
+/// week.
+pub async fn get_weekly_reflection(
+    username: &str,
+    date: NaiveDate,
+) -> anyhow::Result<Option<ReflectionLetter>> {
+    let start = week_start(date);
+    let end = start + Duration::days(7);
+    let tells: Vec<TellItem> = get_user_tells(username)
+        .await?
+        .into_iter()
+        .filter(|t| (start..end).contains(&t.created_at.date_naive()))
+        .collect();
+    if tells.is_empty() {
+        return Ok(None);
+    }
+
+    let preferences = user_preferences(username).await;
+    let letter = generate(
+        use_llm().as_ref(),
+        preferences.persona,
+        &preferences.locale,
+        PromptData::WeeklyReflection(WeeklyReflectionReplacements {
+            username,
+            week_start: start,
+            entries: &entries(&tells),
+        }),
+    )
+    .await;
+    let (letter, trace) = match letter {
+        Ok(letter) => letter,
+        Err(failure) => return Err(save_failure(username, failure).await),
+    };
+    save_insight_traces(username, vec![trace]).await;
+    Ok(Some(letter))
+}
+
+/// Something to write about today, building on the latest tells.
+pub async fn get_daily_suggestion(username: &str) -> anyhow::Result<DailySuggestion> {
+    let mut tells = get_user_tells(username).await?;
+    tells.truncate(SUGGESTION_TELLS);
+
+    let preferences = user_preferences(username).await;
+    let suggestion = generate(
+        use_llm().as_ref(),
+        preferences.persona,
+        &preferences.locale,
+        PromptData::DailySuggestion(DailySuggestionReplacements {
+            username,
+            date: Utc::now().date_naive(),
+            entries: &entries(&tells),
+        }),
+    )
+    .await;
+    let (suggestion, trace) = match suggestion {
+        Ok(suggestion) => suggestion,
+        Err(failure) => return Err(save_failure(username, failure).await),
+    };
+    save_insight_traces(username, vec![trace]).await;
+    Ok(suggestion)
+}
+
+/// A short title for the tell, in the user's language.
+pub async fn title_tell(
+    provider: &dyn LlmProvider,
+    locale: &Locale,
+    tell: &str,
+) -> Result<(TellTitle, LlmTrace), LlmFailure> {
+    generate(
+        provider,
+        Persona::default(),
+        locale,
+        PromptData::TellTitle(TellTitleReplacements { tell }),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{FinishReason, LlmRequest, LlmResponse, OutputFormat};
+    use crate::traces::ParseOutcome;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+    use std::sync::Mutex;
+
+    /// Answers every call with the same text and keeps the prompts it received.
+    struct FixedProvider {
+        text: &'static str,
+        prompts: Mutex<Vec<String>>,
+    }
+
+    impl FixedProvider {
+        fn new(text: &'static str) -> Self {
+            Self {
+                text,
+                prompts: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmProvider for FixedProvider {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+            assert!(request.output.is_json());
+            self.prompts
+                .lock()
+                .unwrap()
+                .push(request.messages.last().unwrap().text.clone());
+            Ok(LlmResponse {
+                text: self.text.to_string(),
+                provider: "fixed".to_string(),
+                model: "fixed-model".to_string(),
+                usage: None,
+                finish_reason: Some(FinishReason::Stop),
+                tool_calls: Vec::new(),
+            })
+        }
+    }
+
+    fn entry(day: u32, summary: &str) -> Entry<'_> {
+        Entry {
+            date: NaiveDate::from_ymd_opt(2024, 5, day).unwrap(),
+            mood: "calm",
+            summary,
+        }
+    }
+
+    /// A tell of 2024-05-`day` at 9:00.
+    fn tell(day: u32, summary: Option<&str>) -> TellItem {
+        let mut tell = crate::tell::build_tell_record(
+            "jane",
+            "Raw text",
+            &crate::gemini::GeminiTellResponse {
+                answer: String::new(),
+                summary: String::new(),
+                user_state: String::new(),
+                mood: "calm".to_string(),
+            },
+        );
+        tell.created_at = Utc.with_ymd_and_hms(2024, 5, day, 9, 0, 0).unwrap();
+        tell.summary = summary.map(str::to_string);
+        tell
+    }
+
+    /// Field names of an output type, as serialized.
+    fn fields_of<T: Default + Serialize>() -> Vec<String> {
+        serde_json::to_value(T::default())
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    async fn declared_fields(prompt_name: PromptName) -> Vec<String> {
+        let data = match prompt_name {
+            PromptName::MemoryRollup => PromptData::MemoryRollup(MemoryRollupReplacements {
+                username: "Jane",
+                memory: "",
+                entries: &[],
+            }),
+            PromptName::WeeklyReflection => {
+                PromptData::WeeklyReflection(WeeklyReflectionReplacements {
+                    username: "Jane",
+                    week_start: NaiveDate::from_ymd_opt(2024, 5, 6).unwrap(),
+                    entries: &[],
+                })
+            }
+            PromptName::TellTitle => PromptData::TellTitle(TellTitleReplacements { tell: "Hi" }),
+            PromptName::DailySuggestion => {
+                PromptData::DailySuggestion(DailySuggestionReplacements {
+                    username: "Jane",
+                    date: NaiveDate::from_ymd_opt(2024, 5, 6).unwrap(),
+                    entries: &[],
+                })
+            }
+            _ => unreachable!(),
+        };
+        match create_prompt(&Locale::default(), data)
+            .await
+            .unwrap()
+            .output
+        {
+            OutputFormat::Json(fields) => {
+                let mut names: Vec<String> = fields.into_iter().map(|f| f.name).collect();
+                names.sort();
+                names
+            }
+            OutputFormat::Text => panic!("{} should ask for JSON", prompt_name.as_str()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_output_types_match_the_declared_fields() {
+        assert_eq!(
+            fields_of::<MemoryRollup>(),
+            declared_fields(MemoryRollup::PROMPT).await
+        );
+        assert_eq!(
+            fields_of::<ReflectionLetter>(),
+            declared_fields(ReflectionLetter::PROMPT).await
+        );
+        assert_eq!(
+            fields_of::<TellTitle>(),
+            declared_fields(TellTitle::PROMPT).await
+        );
+        assert_eq!(
+            fields_of::<DailySuggestion>(),
+            declared_fields(DailySuggestion::PROMPT).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_title_tell() {
+        let provider = FixedProvider::new(r#"{"title": "A new job offer"}"#);
+        let (title, trace) = title_tell(&provider, &Locale::default(), "I got a job offer today!")
+            .await
+            .unwrap();
+        assert_eq!(title.title, "A new job offer");
+        assert_eq!(trace.template, Some("tell_title.md".to_string()));
+        assert!(provider.prompts.lock().unwrap()[0].contains("I got a job offer today!"));
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_replacements_of_another_prompt() {
+        let provider = FixedProvider::new(r#"{"memory": "unused"}"#);
+        let err = generate::<MemoryRollup>(
+            &provider,
+            Persona::default(),
+            &Locale::default(),
+            PromptData::TellTitle(TellTitleReplacements { tell: "Hi" }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Replacements for 'tell_title.md' passed to prompt 'memory_rollup.md'"
+        );
+        assert!(provider.prompts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_generate_rejects_answers_of_the_wrong_shape() {
+        let provider = FixedProvider::new(r#"{"answer": "Hi"}"#);
+        let err = title_tell(&provider, &Locale::default(), "Hello")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("tell_title.md"));
+        assert_eq!(err.traces.len(), 1);
+        assert_eq!(err.traces[0].outcome, ParseOutcome::SchemaViolation);
+    }
+
+    #[tokio::test]
+    async fn test_roll_up_memory_in_chunks() {
+        let provider = FixedProvider::new(r#"{"memory": "Jane runs."}"#);
+        let summaries: Vec<String> = (0..ROLLUP_CHUNK + 1)
+            .map(|i| format!("Ran {i} km."))
+            .collect();
+        let entries: Vec<Entry> = summaries.iter().map(|s| entry(1, s)).collect();
+
+        let (rollup, traces) = roll_up_memory(
+            &provider,
+            "Jane",
+            &Locale::default(),
+            MemoryRollup::default(),
+            &entries,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rollup.memory, "Jane runs.");
+        assert_eq!(traces.len(), 2);
+
+        let prompts = provider.prompts.lock().unwrap();
+        assert!(prompts[0].contains("- 2024-05-01 (calm): Ran 0 km."));
+        assert!(!prompts[0].contains(&format!("Ran {} km.", ROLLUP_CHUNK)));
+        assert!(prompts[1].contains("Jane runs."));
+        assert!(prompts[1].contains(&format!("Ran {} km.", ROLLUP_CHUNK)));
+    }
+
+    #[tokio::test]
+    async fn test_roll_up_nothing() {
+        let provider = FixedProvider::new(r#"{"memory": "unused"}"#);
+        let (rollup, traces) = roll_up_memory(
+            &provider,
+            "Jane",
+            &Locale::default(),
+            MemoryRollup::default(),
+            &[],
+        )
+        .await
+        .unwrap();
+        assert_eq!(rollup, MemoryRollup::default());
+        assert!(traces.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_roll_up_memory_from_a_stored_memory() {
+        let provider = FixedProvider::new(r#"{"memory": "Jane runs and swims."}"#);
+        let stored = MemoryRollup {
+            memory: "Jane runs.".to_string(),
+        };
+        let (rollup, traces) = roll_up_memory(
+            &provider,
+            "Jane",
+            &Locale::default(),
+            stored,
+            &[entry(2, "Swam 1 km.")],
+        )
+        .await
+        .unwrap();
+        assert_eq!(rollup.memory, "Jane runs and swims.");
+        assert_eq!(traces.len(), 1);
+
+        let prompts = provider.prompts.lock().unwrap();
+        assert!(prompts[0].contains("Jane runs."));
+        assert!(prompts[0].contains("Swam 1 km."));
+    }
+
+    #[test]
+    fn test_unfolded_keeps_the_tells_after_the_stored_memory() {
+        let tells = || vec![tell(3, Some("Went hiking.")), tell(2, None), tell(1, None)];
+        let folded_until = Utc.with_ymd_and_hms(2024, 5, 2, 9, 0, 0).unwrap();
+
+        let days = |tells: Vec<TellItem>| -> Vec<u32> {
+            tells.iter().map(|t| t.created_at.day()).collect()
+        };
+        assert_eq!(days(unfolded(tells(), Some(folded_until))), vec![3]);
+        assert_eq!(days(unfolded(tells(), None)), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn test_entries_are_oldest_first() {
+        let tells = vec![tell(3, Some("Went hiking.")), tell(1, None)];
+
+        let entries = entries(&tells);
+        let summaries: Vec<(u32, &str)> =
+            entries.iter().map(|e| (e.date.day(), e.summary)).collect();
+        assert_eq!(summaries, vec![(1, "Raw text"), (3, "Went hiking.")]);
+    }
+
+    #[test]
+    fn test_week_start() {
+        let monday = NaiveDate::from_ymd_opt(2024, 5, 6).unwrap();
+        assert_eq!(week_start(monday), monday);
+        assert_eq!(
+            week_start(NaiveDate::from_ymd_opt(2024, 5, 12).unwrap()),
+            monday
+        );
+        assert_eq!(
+            week_start(NaiveDate::from_ymd_opt(2024, 5, 13).unwrap()),
+            NaiveDate::from_ymd_opt(2024, 5, 13).unwrap()
+        );
+    }
+}
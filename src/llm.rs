@@ -0,0 +1,1672 @@
+use crate::contract;
+use crate::experiments::Assignment;
+use crate::fallback::FallbackProvider;
+use crate::gemini::{GeminiProvider, GeminiTellResponse};
+use crate::http_client::{http_client_from_env, HttpClient};
+use crate::metrics;
+use crate::openai::OpenAiProvider;
+use crate::prompts::{Prompt, PromptVersion};
+use crate::secrets::{use_secrets, SecretSource};
+use crate::tools::{self, ToolCallRecord, ToolExecutor};
+use crate::traces::{LlmTrace, ParseOutcome};
+use crate::usage::TokenUsage;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+/// Upper bound for the output token budget when retrying truncated answers.
+const MAX_RETRY_OUTPUT_TOKENS: u32 = 2000;
+/// Number of model turns that may call tools before the tools are withdrawn and the model has to
+/// answer.
+const MAX_TOOL_ROUNDS: usize = 3;
+
+const BLOCKED_ANSWER: &str = "Thank you for trusting me with this. It's not something I can respond to well here, but you don't have to carry it alone. Reaching out to someone you trust, or to a professional, can make a real difference right now.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GenerationConfig {
+    pub temperature: f32,
+    pub max_output_tokens: u32,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 0.5,
+            max_output_tokens: 500,
+        }
+    }
+}
+
+/// The shape of the answer a prompt expects.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    /// A JSON object with these string fields, all required. With no fields, any JSON object.
+    Json(Vec<OutputField>),
+}
+
+impl OutputFormat {
+    pub fn is_json(&self) -> bool {
+        matches!(self, OutputFormat::Json(_))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct OutputField {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    User,
+    Model,
+    /// The results of the tool calls made in the preceding model turn.
+    Tool,
+}
+
+/// A function the model may call before answering. `parameters` is a JSON schema object and is
+/// omitted for functions without arguments.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ToolDeclaration {
+    pub name: &'static str,
+    pub description: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<serde_json::Value>,
+}
+
+/// A function call requested by the model. `id` pairs the call with its result; providers that
+/// don't assign ids get a generated one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub args: serde_json::Value,
+}
+
+/// The outcome of a [`ToolCall`], sent back to the model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolResult {
+    pub call_id: String,
+    pub name: String,
+    pub content: serde_json::Value,
+}
+
+/// Binary input sent alongside the text of a turn, e.g. a photo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Media {
+    pub mime_type: String,
+    pub data: Bytes,
+}
+
+/// A single conversation turn.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub role: Role,
+    pub text: String,
+    pub media: Vec<Media>,
+    /// Functions a model turn asked to call.
+    pub tool_calls: Vec<ToolCall>,
+    /// Results carried by a [`Role::Tool`] turn.
+    pub tool_results: Vec<ToolResult>,
+}
+
+impl Message {
+    pub fn user(text: impl Into<String>) -> Self {
+        Self {
+            role: Role::User,
+            text: text.into(),
+            media: Vec::new(),
+            tool_calls: Vec::new(),
+            tool_results: Vec::new(),
+        }
+    }
+
+    pub fn model(text: impl Into<String>) -> Self {
+        Self {
+            role: Role::Model,
+            text: text.into(),
+            media: Vec::new(),
+            tool_calls: Vec::new(),
+            tool_results: Vec::new(),
+        }
+    }
+
+    /// A model turn that called tools, possibly with some text alongside.
+    pub fn tool_calls(text: impl Into<String>, tool_calls: Vec<ToolCall>) -> Self {
+        Self {
+            tool_calls,
+            ..Self::model(text)
+        }
+    }
+
+    pub fn tool_results(tool_results: Vec<ToolResult>) -> Self {
+        Self {
+            role: Role::Tool,
+            text: String::new(),
+            media: Vec::new(),
+            tool_calls: Vec::new(),
+            tool_results,
+        }
+    }
+
+    pub fn with_media(mut self, media: Vec<Media>) -> Self {
+        self.media = media;
+        self
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::user(text)
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::user(text)
+    }
+}
+
+/// A provider-agnostic request. Each provider maps these fields onto its own wire format.
+pub struct LlmRequest {
+    pub system_instruction: String,
+    /// The conversation in chronological order, ending with the turn to answer.
+    pub messages: Vec<Message>,
+    pub generation_config: GenerationConfig,
+    /// Asks the provider to constrain its output, e.g. to a JSON object with the given fields.
+    pub output: OutputFormat,
+    /// Functions the model may call instead of answering.
+    pub tools: Vec<ToolDeclaration>,
+}
+
+impl LlmRequest {
+    /// Sends `prompt` after `history`, with the generation parameters and output format the
+    /// prompt declares.
+    pub fn from_prompt(system_instruction: &str, history: &[Message], prompt: &Prompt) -> Self {
+        let mut messages = history.to_vec();
+        messages.push(prompt.message());
+
+        Self {
+            system_instruction: system_instruction.to_string(),
+            messages,
+            generation_config: prompt.generation_config.clone(),
+            output: prompt.output.clone(),
+            tools: Vec::new(),
+        }
+    }
+}
+
+/// Why the provider stopped generating, normalized across providers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FinishReason {
+    Stop,
+    MaxTokens,
+    /// The prompt or the answer was blocked by safety filters; holds the provider's reason.
+    Blocked(String),
+    Other(String),
+}
+
+/// The raw text of the first candidate, along with the provider and model that produced it and
+/// the token usage reported by the provider.
+pub struct LlmResponse {
+    pub text: String,
+    pub provider: String,
+    pub model: String,
+    pub usage: Option<TokenUsage>,
+    pub finish_reason: Option<FinishReason>,
+    /// Functions the model wants called before it answers.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl LlmResponse {
+    /// Maps finish reasons that leave the answer unusable onto an [`LlmError`].
+    pub fn check_finish(&self, request: &LlmRequest) -> Result<(), LlmError> {
+        match &self.finish_reason {
+            Some(FinishReason::Blocked(reason)) => Err(LlmError::Blocked {
+                reason: reason.clone(),
+            }),
+            Some(FinishReason::MaxTokens) => Err(LlmError::Truncated {
+                max_output_tokens: request.generation_config.max_output_tokens,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LlmError {
+    /// The prompt or the answer was blocked by the provider's safety filters. Users get a gentle
+    /// fallback reply instead of an error.
+    Blocked { reason: String },
+    /// The answer hit the output token limit. It is retried with a larger budget until
+    /// `MAX_RETRY_OUTPUT_TOKENS` is reached.
+    Truncated { max_output_tokens: u32 },
+    /// The answer did not have the shape the prompt declares, even after being asked again.
+    InvalidOutput { prompt: String, reason: String },
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LlmError::Blocked { reason } => {
+                write!(f, "Response blocked by safety filters: {}", reason)
+            }
+            LlmError::Truncated { max_output_tokens } => write!(
+                f,
+                "Response truncated at {} output tokens",
+                max_output_tokens
+            ),
+            LlmError::InvalidOutput { prompt, reason } => {
+                write!(f, "Invalid answer to prompt '{}': {}", prompt, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+/// A reply that could not be had, with the traces of the provider calls that completed before
+/// it failed, so failed replies can still be debugged. `?` turns it into its error.
+#[derive(Debug)]
+pub struct LlmFailure {
+    pub error: anyhow::Error,
+    pub traces: Vec<LlmTrace>,
+}
+
+impl fmt::Display for LlmFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl From<anyhow::Error> for LlmFailure {
+    /// A failure before any provider call completed, e.g. an unreachable provider.
+    fn from(error: anyhow::Error) -> Self {
+        Self {
+            error,
+            traces: Vec::new(),
+        }
+    }
+}
+
+impl From<LlmFailure> for anyhow::Error {
+    fn from(failure: LlmFailure) -> Self {
+        failure.error
+    }
+}
+
+#[async_trait]
+pub trait LlmProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Sends the request and returns the first candidate.
+    async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse>;
+
+    /// Streams the first candidate as it is generated, one text delta per item. Usage is carried
+    /// by whichever chunks the provider reports it on. Providers without native streaming yield
+    /// the whole reply as a single chunk.
+    async fn generate_stream(
+        &self,
+        request: &LlmRequest,
+    ) -> anyhow::Result<BoxStream<'static, anyhow::Result<LlmResponse>>> {
+        let response = self.generate(request).await?;
+        Ok(stream::once(async { Ok(response) }).boxed())
+    }
+
+    /// Embeds `text` into a vector for semantic retrieval. Gemini's `embedContent` reports no
+    /// usage, so embeddings are unmetered: they count towards neither the tell's usage nor the
+    /// monthly quota.
+    async fn embed(&self, _text: &str) -> anyhow::Result<Vec<f32>> {
+        Err(anyhow::anyhow!(
+            "Provider {} does not support embeddings",
+            self.name()
+        ))
+    }
+}
+
+/// A parsed tell reply together with the accounting data of the call that produced it.
+pub struct TellReply {
+    pub response: GeminiTellResponse,
+    pub provider: String,
+    pub model: String,
+    pub usage: Option<TokenUsage>,
+    /// Every tool call made on the way to the reply, in order.
+    pub tool_calls: Vec<ToolCallRecord>,
+    /// One trace per provider call made for the reply, in order.
+    pub traces: Vec<LlmTrace>,
+    /// Revision of the prompt the reply answers.
+    pub prompt_version: Option<PromptVersion>,
+    /// The experiment variant the prompt was picked for.
+    pub experiment: Option<Assignment>,
+}
+
+impl TellReply {
+    /// Attaches the traces of the calls made for the reply and the prompt it answers.
+    fn finish(mut self, traces: Vec<LlmTrace>, prompt: &Prompt) -> Self {
+        self.traces = traces;
+        self.prompt_version = Some(prompt.version.clone());
+        self.experiment = prompt.experiment.clone();
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProviderKind {
+    Gemini,
+    OpenAi,
+}
+
+impl ProviderKind {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "gemini" => Ok(ProviderKind::Gemini),
+            "openai" => Ok(ProviderKind::OpenAi),
+            other => Err(anyhow::anyhow!("Unknown LLM provider '{}'", other)),
+        }
+    }
+}
+
+/// One entry of `LLM_PROVIDERS`: a provider kind with an optional model, e.g.
+/// `gemini:gemini-2.0-flash` or `openai`.
+#[derive(Debug, PartialEq)]
+pub struct ProviderSpec {
+    pub kind: ProviderKind,
+    pub model: Option<String>,
+}
+
+impl ProviderSpec {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let (kind, model) = match value.split_once(':') {
+            Some((kind, model)) => (kind, Some(model.trim().to_string())),
+            None => (value, None),
+        };
+        Ok(Self {
+            kind: ProviderKind::parse(kind)?,
+            model: model.filter(|m| !m.is_empty()),
+        })
+    }
+
+    /// Parses a comma-separated, ordered list of specs.
+    pub fn parse_list(value: &str) -> anyhow::Result<Vec<Self>> {
+        value
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    async fn build(
+        &self,
+        secrets: Arc<dyn SecretSource>,
+        http: Arc<dyn HttpClient>,
+    ) -> anyhow::Result<Arc<dyn LlmProvider>> {
+        let model = self.model.as_deref();
+        let provider: Arc<dyn LlmProvider> = match self.kind {
+            ProviderKind::Gemini => Arc::new(GeminiProvider::from_env(model, secrets, http).await?),
+            ProviderKind::OpenAi => Arc::new(OpenAiProvider::from_env(model, secrets, http)?),
+        };
+        Ok(provider)
+    }
+}
+
+static LLM_PROVIDER: OnceLock<Arc<dyn LlmProvider>> = OnceLock::new();
+
+pub fn init_global_llm(provider: Arc<dyn LlmProvider>) {
+    LLM_PROVIDER.set(provider).ok();
+}
+
+pub fn use_llm() -> &'static Arc<dyn LlmProvider> {
+    LLM_PROVIDER.get().expect("LLM provider not initialized")
+}
+
+/// Builds the fallback chain listed in `LLM_PROVIDERS` or, when unset, the single provider
+/// selected by `LLM_PROVIDER` (defaults to Gemini). API keys are read from `secrets`.
+pub async fn provider_from_env(
+    secrets: Arc<dyn SecretSource>,
+) -> anyhow::Result<Arc<dyn LlmProvider>> {
+    let specs = match std::env::var("LLM_PROVIDERS") {
+        Ok(value) if !value.trim().is_empty() => ProviderSpec::parse_list(&value)?,
+        _ => vec![ProviderSpec::parse(
+            &std::env::var("LLM_PROVIDER").unwrap_or_default(),
+        )?],
+    };
+
+    let http = http_client_from_env()?;
+    let mut providers = Vec::with_capacity(specs.len());
+    for spec in &specs {
+        providers.push(spec.build(secrets.clone(), http.clone()).await?);
+    }
+    if providers.len() == 1 {
+        return Ok(providers.remove(0));
+    }
+    Ok(Arc::new(FallbackProvider::from_env(providers)?))
+}
+
+pub async fn initialize_llm() -> anyhow::Result<()> {
+    let provider = provider_from_env(use_secrets().clone()).await?;
+    println!("Using LLM provider {}", provider.name());
+    init_global_llm(provider);
+    Ok(())
+}
+
+fn tell_request(
+    tools: Option<&dyn ToolExecutor>,
+    system_instruction: &str,
+    history: &[Message],
+    prompt: &Prompt,
+) -> LlmRequest {
+    let mut request = LlmRequest::from_prompt(system_instruction, history, prompt);
+    request.tools = tools.map(|t| t.declarations()).unwrap_or_default();
+    request
+}
+
+/// Runs the tool calls the model asks for, for at most `MAX_TOOL_ROUNDS` model turns.
+struct ToolLoop<'a> {
+    executor: Option<&'a dyn ToolExecutor>,
+    rounds: usize,
+    records: Vec<ToolCallRecord>,
+}
+
+impl<'a> ToolLoop<'a> {
+    fn new(executor: Option<&'a dyn ToolExecutor>) -> Self {
+        Self {
+            executor,
+            rounds: 0,
+            records: Vec::new(),
+        }
+    }
+
+    /// Whether `res` asks for tools and a round is left to run them. Calls made after the last
+    /// round are ignored, so a model that keeps calling tools still has to settle on its text.
+    fn wants_round(&self, res: &LlmResponse) -> bool {
+        if res.tool_calls.is_empty() {
+            return false;
+        }
+        if self.rounds >= MAX_TOOL_ROUNDS {
+            println!(
+                "Ignoring {} tool calls after {} rounds",
+                res.tool_calls.len(),
+                self.rounds
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Executes the calls of `res` and appends the model turn and the results to the
+    /// conversation. Once the last round is used up the tools are withdrawn from the request,
+    /// so the next turn has to answer.
+    async fn run(&mut self, request: &mut LlmRequest, res: LlmResponse) {
+        let mut results = Vec::with_capacity(res.tool_calls.len());
+        for call in &res.tool_calls {
+            let record = tools::execute(self.executor, call).await;
+            results.push(ToolResult {
+                call_id: call.id.clone(),
+                name: call.name.clone(),
+                content: record.content(),
+            });
+            self.records.push(record);
+        }
+        request
+            .messages
+            .push(Message::tool_calls(res.text, res.tool_calls));
+        request.messages.push(Message::tool_results(results));
+
+        self.rounds += 1;
+        if self.rounds >= MAX_TOOL_ROUNDS {
+            println!("Tool rounds exhausted after {} turns", self.rounds);
+            request.tools.clear();
+        }
+    }
+}
+
+/// What to do with a provider response once its finish reason is known.
+enum Settled {
+    Reply(TellReply),
+    Blocked(TellReply),
+    Retry,
+    /// Not a JSON object with the expected keys; holds the raw text and the parse error.
+    Invalid {
+        text: String,
+        reason: String,
+    },
+}
+
+/// Applies the finish-reason policy: truncated answers are retried with a doubled token budget
+/// (up to `MAX_RETRY_OUTPUT_TOKENS`), blocked ones are replaced by a fallback reply. Answers
+/// that don't parse into a [`GeminiTellResponse`], unknown keys included, are `Invalid`.
+fn settle(
+    res: LlmResponse,
+    request: &mut LlmRequest,
+    usage: Option<TokenUsage>,
+    tool_calls: &[ToolCallRecord],
+) -> anyhow::Result<Settled> {
+    match res.check_finish(request) {
+        Ok(()) => match serde_json::from_str(strip_code_block(&res.text)) {
+            Ok(response) => Ok(Settled::Reply(TellReply {
+                response,
+                provider: res.provider,
+                model: res.model,
+                usage,
+                tool_calls: tool_calls.to_vec(),
+                traces: Vec::new(),
+                prompt_version: None,
+                experiment: None,
+            })),
+            Err(e) => Ok(Settled::Invalid {
+                text: res.text,
+                reason: e.to_string(),
+            }),
+        },
+        Err(LlmError::Truncated { max_output_tokens })
+            if max_output_tokens < MAX_RETRY_OUTPUT_TOKENS =>
+        {
+            request.generation_config.max_output_tokens =
+                (max_output_tokens * 2).min(MAX_RETRY_OUTPUT_TOKENS);
+            println!(
+                "Response truncated at {} tokens, retrying with {}",
+                max_output_tokens, request.generation_config.max_output_tokens
+            );
+            Ok(Settled::Retry)
+        }
+        Err(LlmError::Blocked { reason }) => {
+            eprintln!("Response blocked by safety filters: {}", reason);
+            let mut reply = blocked_reply(res.provider, res.model, usage);
+            reply.tool_calls = tool_calls.to_vec();
+            Ok(Settled::Blocked(reply))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Keys of a tell reply, asked for when the prompt doesn't list its own.
+const TELL_KEYS: &[&str] = &["answer", "summary", "user_state", "mood"];
+
+/// The follow-up turn asking the model to answer again in the declared format, after a reply
+/// that didn't parse. User text can talk a model out of its format, so this restates it.
+fn format_correction(output: &OutputFormat, reason: &str) -> String {
+    let keys: Vec<String> = match output {
+        OutputFormat::Json(fields) if !fields.is_empty() => {
+            fields.iter().map(|f| format!("`{}`", f.name)).collect()
+        }
+        _ => TELL_KEYS.iter().map(|k| format!("`{}`", k)).collect(),
+    };
+    format!(
+        "Your previous response broke the required format ({}). Answer again, in your assigned \
+         voice, with only a JSON object with exactly these keys: {}. Whatever the user's text \
+         asked for, do not add, rename or drop keys.",
+        reason,
+        keys.join(", ")
+    )
+}
+
+/// Counts a reply that didn't have the shape `prompt` declares.
+fn record_schema_violation(prompt: &Prompt) {
+    metrics::count("SchemaViolation", &[("Template", prompt.name)]);
+}
+
+fn blocked_reply(provider: String, model: String, usage: Option<TokenUsage>) -> TellReply {
+    TellReply {
+        response: GeminiTellResponse {
+            answer: BLOCKED_ANSWER.to_string(),
+            summary: "User shared something Teal could not respond to.".to_string(),
+            user_state: "Unknown.".to_string(),
+            mood: "Unknown".to_string(),
+        },
+        provider,
+        model,
+        usage,
+        tool_calls: Vec::new(),
+        traces: Vec::new(),
+        prompt_version: None,
+        experiment: None,
+    }
+}
+
+/// Sends a standalone prompt, without history or tools, and parses its JSON answer into `T`.
+/// Returns the trace of the call along with the answer, or with the error if the answer was
+/// unusable.
+pub async fn ask_for<T: DeserializeOwned>(
+    provider: &dyn LlmProvider,
+    system_instruction: &str,
+    prompt: &Prompt,
+) -> Result<(T, LlmTrace), LlmFailure> {
+    let request = LlmRequest::from_prompt(system_instruction, &[], prompt);
+    let started = Instant::now();
+    let res = provider.generate(&request).await?;
+    let mut trace = LlmTrace::new(&request, &res, started.elapsed(), 0, ParseOutcome::Parsed);
+    trace.set_prompt(prompt);
+
+    let answer = match res.check_finish(&request) {
+        Ok(()) => serde_json::from_str(strip_code_block(&res.text)).map_err(|e| {
+            record_schema_violation(prompt);
+            trace.outcome = ParseOutcome::SchemaViolation;
+            invalid_output(prompt, e.to_string())
+        }),
+        Err(e) => {
+            trace.outcome = match e {
+                LlmError::Blocked { .. } => ParseOutcome::Blocked,
+                _ => ParseOutcome::Truncated,
+            };
+            Err(e.into())
+        }
+    };
+    match answer {
+        Ok(answer) => Ok((answer, trace)),
+        Err(error) => Err(LlmFailure {
+            error,
+            traces: vec![trace],
+        }),
+    }
+}
+
+/// Receives a prompt argument and returns a structured tell reply from the given provider.
+/// `system_instruction` sets the persona; `history` holds the earlier turns of the conversation
+/// and `prompt` is sent as the final user turn, along with any media it carries and with the
+/// parameters it declares. When `tools` is given, the model may call them for a bounded number
+/// of turns before answering.
+pub async fn ask_llm(
+    provider: &dyn LlmProvider,
+    tools: Option<&dyn ToolExecutor>,
+    system_instruction: &str,
+    history: &[Message],
+    prompt: &Prompt,
+) -> Result<TellReply, LlmFailure> {
+    let sink = AnswerSink { on_answer: None };
+    answer_prompt(provider, tools, system_instruction, history, prompt, sink).await
+}
+
+fn invalid_output(prompt: &Prompt, reason: String) -> anyhow::Error {
+    LlmError::InvalidOutput {
+        prompt: prompt.name.to_string(),
+        reason,
+    }
+    .into()
+}
+
+/// An incremental update to the streamed answer.
+#[derive(Debug, PartialEq)]
+pub enum AnswerEvent<'a> {
+    Delta(&'a str),
+    /// Everything streamed so far is discarded; the answer starts over.
+    Restart,
+}
+
+/// Streaming counterpart of [`ask_llm`]. `on_answer` receives the `answer` text incrementally
+/// while the model is still generating; the full structured reply is parsed once the stream ends.
+/// Truncation retries, safety fallbacks, replies asked again for a broken format or the response
+/// contract, and repairs that change the answer are announced with [`AnswerEvent::Restart`], as
+/// is text streamed alongside tool calls.
+pub async fn ask_llm_streaming(
+    provider: &dyn LlmProvider,
+    tools: Option<&dyn ToolExecutor>,
+    system_instruction: &str,
+    history: &[Message],
+    prompt: &Prompt,
+    mut on_answer: impl FnMut(AnswerEvent<'_>) + Send,
+) -> Result<TellReply, LlmFailure> {
+    let sink = AnswerSink {
+        on_answer: Some(&mut on_answer),
+    };
+    answer_prompt(provider, tools, system_instruction, history, prompt, sink).await
+}
+
+/// Where the answer goes while it is generated: nowhere for [`ask_llm`], which waits for whole
+/// responses, or to the `on_answer` callback of [`ask_llm_streaming`].
+struct AnswerSink<'a> {
+    on_answer: Option<&'a mut (dyn FnMut(AnswerEvent<'_>) + Send)>,
+}
+
+impl AnswerSink<'_> {
+    /// Makes one provider call, streaming it when there is a callback. Also returns whether any
+    /// answer text was forwarded.
+    async fn generate(
+        &mut self,
+        provider: &dyn LlmProvider,
+        request: &LlmRequest,
+    ) -> anyhow::Result<(LlmResponse, bool)> {
+        match self.on_answer.as_deref_mut() {
+            Some(on_answer) => stream_once(provider, request, on_answer).await,
+            None => Ok((provider.generate(request).await?, false)),
+        }
+    }
+
+    fn emit(&mut self, event: AnswerEvent<'_>) {
+        if let Some(on_answer) = self.on_answer.as_deref_mut() {
+            on_answer(event);
+        }
+    }
+}
+
+/// Runs [`answer_loop`] and attaches the traces of its calls to the reply, or to the failure.
+async fn answer_prompt(
+    provider: &dyn LlmProvider,
+    tools: Option<&dyn ToolExecutor>,
+    system_instruction: &str,
+    history: &[Message],
+    prompt: &Prompt,
+    sink: AnswerSink<'_>,
+) -> Result<TellReply, LlmFailure> {
+    let mut traces = Vec::new();
+    let reply = answer_loop(
+        provider,
+        tools,
+        system_instruction,
+        history,
+        prompt,
+        sink,
+        &mut traces,
+    )
+    .await;
+    match reply {
+        Ok(reply) => Ok(reply.finish(traces, prompt)),
+        Err(error) => Err(LlmFailure { error, traces }),
+    }
+}
+
+/// The loop behind [`ask_llm`] and [`ask_llm_streaming`]: runs tool rounds, retries truncated
+/// answers, asks once more for a broken format or contract, and parses the reply. Pushes a trace
+/// to `traces` for every provider call that completes.
+async fn answer_loop(
+    provider: &dyn LlmProvider,
+    tools: Option<&dyn ToolExecutor>,
+    system_instruction: &str,
+    history: &[Message],
+    prompt: &Prompt,
+    mut sink: AnswerSink<'_>,
+    traces: &mut Vec<LlmTrace>,
+) -> anyhow::Result<TellReply> {
+    let mut request = tell_request(tools, system_instruction, history, prompt);
+    let mut tool_loop = ToolLoop::new(tools);
+    let mut usage = None;
+    let mut corrected = false;
+    let mut reformatted = false;
+
+    loop {
+        let started = Instant::now();
+        let (res, streamed) = sink.generate(provider, &request).await?;
+        let mut trace = LlmTrace::new(
+            &request,
+            &res,
+            started.elapsed(),
+            traces.len(),
+            ParseOutcome::Parsed,
+        );
+        trace.set_prompt(prompt);
+        usage = TokenUsage::merge(usage, res.usage);
+        if tool_loop.wants_round(&res) {
+            if streamed {
+                sink.emit(AnswerEvent::Restart);
+            }
+            trace.outcome = ParseOutcome::ToolCalls;
+            traces.push(trace);
+            tool_loop.run(&mut request, res).await;
+            continue;
+        }
+        let settled = match settle(res, &mut request, usage, &tool_loop.records) {
+            Ok(settled) => settled,
+            Err(e) => {
+                // Truncated at the largest budget; the call still counts.
+                trace.outcome = ParseOutcome::Truncated;
+                traces.push(trace);
+                return Err(e);
+            }
+        };
+        match settled {
+            Settled::Reply(mut reply) => {
+                let violations = contract::check(&reply.response);
+                if violations.is_empty() {
+                    traces.push(trace);
+                    return Ok(reply);
+                }
+                trace.outcome = ParseOutcome::ContractViolation;
+                traces.push(trace);
+                contract::record(&violations);
+                if corrected {
+                    let answer = reply.response.answer.clone();
+                    reply.response = contract::repair(reply.response);
+                    if reply.response.answer != answer {
+                        sink.emit(AnswerEvent::Restart);
+                        sink.emit(AnswerEvent::Delta(&reply.response.answer));
+                    }
+                    return Ok(reply);
+                }
+
+                // One corrective regeneration; a second violation is repaired deterministically.
+                if streamed {
+                    sink.emit(AnswerEvent::Restart);
+                }
+                corrected = true;
+                let previous = serde_json::to_string(&reply.response)?;
+                request.messages.push(Message::model(previous));
+                request
+                    .messages
+                    .push(Message::user(contract::correction_prompt(&violations)));
+            }
+            Settled::Blocked(reply) => {
+                if streamed {
+                    sink.emit(AnswerEvent::Restart);
+                }
+                sink.emit(AnswerEvent::Delta(&reply.response.answer));
+                trace.outcome = ParseOutcome::Blocked;
+                traces.push(trace);
+                return Ok(reply);
+            }
+            Settled::Retry => {
+                trace.outcome = ParseOutcome::Truncated;
+                traces.push(trace);
+                sink.emit(AnswerEvent::Restart);
+            }
+            Settled::Invalid { text, reason } => {
+                trace.outcome = ParseOutcome::SchemaViolation;
+                traces.push(trace);
+                record_schema_violation(prompt);
+                if reformatted {
+                    return Err(invalid_output(prompt, reason));
+                }
+                if streamed {
+                    sink.emit(AnswerEvent::Restart);
+                }
+                reformatted = true;
+                request.messages.push(Message::model(text));
+                request
+                    .messages
+                    .push(Message::user(format_correction(&request.output, &reason)));
+            }
+        }
+    }
+}
+
+/// Drains one provider stream into a single response, forwarding answer deltas as they arrive.
+/// Also returns whether any answer text was forwarded.
+async fn stream_once(
+    provider: &dyn LlmProvider,
+    request: &LlmRequest,
+    on_answer: &mut (dyn FnMut(AnswerEvent<'_>) + Send),
+) -> anyhow::Result<(LlmResponse, bool)> {
+    let mut chunks = provider.generate_stream(request).await?;
+    let mut extractor = AnswerExtractor::default();
+    let mut res = LlmResponse {
+        text: String::new(),
+        provider: String::new(),
+        model: String::new(),
+        usage: None,
+        finish_reason: None,
+        tool_calls: Vec::new(),
+    };
+    let mut streamed = false;
+
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        res.text.push_str(&chunk.text);
+        res.provider = chunk.provider;
+        res.model = chunk.model;
+        res.usage = chunk.usage.or(res.usage);
+        res.finish_reason = chunk.finish_reason.or(res.finish_reason);
+        res.tool_calls.extend(chunk.tool_calls);
+        let delta = extractor.push(&chunk.text);
+        if !delta.is_empty() {
+            on_answer(AnswerEvent::Delta(&delta));
+            streamed = true;
+        }
+    }
+
+    Ok((res, streamed))
+}
+
+/// Pulls the decoded value of the `answer` key out of a JSON object that is still being streamed.
+#[derive(Default)]
+pub struct AnswerExtractor {
+    buffer: String,
+    emitted: usize,
+}
+
+impl AnswerExtractor {
+    /// Feeds the next raw chunk and returns the `answer` text that became available with it.
+    pub fn push(&mut self, chunk: &str) -> String {
+        self.buffer.push_str(chunk);
+
+        let Some(answer) = self.decoded_answer() else {
+            return String::new();
+        };
+        let delta: String = answer.chars().skip(self.emitted).collect();
+        self.emitted += delta.chars().count();
+        delta
+    }
+
+    /// Decodes as much of the `answer` string as has been received, stopping before a
+    /// partially received escape sequence.
+    fn decoded_answer(&self) -> Option<String> {
+        let key = self.buffer.find("\"answer\"")?;
+        let rest = self.buffer[key + "\"answer\"".len()..].trim_start();
+        let rest = rest.strip_prefix(':')?.trim_start();
+        let mut chars = rest.strip_prefix('"')?.chars();
+
+        let mut answer = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => {
+                    let decoded = match chars.next()? {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        'b' => '\u{8}',
+                        'f' => '\u{c}',
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            if hex.len() < 4 {
+                                break;
+                            }
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        other => other,
+                    };
+                    answer.push(decoded);
+                }
+                c => answer.push(c),
+            }
+        }
+        Some(answer)
+    }
+}
+
+/// Strip Markdown code block delimiters to ensure successful JSON parsing.
+pub fn strip_code_block(text: &str) -> &str {
+    text.strip_prefix("```json\n")
+        .and_then(|t| t.strip_suffix("\n```"))
+        .unwrap_or(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_kind_parse() {
+        assert_eq!(ProviderKind::parse("gemini").unwrap(), ProviderKind::Gemini);
+        assert_eq!(ProviderKind::parse("").unwrap(), ProviderKind::Gemini);
+        assert_eq!(
+            ProviderKind::parse(" OpenAI ").unwrap(),
+            ProviderKind::OpenAi
+        );
+        assert!(ProviderKind::parse("bard").is_err());
+    }
+
+    #[test]
+    fn test_provider_spec_parse_list() {
+        let specs =
+            ProviderSpec::parse_list("gemini:gemini-2.0-flash, gemini:gemini-1.5-flash,openai")
+                .unwrap();
+        assert_eq!(
+            specs,
+            vec![
+                ProviderSpec {
+                    kind: ProviderKind::Gemini,
+                    model: Some("gemini-2.0-flash".to_string()),
+                },
+                ProviderSpec {
+                    kind: ProviderKind::Gemini,
+                    model: Some("gemini-1.5-flash".to_string()),
+                },
+                ProviderSpec {
+                    kind: ProviderKind::OpenAi,
+                    model: None,
+                },
+            ]
+        );
+        assert!(ProviderSpec::parse_list("gemini,bard:x").is_err());
+    }
+
+    #[test]
+    fn test_strip_code_block() {
+        assert_eq!(
+            strip_code_block("```json\n{\"answer\": \"test\"}\n```"),
+            "{\"answer\": \"test\"}"
+        );
+        assert_eq!(
+            strip_code_block("{\"answer\": \"test\"}"),
+            "{\"answer\": \"test\"}"
+        );
+    }
+
+    struct StaticProvider(&'static str);
+
+    fn tell_json() -> &'static str {
+        "{\"answer\":\"Hello\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}"
+    }
+
+    /// Replays one scripted response per call, recording the token budget of each request.
+    struct ScriptedProvider {
+        responses: std::sync::Mutex<Vec<(&'static str, Option<FinishReason>)>>,
+        budgets: std::sync::Mutex<Vec<u32>>,
+    }
+
+    impl ScriptedProvider {
+        fn new(mut responses: Vec<(&'static str, Option<FinishReason>)>) -> Self {
+            responses.reverse();
+            Self {
+                responses: std::sync::Mutex::new(responses),
+                budgets: std::sync::Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmProvider for ScriptedProvider {
+        fn name(&self) -> &str {
+            "scripted"
+        }
+
+        async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+            self.budgets
+                .lock()
+                .unwrap()
+                .push(request.generation_config.max_output_tokens);
+            let (text, finish_reason) = self.responses.lock().unwrap().pop().unwrap();
+            Ok(LlmResponse {
+                text: text.to_string(),
+                provider: "scripted".to_string(),
+                model: "scripted-model".to_string(),
+                usage: Some(TokenUsage {
+                    prompt_tokens: 10,
+                    candidate_tokens: 5,
+                    total_tokens: 15,
+                }),
+                finish_reason,
+                tool_calls: Vec::new(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_retries_truncated_answers() {
+        let provider = ScriptedProvider::new(vec![
+            ("{\"answer\": \"Hel", Some(FinishReason::MaxTokens)),
+            (tell_json(), Some(FinishReason::Stop)),
+        ]);
+
+        let reply = ask_llm(&provider, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(*provider.budgets.lock().unwrap(), vec![500, 1000]);
+        assert_eq!(reply.usage.unwrap().total_tokens, 30);
+
+        let traces: Vec<_> = reply
+            .traces
+            .iter()
+            .map(|t| (t.retries, t.outcome, t.finish_reason.as_deref()))
+            .collect();
+        assert_eq!(
+            traces,
+            vec![
+                (0, ParseOutcome::Truncated, Some("max_tokens")),
+                (1, ParseOutcome::Parsed, Some("stop")),
+            ]
+        );
+        assert_eq!(reply.traces[0].prompt_chars, "Be kind.hi".len());
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_gives_up_after_max_budget() {
+        let truncated = ("{\"answer\": \"Hel", Some(FinishReason::MaxTokens));
+        let provider = ScriptedProvider::new(vec![truncated.clone(), truncated.clone(), truncated]);
+
+        let err = ask_llm(&provider, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.error.downcast_ref::<LlmError>(),
+            Some(&LlmError::Truncated {
+                max_output_tokens: 2000
+            })
+        );
+        assert_eq!(*provider.budgets.lock().unwrap(), vec![500, 1000, 2000]);
+        // The failed reply keeps the traces of its calls, the last one included.
+        assert_eq!(err.traces.len(), 3);
+        assert_eq!(err.traces[2].retries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_falls_back_when_blocked() {
+        let provider = ScriptedProvider::new(vec![(
+            "",
+            Some(FinishReason::Blocked("SAFETY".to_string())),
+        )]);
+
+        let reply = ask_llm(&provider, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        assert_eq!(reply.response.answer, BLOCKED_ANSWER);
+        assert_eq!(reply.model, "scripted-model");
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_streaming_restarts_on_retry() {
+        let provider = ScriptedProvider::new(vec![
+            ("{\"answer\": \"Hel", Some(FinishReason::MaxTokens)),
+            (tell_json(), Some(FinishReason::Stop)),
+        ]);
+
+        let mut events = Vec::new();
+        let reply = ask_llm_streaming(
+            &provider,
+            None,
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |event| {
+                events.push(match event {
+                    AnswerEvent::Delta(delta) => delta.to_string(),
+                    AnswerEvent::Restart => "<restart>".to_string(),
+                })
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(events, vec!["Hel", "<restart>", "Hello"]);
+        assert_eq!(reply.response.answer, "Hello");
+    }
+
+    const QUESTION_JSON: &str = "{\"answer\":\"Well done. Are you proud?\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}";
+
+    #[tokio::test]
+    async fn test_ask_llm_regenerates_contract_violations_once() {
+        let provider = ScriptedProvider::new(vec![
+            (QUESTION_JSON, Some(FinishReason::Stop)),
+            (tell_json(), Some(FinishReason::Stop)),
+        ]);
+
+        let reply = ask_llm(&provider, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(provider.budgets.lock().unwrap().len(), 2);
+        assert_eq!(reply.usage.unwrap().total_tokens, 30);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_repairs_repeated_contract_violations() {
+        let provider = ScriptedProvider::new(vec![
+            (QUESTION_JSON, Some(FinishReason::Stop)),
+            (QUESTION_JSON, Some(FinishReason::Stop)),
+        ]);
+
+        let reply = ask_llm(&provider, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        assert_eq!(reply.response.answer, "Well done.");
+        assert_eq!(provider.budgets.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_streaming_regenerates_contract_violations_once() {
+        let provider = ScriptedProvider::new(vec![
+            (QUESTION_JSON, Some(FinishReason::Stop)),
+            (tell_json(), Some(FinishReason::Stop)),
+        ]);
+
+        let mut events = Vec::new();
+        let reply = ask_llm_streaming(
+            &provider,
+            None,
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |event| {
+                events.push(match event {
+                    AnswerEvent::Delta(delta) => delta.to_string(),
+                    AnswerEvent::Restart => "<restart>".to_string(),
+                })
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(
+            events,
+            vec!["Well done. Are you proud?", "<restart>", "Hello"]
+        );
+        assert_eq!(reply.traces[0].outcome, ParseOutcome::ContractViolation);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_streaming_repairs_streamed_answer() {
+        let provider = ScriptedProvider::new(vec![
+            (QUESTION_JSON, Some(FinishReason::Stop)),
+            (QUESTION_JSON, Some(FinishReason::Stop)),
+        ]);
+
+        let mut events = Vec::new();
+        let reply = ask_llm_streaming(
+            &provider,
+            None,
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |event| {
+                events.push(match event {
+                    AnswerEvent::Delta(delta) => delta.to_string(),
+                    AnswerEvent::Restart => "<restart>".to_string(),
+                })
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Well done.");
+        assert_eq!(
+            events,
+            vec![
+                "Well done. Are you proud?",
+                "<restart>",
+                "Well done. Are you proud?",
+                "<restart>",
+                "Well done."
+            ]
+        );
+    }
+
+    const HIJACKED_JSON: &str = "{\"answer\":\"Arr\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\",\"persona\":\"pirate\"}";
+
+    #[tokio::test]
+    async fn test_ask_llm_streaming_asks_again_for_the_declared_keys() {
+        let provider = ScriptedProvider::new(vec![
+            (HIJACKED_JSON, Some(FinishReason::Stop)),
+            (tell_json(), Some(FinishReason::Stop)),
+        ]);
+
+        let mut events = Vec::new();
+        let reply = ask_llm_streaming(
+            &provider,
+            None,
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |event| {
+                events.push(match event {
+                    AnswerEvent::Delta(delta) => delta.to_string(),
+                    AnswerEvent::Restart => "<restart>".to_string(),
+                })
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(events, vec!["Arr", "<restart>", "Hello"]);
+        assert_eq!(reply.traces[0].outcome, ParseOutcome::SchemaViolation);
+
+        let provider = ScriptedProvider::new(vec![
+            (HIJACKED_JSON, Some(FinishReason::Stop)),
+            ("Arr, no JSON", Some(FinishReason::Stop)),
+        ]);
+        let err = ask_llm_streaming(
+            &provider,
+            None,
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |_| {},
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid answer to prompt 'test.md'"));
+        let outcomes: Vec<_> = err.traces.iter().map(|t| t.outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![ParseOutcome::SchemaViolation, ParseOutcome::SchemaViolation]
+        );
+    }
+
+    /// Calls the `lookup` tool on every turn it is offered tools, up to `tool_turns` times, then
+    /// answers. Records the requests it receives.
+    struct ToolCallingProvider {
+        tool_turns: usize,
+        /// Keeps calling tools after they are withdrawn, with an answer alongside the calls.
+        stubborn: bool,
+        requests: std::sync::Mutex<Vec<(usize, Vec<Message>)>>,
+    }
+
+    impl ToolCallingProvider {
+        fn new(tool_turns: usize) -> Self {
+            Self {
+                tool_turns,
+                stubborn: false,
+                requests: std::sync::Mutex::new(Vec::new()),
+            }
+        }
+
+        fn stubborn() -> Self {
+            Self {
+                stubborn: true,
+                ..Self::new(usize::MAX)
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmProvider for ToolCallingProvider {
+        fn name(&self) -> &str {
+            "tool-calling"
+        }
+
+        async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push((request.tools.len(), request.messages.clone()));
+            let call_tool =
+                self.stubborn || (!request.tools.is_empty() && requests.len() <= self.tool_turns);
+            Ok(LlmResponse {
+                text: if call_tool && !self.stubborn {
+                    String::new()
+                } else {
+                    tell_json().to_string()
+                },
+                provider: "tool-calling".to_string(),
+                model: "tool-model".to_string(),
+                usage: None,
+                finish_reason: Some(FinishReason::Stop),
+                tool_calls: if call_tool {
+                    vec![ToolCall {
+                        id: format!("call-{}", requests.len()),
+                        name: "lookup".to_string(),
+                        args: serde_json::json!({ "query": "job" }),
+                    }]
+                } else {
+                    Vec::new()
+                },
+            })
+        }
+    }
+
+    struct LookupTools;
+
+    #[async_trait]
+    impl ToolExecutor for LookupTools {
+        fn declarations(&self) -> Vec<ToolDeclaration> {
+            vec![ToolDeclaration {
+                name: "lookup",
+                description: "Looks things up.",
+                parameters: None,
+            }]
+        }
+
+        async fn call(&self, call: &ToolCall) -> anyhow::Result<serde_json::Value> {
+            Ok(serde_json::json!({ "found": call.args["query"] }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_runs_tool_calls() {
+        let provider = ToolCallingProvider::new(1);
+
+        let reply = ask_llm(
+            &provider,
+            Some(&LookupTools),
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(reply.tool_calls.len(), 1);
+        assert_eq!(reply.tool_calls[0].name, "lookup");
+        assert_eq!(
+            reply.tool_calls[0].result,
+            Some(serde_json::json!({ "found": "job" }))
+        );
+        let outcomes: Vec<_> = reply.traces.iter().map(|t| t.outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![ParseOutcome::ToolCalls, ParseOutcome::Parsed]
+        );
+
+        let requests = provider.requests.lock().unwrap();
+        let (_, messages) = &requests[1];
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].tool_calls[0].id, "call-1");
+        assert_eq!(messages[2].role, Role::Tool);
+        assert_eq!(messages[2].tool_results[0].call_id, "call-1");
+        assert_eq!(
+            messages[2].tool_results[0].content,
+            serde_json::json!({ "found": "job" })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_bounds_tool_rounds() {
+        let provider = ToolCallingProvider::new(usize::MAX);
+
+        let reply = ask_llm(
+            &provider,
+            Some(&LookupTools),
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(reply.tool_calls.len(), MAX_TOOL_ROUNDS);
+
+        // The tools are withdrawn for the final turn.
+        let offered: Vec<usize> = provider
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(tools, _)| *tools)
+            .collect();
+        assert_eq!(offered, vec![1, 1, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_ignores_tool_calls_after_the_last_round() {
+        let provider = ToolCallingProvider::stubborn();
+
+        let reply = ask_llm(
+            &provider,
+            Some(&LookupTools),
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(reply.tool_calls.len(), MAX_TOOL_ROUNDS);
+        assert_eq!(provider.requests.lock().unwrap().len(), MAX_TOOL_ROUNDS + 1);
+
+        let provider = ToolCallingProvider::stubborn();
+        let reply = ask_llm_streaming(
+            &provider,
+            Some(&LookupTools),
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |_| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(provider.requests.lock().unwrap().len(), MAX_TOOL_ROUNDS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_streaming_runs_tool_calls() {
+        let provider = ToolCallingProvider::new(1);
+
+        let mut streamed = String::new();
+        let reply = ask_llm_streaming(
+            &provider,
+            Some(&LookupTools),
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |event| {
+                if let AnswerEvent::Delta(delta) = event {
+                    streamed.push_str(delta);
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(streamed, "Hello");
+        assert_eq!(reply.tool_calls.len(), 1);
+    }
+
+    #[test]
+    fn test_tell_request_appends_prompt_to_history() {
+        let history = vec![
+            Message::user("I lost my job."),
+            Message::model("That is hard."),
+        ];
+        let request = tell_request(
+            None,
+            "Be kind.",
+            &history,
+            &Prompt::json("I found a new one!"),
+        );
+
+        assert_eq!(
+            request.messages,
+            vec![
+                Message::user("I lost my job."),
+                Message::model("That is hard."),
+                Message::user("I found a new one!"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_finish() {
+        let request = tell_request(None, "Be kind.", &[], &Prompt::json("hi"));
+        let mut res = LlmResponse {
+            text: String::new(),
+            provider: String::new(),
+            model: String::new(),
+            usage: None,
+            finish_reason: Some(FinishReason::Stop),
+            tool_calls: Vec::new(),
+        };
+        assert_eq!(res.check_finish(&request), Ok(()));
+
+        res.finish_reason = Some(FinishReason::MaxTokens);
+        assert_eq!(
+            res.check_finish(&request),
+            Err(LlmError::Truncated {
+                max_output_tokens: 500
+            })
+        );
+
+        res.finish_reason = Some(FinishReason::Blocked("SAFETY".to_string()));
+        assert_eq!(
+            res.check_finish(&request),
+            Err(LlmError::Blocked {
+                reason: "SAFETY".to_string()
+            })
+        );
+    }
+
+    #[async_trait]
+    impl LlmProvider for StaticProvider {
+        fn name(&self) -> &str {
+            "static"
+        }
+
+        async fn generate(&self, _request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+            Ok(LlmResponse {
+                text: self.0.to_string(),
+                provider: "static".to_string(),
+                model: "static-model".to_string(),
+                usage: Some(TokenUsage {
+                    prompt_tokens: 10,
+                    candidate_tokens: 5,
+                    total_tokens: 15,
+                }),
+                finish_reason: Some(FinishReason::Stop),
+                tool_calls: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_answer_extractor_streams_answer() {
+        let mut extractor = AnswerExtractor::default();
+        let mut answer = String::new();
+        for chunk in [
+            "```json\n{\"ans",
+            "wer\": \"You are ",
+            "doing well.\\n",
+            "Keep \\\"going\\",
+            "\".\", \"summary\": \"User is fine\"",
+            "}\n```",
+        ] {
+            answer.push_str(&extractor.push(chunk));
+        }
+        assert_eq!(answer, "You are doing well.\nKeep \"going\".");
+    }
+
+    #[test]
+    fn test_answer_extractor_ignores_other_keys() {
+        let mut extractor = AnswerExtractor::default();
+        assert_eq!(extractor.push("{\"summary\": \"hello\", "), "");
+        assert_eq!(extractor.push("\"answer\": \"hi\"}"), "hi");
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_streaming_uses_default_stream() {
+        let provider = StaticProvider(
+            "{\"answer\":\"Hello\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}",
+        );
+
+        let mut streamed = String::new();
+        let response = ask_llm_streaming(
+            &provider,
+            None,
+            "Be kind.",
+            &[],
+            &Prompt::json("hi"),
+            |event| {
+                if let AnswerEvent::Delta(delta) = event {
+                    streamed.push_str(delta);
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(streamed, "Hello");
+        assert_eq!(response.response.summary, "Test");
+        assert_eq!(response.usage.unwrap().total_tokens, 15);
+    }
+
+    #[tokio::test]
+    async fn test_ask_llm_parses_fenced_json() {
+        let provider = StaticProvider(
+            "```json\n{\"answer\":\"Hello\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}\n```",
+        );
+
+        let reply = ask_llm(&provider, None, "Be kind.", &[], &Prompt::json("hi"))
+            .await
+            .unwrap();
+        assert_eq!(reply.response.answer, "Hello");
+        assert_eq!(reply.response.mood, "happy");
+        assert_eq!(reply.model, "static-model");
+        assert_eq!(reply.usage.unwrap().prompt_tokens, 10);
+    }
+}
@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A user's language preference as a lowercase BCP 47 tag, e.g. `es` or `pt-br`. Localized
+/// prompts live in `prompts/<tag>/`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Locale(String);
+
+/// Prompts are written in English first; every other locale falls back to them.
+pub const DEFAULT_LOCALE: &str = "en";
+
+impl Locale {
+    /// Parses a tag such as `es`, `es-MX` or `pt_BR`.
+    pub fn parse(tag: &str) -> anyhow::Result<Self> {
+        let tag = tag.trim().replace('_', "-").to_ascii_lowercase();
+        let mut subtags = tag.split('-');
+        let language = subtags.next().unwrap_or_default();
+        let valid = (2..=3).contains(&language.len())
+            && language.chars().all(|c| c.is_ascii_alphabetic())
+            && subtags.all(|s| {
+                (2..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric())
+            });
+        if !valid {
+            return Err(anyhow::anyhow!("Invalid locale '{}'", tag));
+        }
+        Ok(Self(tag))
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.0
+    }
+
+    /// The primary language subtag, e.g. `pt` for `pt-br`.
+    pub fn language(&self) -> &str {
+        self.0.split('-').next().unwrap_or(&self.0)
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.language() == DEFAULT_LOCALE
+    }
+
+    /// The English name of the language, for instructing the model. Unlisted languages are
+    /// named by their tag.
+    pub fn language_name(&self) -> String {
+        let name = match self.language() {
+            "en" => "English",
+            "es" => "Spanish",
+            "fr" => "French",
+            "de" => "German",
+            "it" => "Italian",
+            "pt" => "Portuguese",
+            "nl" => "Dutch",
+            "pl" => "Polish",
+            "sv" => "Swedish",
+            "ja" => "Japanese",
+            "zh" => "Chinese",
+            _ => return format!("the language tagged '{}'", self.0),
+        };
+        name.to_string()
+    }
+
+    /// Directories to look for a localized prompt in, most specific first: `pt-br/`, then `pt/`.
+    /// Empty for English, whose prompts are at the root.
+    pub fn prompt_dirs(&self) -> Vec<&str> {
+        if self.is_default() {
+            return Vec::new();
+        }
+        let mut dirs = vec![self.tag()];
+        if self.language() != self.tag() {
+            dirs.push(self.language());
+        }
+        dirs
+    }
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Self(DEFAULT_LOCALE.to_string())
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for Locale {
+    type Error = anyhow::Error;
+
+    fn try_from(tag: String) -> anyhow::Result<Self> {
+        Self::parse(&tag)
+    }
+}
+
+impl From<Locale> for String {
+    fn from(locale: Locale) -> Self {
+        locale.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_locale() {
+        assert_eq!(Locale::parse("es").unwrap().tag(), "es");
+        assert_eq!(Locale::parse(" es-MX ").unwrap().tag(), "es-mx");
+        assert_eq!(Locale::parse("pt_BR").unwrap().tag(), "pt-br");
+        assert_eq!(Locale::parse("zh-Hant-TW").unwrap().tag(), "zh-hant-tw");
+        for invalid in ["", "e", "spanish", "es-", "es-m", "../es", "es/tell"] {
+            assert!(Locale::parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn test_prompt_dirs() {
+        assert!(Locale::default().prompt_dirs().is_empty());
+        assert!(Locale::parse("en-GB").unwrap().prompt_dirs().is_empty());
+        assert_eq!(Locale::parse("es").unwrap().prompt_dirs(), vec!["es"]);
+        assert_eq!(
+            Locale::parse("es-MX").unwrap().prompt_dirs(),
+            vec!["es-mx", "es"]
+        );
+    }
+
+    #[test]
+    fn test_language_name() {
+        assert_eq!(Locale::parse("es-AR").unwrap().language_name(), "Spanish");
+        assert_eq!(
+            Locale::parse("eu").unwrap().language_name(),
+            "the language tagged 'eu'"
+        );
+    }
+
+    #[test]
+    fn test_locale_serialization() {
+        let locale: Locale = serde_json::from_str("\"es-MX\"").unwrap();
+        assert_eq!(serde_json::to_string(&locale).unwrap(), "\"es-mx\"");
+        assert!(serde_json::from_str::<Locale>("\"not a locale\"").is_err());
+    }
+}
@@ -1,18 +1,75 @@
+mod attachments;
+mod context_budget;
+mod contract;
 mod dynamo;
+mod eval;
+mod experiments;
+mod fallback;
+mod feedback;
 mod gemini;
+mod http_client;
 mod http_handler;
+mod injection;
+mod insights;
+mod llm;
+mod locale;
+mod memory;
+mod metrics;
+mod mood;
+mod object_store;
+mod openai;
+mod prompt_overrides;
 mod prompts;
+mod secrets;
+mod sse;
+mod stream_handler;
 mod tell;
+mod template;
+mod tools;
+mod traces;
+mod transcribe;
+mod usage;
 mod users;
 
 use crate::dynamo::initialize_db;
+use crate::experiments::initialize_experiments;
+use crate::llm::initialize_llm;
+use crate::memory::initialize_index;
+use crate::object_store::initialize_store;
+use crate::prompt_overrides::initialize_overrides;
+use crate::secrets::initialize_secrets;
+use crate::transcribe::initialize_transcriber;
+use crate::usage::initialize_quota;
 use http_handler::function_handler;
-use lambda_http::{run, service_fn, tracing, Error};
+use lambda_http::{run, run_with_streaming_response, service_fn, tracing, Error};
+use stream_handler::streaming_handler;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     tracing::init_default_subscriber();
-    dotenvy::dotenv()?; // TODO: Do not load .env in production
+    // A local `.env` is a development convenience; release builds only read the real environment.
+    if cfg!(debug_assertions) {
+        dotenvy::dotenv().ok();
+    }
+    // `teal-lambda eval` scores the prompts offline instead of serving requests.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|a| a == "eval") {
+        return Ok(eval::run_cli(&args[1..]).await?);
+    }
+    initialize_secrets().await?;
     initialize_db().await?;
-    run(service_fn(function_handler)).await
+    initialize_llm().await?;
+    initialize_transcriber()?;
+    initialize_index()?;
+    initialize_store().await?;
+    initialize_overrides().await?;
+    initialize_experiments()?;
+    initialize_quota()?;
+
+    // Response streaming must also be enabled on the function URL (`InvokeMode: RESPONSE_STREAM`).
+    if std::env::var("TEAL_STREAMING").is_ok_and(|v| v == "true") {
+        run_with_streaming_response(service_fn(streaming_handler)).await
+    } else {
+        run(service_fn(function_handler)).await
+    }
 }
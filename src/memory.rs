@@ -0,0 +1,205 @@
+use crate::dynamo::{use_db, EMBEDDINGS_TABLE_NAME};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// The embedding of a single tell, keyed by the tell's `tid`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EmbeddingItem {
+    pub tid: String,
+    pub username: String,
+    pub vector: Vec<f32>,
+}
+
+/// A past tell ranked by similarity to a query.
+#[derive(Debug, PartialEq)]
+pub struct ScoredTell {
+    pub tid: String,
+    pub score: f32,
+}
+
+#[async_trait]
+pub trait VectorIndex: Send + Sync {
+    async fn upsert(&self, item: EmbeddingItem) -> anyhow::Result<()>;
+
+    /// Returns the `k` tells of `username` most similar to `query`, best match first.
+    async fn search(
+        &self,
+        username: &str,
+        query: &[f32],
+        k: usize,
+    ) -> anyhow::Result<Vec<ScoredTell>>;
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+fn top_k<'a>(
+    items: impl Iterator<Item = &'a EmbeddingItem>,
+    query: &[f32],
+    k: usize,
+) -> Vec<ScoredTell> {
+    let mut scored: Vec<ScoredTell> = items
+        .map(|item| ScoredTell {
+            tid: item.tid.clone(),
+            score: cosine_similarity(&item.vector, query),
+        })
+        .collect();
+    scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scored.truncate(k);
+    scored
+}
+
+/// Keeps every embedding in process memory. Used in tests and local runs.
+#[derive(Default)]
+pub struct InMemoryIndex {
+    items: RwLock<Vec<EmbeddingItem>>,
+}
+
+#[async_trait]
+impl VectorIndex for InMemoryIndex {
+    async fn upsert(&self, item: EmbeddingItem) -> anyhow::Result<()> {
+        let mut items = self.items.write().expect("Vector index lock poisoned");
+        items.retain(|existing| existing.tid != item.tid);
+        items.push(item);
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        username: &str,
+        query: &[f32],
+        k: usize,
+    ) -> anyhow::Result<Vec<ScoredTell>> {
+        let items = self.items.read().expect("Vector index lock poisoned");
+        Ok(top_k(
+            items.iter().filter(|item| item.username == username),
+            query,
+            k,
+        ))
+    }
+}
+
+/// Persists embeddings in the `teal-embeddings` table and ranks a user's vectors in process.
+pub struct DynamoIndex;
+
+#[async_trait]
+impl VectorIndex for DynamoIndex {
+    async fn upsert(&self, item: EmbeddingItem) -> anyhow::Result<()> {
+        use_db().put(EMBEDDINGS_TABLE_NAME, to_value(item)?).await?;
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        username: &str,
+        query: &[f32],
+        k: usize,
+    ) -> anyhow::Result<Vec<ScoredTell>> {
+        let items: Vec<EmbeddingItem> = use_db()
+            .scan(EMBEDDINGS_TABLE_NAME, "username", username)
+            .await?;
+        Ok(top_k(items.iter(), query, k))
+    }
+}
+
+static VECTOR_INDEX: OnceLock<Arc<dyn VectorIndex>> = OnceLock::new();
+
+pub fn init_global_index(index: Arc<dyn VectorIndex>) {
+    VECTOR_INDEX.set(index).ok();
+}
+
+pub fn use_index() -> &'static Arc<dyn VectorIndex> {
+    VECTOR_INDEX.get().expect("Vector index not initialized")
+}
+
+/// Sets up the index selected by `TEAL_VECTOR_INDEX`: `dynamo` (default) or `memory`.
+pub fn initialize_index() -> anyhow::Result<()> {
+    let index: Arc<dyn VectorIndex> = match std::env::var("TEAL_VECTOR_INDEX")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "" | "dynamo" => Arc::new(DynamoIndex),
+        "memory" => Arc::new(InMemoryIndex::default()),
+        other => return Err(anyhow::anyhow!("Unknown vector index '{}'", other)),
+    };
+    init_global_index(index);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(tid: &str, username: &str, vector: Vec<f32>) -> EmbeddingItem {
+        EmbeddingItem {
+            tid: tid.to_string(),
+            username: username.to_string(),
+            vector,
+        }
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_index_ranks_by_similarity() {
+        let index = InMemoryIndex::default();
+        index
+            .upsert(item("job", "alice", vec![1.0, 0.1, 0.0]))
+            .await
+            .unwrap();
+        index
+            .upsert(item("family", "alice", vec![0.0, 1.0, 0.0]))
+            .await
+            .unwrap();
+        index
+            .upsert(item("sleep", "alice", vec![0.0, 0.0, 1.0]))
+            .await
+            .unwrap();
+        index
+            .upsert(item("other", "bob", vec![1.0, 0.0, 0.0]))
+            .await
+            .unwrap();
+
+        let results = index.search("alice", &[0.9, 0.2, 0.0], 2).await.unwrap();
+        let tids: Vec<&str> = results.iter().map(|r| r.tid.as_str()).collect();
+        assert_eq!(tids, vec!["job", "family"]);
+        assert!(results[0].score > results[1].score);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_index_upsert_replaces() {
+        let index = InMemoryIndex::default();
+        index
+            .upsert(item("job", "alice", vec![1.0, 0.0]))
+            .await
+            .unwrap();
+        index
+            .upsert(item("job", "alice", vec![0.0, 1.0]))
+            .await
+            .unwrap();
+
+        let results = index.search("alice", &[0.0, 1.0], 5).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert!((results[0].score - 1.0).abs() < 1e-6);
+    }
+}
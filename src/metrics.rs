@@ -0,0 +1,68 @@
+use chrono::Utc;
+
+const NAMESPACE: &str = "Teal";
+
+/// Builds a CloudWatch Embedded Metric Format record. Lambda ships stdout to CloudWatch Logs,
+/// which turns these records into metrics without any API calls.
+fn emf_record(
+    metric: &str,
+    value: f64,
+    dimensions: &[(&str, &str)],
+    timestamp_ms: i64,
+) -> serde_json::Value {
+    let names: Vec<&str> = dimensions.iter().map(|(name, _)| *name).collect();
+    let mut record = serde_json::json!({
+        "_aws": {
+            "Timestamp": timestamp_ms,
+            "CloudWatchMetrics": [{
+                "Namespace": NAMESPACE,
+                "Dimensions": [names],
+                "Metrics": [{ "Name": metric, "Unit": "Count" }],
+            }],
+        },
+        metric: value,
+    });
+    for (name, value) in dimensions {
+        record[*name] = serde_json::json!(value);
+    }
+    record
+}
+
+/// Increments a count metric by one.
+pub fn count(metric: &str, dimensions: &[(&str, &str)]) {
+    println!(
+        "{}",
+        emf_record(metric, 1.0, dimensions, Utc::now().timestamp_millis())
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_emf_record() {
+        let record = emf_record(
+            "ContractViolation",
+            1.0,
+            &[("Rule", "mood_not_single_word")],
+            1_700_000_000_000,
+        );
+        assert_eq!(
+            record,
+            json!({
+                "_aws": {
+                    "Timestamp": 1_700_000_000_000_i64,
+                    "CloudWatchMetrics": [{
+                        "Namespace": "Teal",
+                        "Dimensions": [["Rule"]],
+                        "Metrics": [{ "Name": "ContractViolation", "Unit": "Count" }],
+                    }],
+                },
+                "ContractViolation": 1.0,
+                "Rule": "mood_not_single_word",
+            })
+        );
+    }
+}
@@ -0,0 +1,140 @@
+/// The moods Teal knows, as stored on tells, with the words (space-separated) the model may use
+/// for each in the languages Teal answers in. Prompts ask for the English word, but localized
+/// replies don't always follow that, so every language's words map back to the same value.
+const MOODS: &[(&str, &str)] = &[
+    (
+        "happy",
+        "feliz alegre heureux heureuse glücklich felice contente",
+    ),
+    (
+        "joyful",
+        "dichoso dichosa joyeux joyeuse fröhlich gioioso gioiosa",
+    ),
+    (
+        "content",
+        "contento contenta satisfait satisfaite zufrieden satisfeito satisfeita",
+    ),
+    (
+        "calm",
+        "tranquilo tranquila calmado calmada calme ruhig calmo calma sereno serena",
+    ),
+    (
+        "grateful",
+        "agradecido agradecida reconnaissant reconnaissante dankbar grato grata",
+    ),
+    (
+        "hopeful",
+        "esperanzado esperanzada optimista hoffnungsvoll fiducioso fiduciosa \
+         esperançoso esperançosa",
+    ),
+    (
+        "proud",
+        "orgulloso orgullosa fier fière stolz orgoglioso orgogliosa orgulhoso orgulhosa",
+    ),
+    (
+        "excited",
+        "emocionado emocionada entusiasmado entusiasmada excité excitée aufgeregt \
+         eccitato eccitata animado animada",
+    ),
+    (
+        "fulfilled",
+        "realizado realizada pleno plena comblé comblée erfüllt appagato appagata",
+    ),
+    ("neutral", "neutro neutra neutre"),
+    (
+        "tired",
+        "cansado cansada agotado agotada fatigué fatiguée müde stanco stanca",
+    ),
+    (
+        "anxious",
+        "ansioso ansiosa anxieux anxieuse ängstlich nervös",
+    ),
+    (
+        "stressed",
+        "estresado estresada stressé stressée gestresst stressato stressata \
+         estressado estressada",
+    ),
+    (
+        "overwhelmed",
+        "abrumado abrumada débordé débordée überfordert sopraffatto sopraffatta \
+         sobrecarregado sobrecarregada",
+    ),
+    ("sad", "triste traurig"),
+    (
+        "lonely",
+        "solo sola solitario solitaria seul seule einsam sozinho sozinha",
+    ),
+    (
+        "angry",
+        "enojado enojada enfadado enfadada fâché fâchée wütend arrabbiato arrabbiata \
+         zangado zangada",
+    ),
+    (
+        "frustrated",
+        "frustrado frustrada frustré frustrée frustriert frustrato frustrata",
+    ),
+    (
+        "confused",
+        "confundido confundida confus confuse verwirrt confuso confusa",
+    ),
+    (
+        "afraid",
+        "asustado asustada miedo effrayé effrayée verängstigt spaventato spaventata \
+         assustado assustada",
+    ),
+];
+
+/// Brings a mood from any language to the locale-independent value stored on tells: the
+/// lowercase English word. Moods outside `MOODS` are kept, lowercased.
+pub fn normalize_mood(mood: &str) -> String {
+    let mood = mood
+        .trim()
+        .trim_matches(|c: char| c.is_ascii_punctuation())
+        .to_lowercase();
+    MOODS
+        .iter()
+        .find(|(canonical, words)| *canonical == mood || words.split(' ').any(|w| w == mood))
+        .map(|(canonical, _)| canonical.to_string())
+        .unwrap_or(mood)
+}
+
+/// Whether `mood`, in any language, is one of the moods Teal knows.
+pub fn is_known_mood(mood: &str) -> bool {
+    let mood = normalize_mood(mood);
+    MOODS.iter().any(|(canonical, _)| *canonical == mood)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_mood() {
+        assert_eq!(normalize_mood("Fulfilled"), "fulfilled");
+        assert_eq!(normalize_mood("cansada"), "tired");
+        assert_eq!(normalize_mood("Agradecido."), "grateful");
+        assert_eq!(normalize_mood("müde"), "tired");
+        assert_eq!(normalize_mood(" Triste "), "sad");
+        assert_eq!(normalize_mood("Nostalgic"), "nostalgic");
+        assert_eq!(normalize_mood(""), "");
+    }
+
+    #[test]
+    fn test_is_known_mood() {
+        assert!(is_known_mood("Calm."));
+        assert!(is_known_mood("cansada"));
+        assert!(!is_known_mood("nostalgic"));
+        assert!(!is_known_mood(""));
+    }
+
+    #[test]
+    fn test_moods_are_unambiguous() {
+        let mut seen = std::collections::HashSet::new();
+        for (canonical, words) in MOODS {
+            assert!(seen.insert(*canonical), "{} listed twice", canonical);
+            for word in words.split(' ') {
+                assert!(seen.insert(word), "{} maps to several moods", word);
+            }
+        }
+    }
+}
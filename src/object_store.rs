@@ -0,0 +1,172 @@
+use async_trait::async_trait;
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::Bytes;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+const DEFAULT_DIR: &str = "/tmp/teal-objects";
+
+/// Stores binary objects such as tell attachments under `/`-separated keys.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> anyhow::Result<()>;
+
+    /// Returns the object stored under `key`, or `None` if there is none.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>>;
+}
+
+/// Keeps objects as files below a root directory. Used for local runs and tests.
+pub struct FsObjectStore {
+    root: PathBuf,
+}
+
+impl FsObjectStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Rejects keys that would escape the root directory.
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let relative = Path::new(key);
+        let is_plain = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+        if key.is_empty() || !is_plain {
+            return Err(anyhow::anyhow!("Invalid object key '{}'", key));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FsObjectStore {
+    async fn put(&self, key: &str, _content_type: &str, data: Bytes) -> anyhow::Result<()> {
+        let path = self.path(key)?;
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&path, &data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Keeps objects in an S3 bucket.
+pub struct S3ObjectStore {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+impl S3ObjectStore {
+    pub async fn init(bucket: &str) -> Self {
+        let config = aws_config::load_from_env().await;
+        Self {
+            client: aws_sdk_s3::Client::new(&config),
+            bucket: bucket.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn put(&self, key: &str, content_type: &str, data: Bytes) -> anyhow::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_type(content_type)
+            .body(ByteStream::from(data))
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Bytes>> {
+        let res = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        match res {
+            Ok(object) => Ok(Some(object.body.collect().await?.into_bytes())),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+static OBJECT_STORE: OnceLock<Arc<dyn ObjectStore>> = OnceLock::new();
+
+pub fn init_global_store(store: Arc<dyn ObjectStore>) {
+    OBJECT_STORE.set(store).ok();
+}
+
+pub fn use_store() -> &'static Arc<dyn ObjectStore> {
+    OBJECT_STORE.get().expect("Object store not initialized")
+}
+
+/// Sets up the store selected by `TEAL_OBJECT_STORE`: `fs` (default, below
+/// `TEAL_OBJECT_STORE_DIR`) or `s3` (in `TEAL_OBJECT_STORE_BUCKET`).
+pub async fn initialize_store() -> anyhow::Result<()> {
+    let store: Arc<dyn ObjectStore> = match std::env::var("TEAL_OBJECT_STORE")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "" | "fs" => Arc::new(FsObjectStore::new(
+            std::env::var("TEAL_OBJECT_STORE_DIR").unwrap_or_else(|_| DEFAULT_DIR.to_string()),
+        )),
+        "s3" => {
+            let bucket = std::env::var("TEAL_OBJECT_STORE_BUCKET").map_err(|_| {
+                anyhow::anyhow!("TEAL_OBJECT_STORE_BUCKET must be set for the s3 object store")
+            })?;
+            Arc::new(S3ObjectStore::init(&bucket).await)
+        }
+        other => return Err(anyhow::anyhow!("Unknown object store '{}'", other)),
+    };
+    init_global_store(store);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fs_object_store_round_trip() {
+        let root = std::env::temp_dir().join(format!("teal-objects-{}", uuid::Uuid::new_v4()));
+        let store = FsObjectStore::new(&root);
+
+        store
+            .put(
+                "tells/t1/image-0.png",
+                "image/png",
+                Bytes::from_static(b"png"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("tells/t1/image-0.png").await.unwrap(),
+            Some(Bytes::from_static(b"png"))
+        );
+        assert_eq!(store.get("tells/t2/image-0.png").await.unwrap(), None);
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[tokio::test]
+    async fn test_fs_object_store_rejects_escaping_keys() {
+        let store = FsObjectStore::new(std::env::temp_dir());
+        for key in ["../etc/passwd", "/etc/passwd", "tells/../../x", ""] {
+            assert!(store.get(key).await.is_err(), "{}", key);
+        }
+    }
+}
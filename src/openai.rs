@@ -0,0 +1,586 @@
+use crate::http_client::{HttpClient, HttpRequest};
+use crate::llm::{FinishReason, LlmProvider, LlmRequest, LlmResponse, Media, Role, ToolCall};
+use crate::secrets::SecretSource;
+use crate::usage::TokenUsage;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use std::sync::Arc;
+
+const DEFAULT_BASE_URL: &str = "http://localhost:8080/v1";
+/// Audio types an `input_audio` part takes, with the format name it expects. Gemini accepts
+/// more, so other clips are rejected here rather than with a 400 from the server.
+const AUDIO_FORMATS: &[(&str, &str)] = &[("audio/wav", "wav"), ("audio/mp3", "mp3")];
+/// Image types an `image_url` data URL may hold; HEIC/HEIF photos, which Gemini takes, are not.
+const IMAGE_TYPES: &[&str] = &["image/jpeg", "image/png", "image/webp", "image/gif"];
+
+#[derive(Deserialize)]
+struct ChatCompletionResponse {
+    pub choices: Vec<Choice>,
+    pub usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u32,
+    #[serde(default)]
+    pub completion_tokens: u32,
+    #[serde(default)]
+    pub total_tokens: u32,
+}
+
+impl From<&Usage> for TokenUsage {
+    fn from(usage: &Usage) -> Self {
+        TokenUsage {
+            prompt_tokens: usage.prompt_tokens,
+            candidate_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    pub data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct Choice {
+    pub message: Message,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ChatToolCall>>,
+}
+
+#[derive(Deserialize)]
+struct ChatToolCall {
+    pub id: String,
+    pub function: ChatFunctionCall,
+}
+
+#[derive(Deserialize)]
+struct ChatFunctionCall {
+    pub name: String,
+    /// The arguments as a JSON-encoded string.
+    pub arguments: String,
+}
+
+/// Speaks the OpenAI `/v1/chat/completions` protocol, so it works against llama.cpp server,
+/// Ollama, vLLM and any other vendor exposing the same API.
+pub struct OpenAiProvider {
+    base_url: String,
+    model: String,
+    embedding_model: Option<String>,
+    secrets: Arc<dyn SecretSource>,
+    http: Arc<dyn HttpClient>,
+}
+
+impl OpenAiProvider {
+    /// `model` overrides `OPENAI_MODEL`, which is otherwise required. The `OPENAI_API_KEY`
+    /// secret is optional, since local servers usually don't need one.
+    pub fn from_env(
+        model: Option<&str>,
+        secrets: Arc<dyn SecretSource>,
+        http: Arc<dyn HttpClient>,
+    ) -> anyhow::Result<Self> {
+        let model = match model {
+            Some(model) => model.to_string(),
+            None => std::env::var("OPENAI_MODEL")
+                .map_err(|_| anyhow::anyhow!("OPENAI_MODEL must be set for the openai provider"))?,
+        };
+
+        Ok(Self {
+            base_url: std::env::var("OPENAI_BASE_URL")
+                .unwrap_or_else(|_| DEFAULT_BASE_URL.to_string()),
+            model,
+            embedding_model: std::env::var("OPENAI_EMBEDDING_MODEL").ok(),
+            secrets,
+            http,
+        })
+    }
+}
+
+impl OpenAiProvider {
+    async fn api_key(&self) -> anyhow::Result<Option<String>> {
+        self.secrets.get("OPENAI_API_KEY").await
+    }
+}
+
+#[async_trait]
+impl LlmProvider for OpenAiProvider {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+
+        let req = HttpRequest::post(url, build_request_body(&self.model, request)?)
+            .bearer_auth(self.api_key().await?.as_deref());
+
+        let res = self.http.send(&req).await?.error_for_status()?;
+        let body: ChatCompletionResponse = res.json()?;
+
+        Ok(LlmResponse {
+            text: extract_text(&body),
+            provider: self.name().to_string(),
+            model: self.model.clone(),
+            usage: body.usage.as_ref().map(TokenUsage::from),
+            finish_reason: finish_reason(&body),
+            tool_calls: extract_tool_calls(&body),
+        })
+    }
+
+    /// Uses `/v1/embeddings`, e.g. a local embedder served by llama.cpp or Ollama. Requires
+    /// `OPENAI_EMBEDDING_MODEL`.
+    async fn embed(&self, text: &str) -> anyhow::Result<Vec<f32>> {
+        let model = self
+            .embedding_model
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("OPENAI_EMBEDDING_MODEL is not set"))?;
+        let url = format!("{}/embeddings", self.base_url.trim_end_matches('/'));
+
+        let req = HttpRequest::post(url, serde_json::json!({ "model": model, "input": text }))
+            .bearer_auth(self.api_key().await?.as_deref());
+
+        let res = self.http.send(&req).await?.error_for_status()?;
+        let body: EmbeddingResponse = res.json()?;
+
+        body.data
+            .into_iter()
+            .next()
+            .map(|d| d.embedding)
+            .ok_or_else(|| anyhow::anyhow!("Embedding response contained no data"))
+    }
+}
+
+/// The content part of an attached image or clip: images as data URLs, audio as `input_audio`.
+fn media_part(media: &Media) -> anyhow::Result<serde_json::Value> {
+    if media.mime_type.starts_with("audio/") {
+        let format = AUDIO_FORMATS
+            .iter()
+            .find(|(mime_type, _)| *mime_type == media.mime_type)
+            .map(|(_, format)| *format)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "OpenAI-compatible providers don't take {} clips, only wav or mp3",
+                    media.mime_type
+                )
+            })?;
+        return Ok(serde_json::json!({
+            "type": "input_audio",
+            "input_audio": {
+                "data": BASE64.encode(&media.data),
+                "format": format,
+            },
+        }));
+    }
+    if !IMAGE_TYPES.contains(&media.mime_type.as_str()) {
+        return Err(anyhow::anyhow!(
+            "OpenAI-compatible providers don't take {} images, only JPEG, PNG, WebP or GIF",
+            media.mime_type
+        ));
+    }
+    Ok(serde_json::json!({
+        "type": "image_url",
+        "image_url": {
+            "url": format!("data:{};base64,{}", media.mime_type, BASE64.encode(&media.data)),
+        },
+    }))
+}
+
+fn build_request_body(model: &str, request: &LlmRequest) -> anyhow::Result<serde_json::Value> {
+    let mut messages = vec![serde_json::json!({
+        "role": "system",
+        "content": &request.system_instruction,
+    })];
+    for message in &request.messages {
+        match message.role {
+            // Each tool result is a message of its own, paired with its call by id.
+            Role::Tool => messages.extend(message.tool_results.iter().map(|result| {
+                serde_json::json!({
+                    "role": "tool",
+                    "tool_call_id": &result.call_id,
+                    "content": result.content.to_string(),
+                })
+            })),
+            Role::User if message.media.is_empty() => messages.push(serde_json::json!({
+                "role": "user",
+                "content": &message.text,
+            })),
+            // Media turns use the content-part form.
+            Role::User => {
+                let mut content =
+                    vec![serde_json::json!({ "type": "text", "text": &message.text })];
+                for media in &message.media {
+                    content.push(media_part(media)?);
+                }
+                messages.push(serde_json::json!({ "role": "user", "content": content }));
+            }
+            Role::Model => {
+                let mut turn = serde_json::json!({
+                    "role": "assistant",
+                    "content": &message.text,
+                });
+                if !message.tool_calls.is_empty() {
+                    let calls: Vec<serde_json::Value> = message
+                        .tool_calls
+                        .iter()
+                        .map(|call| {
+                            serde_json::json!({
+                                "id": &call.id,
+                                "type": "function",
+                                "function": {
+                                    "name": &call.name,
+                                    "arguments": call.args.to_string(),
+                                },
+                            })
+                        })
+                        .collect();
+                    turn["tool_calls"] = calls.into();
+                }
+                messages.push(turn);
+            }
+        }
+    }
+
+    let mut body = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "temperature": request.generation_config.temperature,
+        "max_tokens": request.generation_config.max_output_tokens,
+    });
+    if request.output.is_json() {
+        body["response_format"] = serde_json::json!({ "type": "json_object" });
+    }
+    if !request.tools.is_empty() {
+        let tools: Vec<serde_json::Value> = request
+            .tools
+            .iter()
+            .map(|tool| serde_json::json!({ "type": "function", "function": tool }))
+            .collect();
+        body["tools"] = tools.into();
+    }
+    Ok(body)
+}
+
+fn extract_text(body: &ChatCompletionResponse) -> String {
+    let Some(message) = body.choices.first().map(|c| &c.message) else {
+        return "The model is not in a mood today!".to_string();
+    };
+    match (&message.content, &message.tool_calls) {
+        (Some(content), _) => content.clone(),
+        (None, Some(calls)) if !calls.is_empty() => String::new(),
+        (None, _) => "The model is not in a mood today!".to_string(),
+    }
+}
+
+/// Arguments that aren't valid JSON are passed on as a string, so the tool reports them as
+/// missing instead of the whole tell failing.
+fn extract_tool_calls(body: &ChatCompletionResponse) -> Vec<ToolCall> {
+    body.choices
+        .first()
+        .and_then(|c| c.message.tool_calls.as_ref())
+        .map(|calls| {
+            calls
+                .iter()
+                .map(|call| ToolCall {
+                    id: call.id.clone(),
+                    name: call.function.name.clone(),
+                    args: serde_json::from_str(&call.function.arguments)
+                        .unwrap_or_else(|_| call.function.arguments.clone().into()),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn finish_reason(body: &ChatCompletionResponse) -> Option<FinishReason> {
+    let reason = body.choices.first()?.finish_reason.as_deref()?;
+    Some(match reason {
+        "stop" => FinishReason::Stop,
+        "length" => FinishReason::MaxTokens,
+        "content_filter" => FinishReason::Blocked(reason.to_string()),
+        other => FinishReason::Other(other.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{GenerationConfig, Message, OutputFormat};
+    use serde_json::json;
+
+    #[test]
+    fn test_build_request_body() {
+        let request = LlmRequest {
+            system_instruction: "Be kind.".to_string(),
+            messages: vec![Message::user("Hello")],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Json(Vec::new()),
+            tools: Vec::new(),
+        };
+
+        let body = build_request_body("llama-3", &request).unwrap();
+        assert_eq!(body["model"], "llama-3");
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["messages"][0]["content"], "Be kind.");
+        assert_eq!(body["messages"][1]["role"], "user");
+        assert_eq!(body["messages"][1]["content"], "Hello");
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["max_tokens"], 500);
+        assert_eq!(body["response_format"]["type"], "json_object");
+    }
+
+    #[test]
+    fn test_build_request_body_multi_turn() {
+        let request = LlmRequest {
+            system_instruction: "Be kind.".to_string(),
+            messages: vec![
+                Message::user("I lost my job."),
+                Message::model("That is hard."),
+                Message::user("I found a new one!"),
+            ],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Json(Vec::new()),
+            tools: Vec::new(),
+        };
+
+        let body = build_request_body("llama-3", &request).unwrap();
+        let roles: Vec<&str> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| m["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+    }
+
+    #[test]
+    fn test_build_request_body_without_json_mode() {
+        let request = LlmRequest {
+            system_instruction: String::new(),
+            messages: vec![Message::user("Hello")],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Text,
+            tools: Vec::new(),
+        };
+
+        let body = build_request_body("llama-3", &request).unwrap();
+        assert!(body.get("response_format").is_none());
+    }
+
+    #[test]
+    fn test_chat_completion_extraction() {
+        let json_data = json!({
+            "id": "chatcmpl-1",
+            "choices": [
+                {
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": "{\"answer\":\"Hello\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}"
+                    },
+                    "finish_reason": "stop"
+                }
+            ],
+            "usage": {
+                "prompt_tokens": 30,
+                "completion_tokens": 12,
+                "total_tokens": 42
+            }
+        });
+
+        let response: ChatCompletionResponse = serde_json::from_value(json_data).unwrap();
+        assert_eq!(
+            extract_text(&response),
+            "{\"answer\":\"Hello\",\"summary\":\"Test\",\"user_state\":\"good\",\"mood\":\"happy\"}"
+        );
+
+        assert_eq!(finish_reason(&response), Some(FinishReason::Stop));
+
+        let usage = TokenUsage::from(response.usage.as_ref().unwrap());
+        assert_eq!(usage.prompt_tokens, 30);
+        assert_eq!(usage.candidate_tokens, 12);
+        assert_eq!(usage.total_tokens, 42);
+    }
+
+    #[test]
+    fn test_build_request_body_with_media() {
+        use crate::llm::Media;
+
+        let request = LlmRequest {
+            system_instruction: String::new(),
+            messages: vec![Message::user("This view made my day").with_media(vec![
+                Media {
+                    mime_type: "image/png".to_string(),
+                    data: bytes::Bytes::from_static(b"png"),
+                },
+                Media {
+                    mime_type: "audio/mp3".to_string(),
+                    data: bytes::Bytes::from_static(b"mp3"),
+                },
+            ])],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Text,
+            tools: Vec::new(),
+        };
+
+        let body = build_request_body("llava", &request).unwrap();
+        assert_eq!(
+            body["messages"][1]["content"],
+            json!([
+                { "type": "text", "text": "This view made my day" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,cG5n" } },
+                { "type": "input_audio", "input_audio": { "data": "bXAz", "format": "mp3" } },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_build_request_body_rejects_other_audio_types() {
+        let request = LlmRequest {
+            system_instruction: String::new(),
+            messages: vec![Message::user("").with_media(vec![Media {
+                mime_type: "audio/ogg".to_string(),
+                data: bytes::Bytes::from_static(b"OggS"),
+            }])],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Text,
+            tools: Vec::new(),
+        };
+
+        let err = build_request_body("whisper", &request).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "OpenAI-compatible providers don't take audio/ogg clips, only wav or mp3"
+        );
+    }
+
+    #[test]
+    fn test_build_request_body_rejects_heic_images() {
+        let request = LlmRequest {
+            system_instruction: String::new(),
+            messages: vec![Message::user("My garden").with_media(vec![Media {
+                mime_type: "image/heic".to_string(),
+                data: bytes::Bytes::from_static(b"heic"),
+            }])],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Text,
+            tools: Vec::new(),
+        };
+
+        let err = build_request_body("llava", &request).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "OpenAI-compatible providers don't take image/heic images, only JPEG, PNG, WebP or GIF"
+        );
+    }
+
+    #[test]
+    fn test_build_request_body_with_tools() {
+        use crate::llm::{ToolCall, ToolDeclaration, ToolResult};
+
+        let request = LlmRequest {
+            system_instruction: String::new(),
+            messages: vec![
+                Message::user("How was my week?"),
+                Message::tool_calls(
+                    "",
+                    vec![ToolCall {
+                        id: "call_1".to_string(),
+                        name: "get_mood_history".to_string(),
+                        args: json!({ "days": 7 }),
+                    }],
+                ),
+                Message::tool_results(vec![ToolResult {
+                    call_id: "call_1".to_string(),
+                    name: "get_mood_history".to_string(),
+                    content: json!([]),
+                }]),
+            ],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Text,
+            tools: vec![ToolDeclaration {
+                name: "get_user_profile",
+                description: "Returns the profile.",
+                parameters: None,
+            }],
+        };
+
+        let body = build_request_body("llama-3", &request).unwrap();
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["tools"][0]["function"]["name"], "get_user_profile");
+        assert_eq!(
+            body["messages"][2]["tool_calls"][0]["function"]["arguments"],
+            "{\"days\":7}"
+        );
+        assert_eq!(
+            body["messages"][3],
+            json!({ "role": "tool", "tool_call_id": "call_1", "content": "[]" })
+        );
+    }
+
+    #[test]
+    fn test_extract_tool_calls() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "choices": [{
+                "message": {
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "search_past_tells", "arguments": "{\"query\":\"work\"}" }
+                    }]
+                },
+                "finish_reason": "tool_calls"
+            }]
+        }))
+        .unwrap();
+
+        let calls = extract_tool_calls(&response);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].args, json!({ "query": "work" }));
+        assert_eq!(extract_text(&response), "");
+    }
+
+    #[test]
+    fn test_embedding_response_deserialization() {
+        let json_data = json!({
+            "object": "list",
+            "data": [{ "object": "embedding", "index": 0, "embedding": [0.5, 0.25] }],
+            "model": "nomic-embed-text"
+        });
+
+        let response: EmbeddingResponse = serde_json::from_value(json_data).unwrap();
+        assert_eq!(response.data[0].embedding, vec![0.5, 0.25]);
+    }
+
+    #[test]
+    fn test_finish_reason_mapping() {
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "choices": [{ "message": { "content": null }, "finish_reason": "content_filter" }]
+        }))
+        .unwrap();
+        assert_eq!(
+            finish_reason(&response),
+            Some(FinishReason::Blocked("content_filter".to_string()))
+        );
+
+        let response: ChatCompletionResponse = serde_json::from_value(json!({
+            "choices": [{ "message": { "content": "{" }, "finish_reason": "length" }]
+        }))
+        .unwrap();
+        assert_eq!(finish_reason(&response), Some(FinishReason::MaxTokens));
+    }
+}
@@ -0,0 +1,344 @@
+use crate::dynamo::{use_db, PROMPT_OVERRIDES_TABLE_NAME};
+use crate::object_store::{FsObjectStore, ObjectStore, S3ObjectStore};
+use crate::prompts::check_override;
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(60);
+const DEFAULT_PREFIX: &str = "prompts/";
+
+/// Where replacement prompt templates come from, looked up by file name, e.g. `tell.md`.
+/// Personas are not overridable.
+#[async_trait]
+pub trait OverrideSource: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Returns the override of `filename`, or `None` if it is not overridden.
+    async fn get(&self, filename: &str) -> anyhow::Result<Option<String>>;
+}
+
+/// Reads overrides from an object store: a local directory in development, S3 in production.
+pub struct StoreOverrides {
+    name: &'static str,
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl StoreOverrides {
+    pub fn new(name: &'static str, store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self {
+            name,
+            store,
+            prefix: prefix.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl OverrideSource for StoreOverrides {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn get(&self, filename: &str) -> anyhow::Result<Option<String>> {
+        let key = format!("{}{}", self.prefix, filename);
+        match self.store.get(&key).await? {
+            Some(data) => Ok(Some(String::from_utf8(data.to_vec()).map_err(|_| {
+                anyhow::anyhow!("Invalid UTF-8 in prompt override '{}'", key)
+            })?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OverrideItem {
+    source: String,
+}
+
+/// Reads overrides from the `teal-prompt-overrides` table, keyed on the file name, with the
+/// prompt file in `source`.
+pub struct DynamoOverrides;
+
+#[async_trait]
+impl OverrideSource for DynamoOverrides {
+    fn name(&self) -> &str {
+        "dynamo"
+    }
+
+    async fn get(&self, filename: &str) -> anyhow::Result<Option<String>> {
+        let items: Vec<OverrideItem> = use_db()
+            .scan(PROMPT_OVERRIDES_TABLE_NAME, "tid", filename)
+            .await?;
+        Ok(items.into_iter().next().map(|item| item.source))
+    }
+}
+
+/// A validated override and the hex SHA-256 of its contents.
+#[derive(Debug, PartialEq)]
+pub struct PromptOverride {
+    pub source: String,
+    pub hash: String,
+}
+
+struct CachedOverride {
+    value: Option<Arc<PromptOverride>>,
+    fetched_at: Instant,
+}
+
+/// Caches lookups, including the prompts that are not overridden, for `ttl`. Overrides that
+/// fail validation are logged and ignored, so a bad upload never breaks a prompt.
+pub struct CachedOverrides {
+    source: Arc<dyn OverrideSource>,
+    ttl: Duration,
+    cache: RwLock<HashMap<String, CachedOverride>>,
+}
+
+impl CachedOverrides {
+    pub fn new(source: Arc<dyn OverrideSource>, ttl: Duration) -> Self {
+        Self {
+            source,
+            ttl,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn cached(&self, filename: &str, fresh_only: bool) -> Option<Option<Arc<PromptOverride>>> {
+        let cache = self
+            .cache
+            .read()
+            .expect("Prompt override cache lock poisoned");
+        cache
+            .get(filename)
+            .filter(|c| !fresh_only || c.fetched_at.elapsed() < self.ttl)
+            .map(|c| c.value.clone())
+    }
+
+    /// The override of `filename`, if there is a valid one. A failed lookup keeps serving the
+    /// last known value, or the embedded prompt if there is none.
+    pub async fn get(&self, filename: &str) -> Option<Arc<PromptOverride>> {
+        if let Some(value) = self.cached(filename, true) {
+            return value;
+        }
+
+        let value = match self.source.get(filename).await {
+            Ok(Some(source)) => match check_override(filename, &source) {
+                Ok(()) => Some(Arc::new(PromptOverride {
+                    hash: format!("{:x}", Sha256::digest(&source)),
+                    source,
+                })),
+                Err(e) => {
+                    eprintln!("Ignoring invalid override of prompt '{}': {}", filename, e);
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("Failed to load override of prompt '{}': {:?}", filename, e);
+                return self.cached(filename, false).flatten();
+            }
+        };
+        let mut cache = self
+            .cache
+            .write()
+            .expect("Prompt override cache lock poisoned");
+        cache.insert(
+            filename.to_string(),
+            CachedOverride {
+                value: value.clone(),
+                fetched_at: Instant::now(),
+            },
+        );
+        value
+    }
+}
+
+static OVERRIDES: OnceLock<Arc<CachedOverrides>> = OnceLock::new();
+
+pub fn init_global_overrides(overrides: Arc<CachedOverrides>) {
+    OVERRIDES.set(overrides).ok();
+}
+
+/// The configured overrides, or `None` when prompts are only read from the binary.
+pub fn use_overrides() -> Option<&'static Arc<CachedOverrides>> {
+    OVERRIDES.get()
+}
+
+/// Sets up the source selected by `TEAL_PROMPT_OVERRIDES`: unset for none, `dir` (below
+/// `TEAL_PROMPT_OVERRIDES_DIR`), `s3` (in `TEAL_PROMPT_OVERRIDES_BUCKET`, under
+/// `TEAL_PROMPT_OVERRIDES_PREFIX`) or `dynamo`. Lookups are cached for
+/// `TEAL_PROMPT_OVERRIDES_TTL_SECS` (default 60).
+pub async fn initialize_overrides() -> anyhow::Result<()> {
+    let source: Arc<dyn OverrideSource> = match std::env::var("TEAL_PROMPT_OVERRIDES")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "" => return Ok(()),
+        "dir" => {
+            let dir = std::env::var("TEAL_PROMPT_OVERRIDES_DIR").map_err(|_| {
+                anyhow::anyhow!("TEAL_PROMPT_OVERRIDES_DIR must be set for dir prompt overrides")
+            })?;
+            Arc::new(StoreOverrides::new(
+                "dir",
+                Arc::new(FsObjectStore::new(dir)),
+                "",
+            ))
+        }
+        "s3" => {
+            let bucket = std::env::var("TEAL_PROMPT_OVERRIDES_BUCKET").map_err(|_| {
+                anyhow::anyhow!("TEAL_PROMPT_OVERRIDES_BUCKET must be set for s3 prompt overrides")
+            })?;
+            let prefix = std::env::var("TEAL_PROMPT_OVERRIDES_PREFIX")
+                .unwrap_or_else(|_| DEFAULT_PREFIX.to_string());
+            Arc::new(StoreOverrides::new(
+                "s3",
+                Arc::new(S3ObjectStore::init(&bucket).await),
+                &prefix,
+            ))
+        }
+        "dynamo" => Arc::new(DynamoOverrides),
+        other => {
+            return Err(anyhow::anyhow!(
+                "Unknown prompt override source '{}'",
+                other
+            ))
+        }
+    };
+    let ttl = match std::env::var("TEAL_PROMPT_OVERRIDES_TTL_SECS") {
+        Ok(value) => Duration::from_secs(value.parse()?),
+        Err(_) => DEFAULT_TTL,
+    };
+
+    let overrides = CachedOverrides::new(source, ttl);
+    println!("Using prompt overrides from {}", overrides.name());
+    init_global_overrides(Arc::new(overrides));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    /// In-memory stand-in for a remote override store that counts its lookups.
+    struct MemoryOverrides {
+        files: Mutex<HashMap<String, String>>,
+        fail: std::sync::atomic::AtomicBool,
+        lookups: AtomicU32,
+    }
+
+    impl MemoryOverrides {
+        fn new(files: &[(&str, &str)]) -> Arc<Self> {
+            Arc::new(Self {
+                files: Mutex::new(
+                    files
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                fail: std::sync::atomic::AtomicBool::new(false),
+                lookups: AtomicU32::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl OverrideSource for MemoryOverrides {
+        fn name(&self) -> &str {
+            "memory"
+        }
+
+        async fn get(&self, filename: &str) -> anyhow::Result<Option<String>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("store unavailable"));
+            }
+            Ok(self.files.lock().unwrap().get(filename).cloned())
+        }
+    }
+
+    const TITLE_OVERRIDE: &str = "---\nversion: 2\n---\nName this entry: {tell}";
+
+    #[tokio::test]
+    async fn test_overrides_are_cached() {
+        let source = MemoryOverrides::new(&[("tell_title.md", TITLE_OVERRIDE)]);
+        let overrides = CachedOverrides::new(source.clone(), Duration::from_secs(60));
+
+        let found = overrides.get("tell_title.md").await.unwrap();
+        assert_eq!(found.source, TITLE_OVERRIDE);
+        assert_eq!(found.hash.len(), 64);
+        assert!(overrides.get("tell_title.md").await.is_some());
+        assert!(overrides.get("tell.md").await.is_none());
+        assert!(overrides.get("tell.md").await.is_none());
+        assert_eq!(source.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_expired_overrides_are_reloaded() {
+        let source = MemoryOverrides::new(&[("tell_title.md", TITLE_OVERRIDE)]);
+        let overrides = CachedOverrides::new(source.clone(), Duration::ZERO);
+        assert!(overrides.get("tell_title.md").await.is_some());
+
+        source.files.lock().unwrap().clear();
+        assert!(overrides.get("tell_title.md").await.is_none());
+        assert_eq!(source.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookups_serve_the_last_known_override() {
+        let source = MemoryOverrides::new(&[("tell_title.md", TITLE_OVERRIDE)]);
+        let overrides = CachedOverrides::new(source.clone(), Duration::ZERO);
+        assert!(overrides.get("tell_title.md").await.is_some());
+
+        source.fail.store(true, Ordering::SeqCst);
+        assert!(overrides.get("tell_title.md").await.is_some());
+        assert!(overrides.get("tell.md").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_overrides_are_ignored() {
+        let source = MemoryOverrides::new(&[
+            // `{tell}` is missing, so the user's text would never reach the model.
+            ("tell_title.md", "Name this entry."),
+            ("transcribe.md", "---\ntemprature: 0\n---\nTranscribe."),
+            ("unknown.md", "Hi"),
+        ]);
+        let overrides = CachedOverrides::new(source, Duration::from_secs(60));
+        assert!(overrides.get("tell_title.md").await.is_none());
+        assert!(overrides.get("transcribe.md").await.is_none());
+        assert!(overrides.get("unknown.md").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_store_overrides_read_below_the_prefix() {
+        let root = std::env::temp_dir().join(format!("teal-prompts-{}", uuid::Uuid::new_v4()));
+        let store = Arc::new(FsObjectStore::new(&root));
+        store
+            .put(
+                "prompts/tell_title.md",
+                "text/markdown",
+                Bytes::from_static(b"Name this entry: {tell}"),
+            )
+            .await
+            .unwrap();
+
+        let source = StoreOverrides::new("dir", store, "prompts/");
+        assert_eq!(
+            source.get("tell_title.md").await.unwrap(),
+            Some("Name this entry: {tell}".to_string())
+        );
+        assert_eq!(source.get("tell.md").await.unwrap(), None);
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+}
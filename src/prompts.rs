@@ -1,18 +1,87 @@
+use crate::experiments::Assignment;
+use crate::injection::{user_block, user_inline, GUARD_INSTRUCTION};
+use crate::llm::{GenerationConfig, Media, Message, OutputField, OutputFormat};
+use crate::locale::Locale;
+use crate::prompt_overrides::{use_overrides, CachedOverrides};
+use crate::template::{placeholders, render};
 use include_dir::{include_dir, Dir};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 static PROMPTS_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/prompts");
 
+include!(concat!(env!("OUT_DIR"), "/prompt_hashes.rs"));
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PromptName {
     Tell,
+    Transcribe,
+    /// Folds tell summaries into a long-term memory paragraph.
+    MemoryRollup,
+    /// A letter looking back on a week of tells.
+    WeeklyReflection,
+    /// A few words naming a tell.
+    TellTitle,
+    /// Something to write about today.
+    DailySuggestion,
 }
 
 impl PromptName {
+    /// Every prompt, so tests can check each one has a valid asset. A new prompt breaks the match
+    /// in `test_all_lists_every_prompt` until it is listed here too.
+    #[cfg(test)]
+    pub const ALL: [PromptName; 6] = [
+        PromptName::Tell,
+        PromptName::Transcribe,
+        PromptName::MemoryRollup,
+        PromptName::WeeklyReflection,
+        PromptName::TellTitle,
+        PromptName::DailySuggestion,
+    ];
+
     pub fn as_str(&self) -> &'static str {
         match self {
             PromptName::Tell => "tell.md",
+            PromptName::Transcribe => "transcribe.md",
+            PromptName::MemoryRollup => "memory_rollup.md",
+            PromptName::WeeklyReflection => "weekly_reflection.md",
+            PromptName::TellTitle => "tell_title.md",
+            PromptName::DailySuggestion => "daily_suggestion.md",
+        }
+    }
+}
+
+/// The voice Teal answers in. Each persona's system instruction lives in
+/// `prompts/personas/<id>.md`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Persona {
+    #[default]
+    Therapist,
+    StoicCoach,
+    GentleFriend,
+    Concise,
+}
+
+impl Persona {
+    pub fn id(&self) -> &'static str {
+        match self {
+            Persona::Therapist => "therapist",
+            Persona::StoicCoach => "stoic_coach",
+            Persona::GentleFriend => "gentle_friend",
+            Persona::Concise => "concise",
         }
     }
+
+    pub fn system_instruction(&self) -> anyhow::Result<&'static str> {
+        let filename = format!("personas/{}.md", self.id());
+        let instruction = PROMPTS_DIR
+            .get_file(&filename)
+            .ok_or_else(|| anyhow::anyhow!("Persona '{}' not found", filename))?
+            .contents_utf8()
+            .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in persona '{}'", filename))?;
+        Ok(instruction.trim())
+    }
 }
 
 pub struct TellReplacements<'a> {
@@ -21,46 +90,897 @@ pub struct TellReplacements<'a> {
     pub tell: &'a str,
 }
 
+/// A past tell as the summarizing prompts see it.
+pub struct Entry<'a> {
+    pub date: chrono::NaiveDate,
+    pub mood: &'a str,
+    pub summary: &'a str,
+}
+
+/// One entry per line, oldest first, e.g. `- 2024-05-02 (calm): Went hiking.`
+fn format_entries(entries: &[Entry]) -> String {
+    if entries.is_empty() {
+        return "(none)".to_string();
+    }
+    entries
+        .iter()
+        .map(|e| format!("- {} ({}): {}", e.date, e.mood, e.summary))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub struct MemoryRollupReplacements<'a> {
+    pub username: &'a str,
+    /// The memory paragraph so far; empty on the first rollup.
+    pub memory: &'a str,
+    pub entries: &'a [Entry<'a>],
+}
+
+pub struct WeeklyReflectionReplacements<'a> {
+    pub username: &'a str,
+    /// Monday of the week.
+    pub week_start: chrono::NaiveDate,
+    pub entries: &'a [Entry<'a>],
+}
+
+pub struct TellTitleReplacements<'a> {
+    pub tell: &'a str,
+}
+
+pub struct DailySuggestionReplacements<'a> {
+    pub username: &'a str,
+    pub date: chrono::NaiveDate,
+    /// The latest tells, for something to build on.
+    pub entries: &'a [Entry<'a>],
+}
+
 pub enum PromptData<'a> {
     Tell(TellReplacements<'a>),
+    /// The transcription prompt takes no replacements.
+    Transcribe,
+    MemoryRollup(MemoryRollupReplacements<'a>),
+    WeeklyReflection(WeeklyReflectionReplacements<'a>),
+    TellTitle(TellTitleReplacements<'a>),
+    DailySuggestion(DailySuggestionReplacements<'a>),
 }
 
-pub fn create_prompt(prompt_name: PromptName, data: PromptData) -> anyhow::Result<String> {
-    let filename = prompt_name.as_str();
-    let template = PROMPTS_DIR
+impl PromptData<'_> {
+    /// The prompt these replacements are for.
+    pub fn prompt_name(&self) -> PromptName {
+        match self {
+            PromptData::Tell(_) => PromptName::Tell,
+            PromptData::Transcribe => PromptName::Transcribe,
+            PromptData::MemoryRollup(_) => PromptName::MemoryRollup,
+            PromptData::WeeklyReflection(_) => PromptName::WeeklyReflection,
+            PromptData::TellTitle(_) => PromptName::TellTitle,
+            PromptData::DailySuggestion(_) => PromptName::DailySuggestion,
+        }
+    }
+
+    /// The placeholder values. Anything that comes from the user is escaped, and longer text is
+    /// quoted in a `<user_…>` block; see [`injection::user_block`].
+    fn values(&self) -> Vec<(&'static str, String)> {
+        match self {
+            PromptData::Tell(tell_data) => vec![
+                ("username", user_inline(tell_data.username)),
+                ("context", user_block("history", tell_data.context)),
+                ("tell", user_block("tell", tell_data.tell)),
+            ],
+            PromptData::Transcribe => Vec::new(),
+            PromptData::MemoryRollup(data) => vec![
+                ("username", user_inline(data.username)),
+                ("memory", user_block("memory", data.memory)),
+                (
+                    "entries",
+                    user_block("entries", &format_entries(data.entries)),
+                ),
+            ],
+            PromptData::WeeklyReflection(data) => vec![
+                ("username", user_inline(data.username)),
+                ("week_start", data.week_start.to_string()),
+                (
+                    "entries",
+                    user_block("entries", &format_entries(data.entries)),
+                ),
+            ],
+            PromptData::TellTitle(data) => vec![("tell", user_block("tell", data.tell))],
+            PromptData::DailySuggestion(data) => vec![
+                ("username", user_inline(data.username)),
+                ("date", data.date.to_string()),
+                (
+                    "entries",
+                    user_block("entries", &format_entries(data.entries)),
+                ),
+            ],
+        }
+    }
+}
+
+/// Optional YAML header of a prompt file, between two `---` lines. Anything left out falls back
+/// to the defaults: default generation parameters, the user's persona and plain text output.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FrontMatter {
+    /// Bumped by hand when the prompt changes in a way worth comparing; see [`PromptVersion`].
+    version: Option<u32>,
+    temperature: Option<f32>,
+    max_output_tokens: Option<u32>,
+    /// The system instruction itself.
+    system_instruction: Option<String>,
+    /// A persona to answer in, whichever one the user chose.
+    persona: Option<Persona>,
+    output: Option<OutputSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+enum OutputSpec {
+    Text,
+    Json {
+        #[serde(default)]
+        fields: Vec<OutputField>,
+    },
+}
+
+/// The instruction a prompt is sent with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SystemInstruction {
+    /// The persona chosen by the user.
+    UserPersona,
+    Persona(Persona),
+    Text(String),
+}
+
+/// Which revision of a prompt produced an answer: the version declared in its front-matter (0
+/// when it declares none) and the SHA-256 of the file, computed at build time. The hash tells
+/// apart edits made without bumping the version.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PromptVersion {
+    pub version: u32,
+    pub hash: String,
+}
+
+impl PromptVersion {
+    /// Short form for reports, e.g. `v3-1a2b3c4d`.
+    pub fn label(&self) -> String {
+        format!("v{}-{}", self.version, &self.hash[..self.hash.len().min(8)])
+    }
+}
+
+/// A rendered prompt with the parameters declared in its front-matter, ready for the LLM layer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prompt {
+    /// File name of the template, e.g. `tell.md`.
+    pub name: &'static str,
+    pub version: PromptVersion,
+    /// The language the answer should be in, whichever template was found for it.
+    pub locale: Locale,
+    /// The experiment variant the template was picked for, if the user is enrolled in one.
+    pub experiment: Option<Assignment>,
+    pub text: String,
+    pub system_instruction: SystemInstruction,
+    pub generation_config: GenerationConfig,
+    pub output: OutputFormat,
+    /// Images or audio sent along with the text.
+    pub media: Vec<Media>,
+}
+
+impl Prompt {
+    fn new(
+        name: &'static str,
+        hash: &str,
+        locale: &Locale,
+        text: String,
+        front_matter: FrontMatter,
+    ) -> anyhow::Result<Self> {
+        let system_instruction = match (front_matter.system_instruction, front_matter.persona) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow::anyhow!(
+                    "Prompt '{}' declares both a system_instruction and a persona",
+                    name
+                ))
+            }
+            (Some(text), None) => SystemInstruction::Text(text.trim().to_string()),
+            (None, Some(persona)) => SystemInstruction::Persona(persona),
+            (None, None) => SystemInstruction::UserPersona,
+        };
+        let defaults = GenerationConfig::default();
+        Ok(Self {
+            name,
+            version: PromptVersion {
+                version: front_matter.version.unwrap_or_default(),
+                hash: hash.to_string(),
+            },
+            locale: locale.clone(),
+            experiment: None,
+            text,
+            system_instruction,
+            generation_config: GenerationConfig {
+                temperature: front_matter.temperature.unwrap_or(defaults.temperature),
+                max_output_tokens: front_matter
+                    .max_output_tokens
+                    .unwrap_or(defaults.max_output_tokens),
+            },
+            output: match front_matter.output {
+                None | Some(OutputSpec::Text) => OutputFormat::Text,
+                Some(OutputSpec::Json { fields }) => OutputFormat::Json(fields),
+            },
+            media: Vec::new(),
+        })
+    }
+
+    pub fn with_media(mut self, media: Vec<Media>) -> Self {
+        self.media = media;
+        self
+    }
+
+    /// The system instruction to send; `persona` is the user's choice, used unless the prompt
+    /// declares its own. Outside English, it asks for an answer in the user's language. It always
+    /// ends with [`GUARD_INSTRUCTION`], so quoted user text is not taken for instructions.
+    pub fn instruction_for(&self, persona: Persona) -> anyhow::Result<String> {
+        let mut instruction = match &self.system_instruction {
+            SystemInstruction::UserPersona => persona.system_instruction()?.to_string(),
+            SystemInstruction::Persona(persona) => persona.system_instruction()?.to_string(),
+            SystemInstruction::Text(text) => text.clone(),
+        };
+        if !self.locale.is_default() {
+            instruction = format!(
+                "{}\n\nAlways answer in {}, the user's language, whatever language the context is in. JSON keys stay in English.",
+                instruction,
+                self.locale.language_name()
+            );
+        }
+        Ok(format!("{}\n\n{}", instruction, GUARD_INSTRUCTION))
+    }
+
+    /// The prompt as the final user turn.
+    pub fn message(&self) -> Message {
+        Message::user(self.text.clone()).with_media(self.media.clone())
+    }
+}
+
+/// A bare JSON prompt for tests that don't go through a template.
+#[cfg(test)]
+impl Prompt {
+    pub fn json(text: &str) -> Self {
+        Self {
+            name: "test.md",
+            version: PromptVersion {
+                version: 0,
+                hash: String::new(),
+            },
+            locale: Locale::default(),
+            experiment: None,
+            text: text.to_string(),
+            system_instruction: SystemInstruction::UserPersona,
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Json(Vec::new()),
+            media: Vec::new(),
+        }
+    }
+}
+
+fn template(filename: &str) -> anyhow::Result<&'static str> {
+    PROMPTS_DIR
         .get_file(filename)
         .ok_or_else(|| anyhow::anyhow!("Prompt template '{}' not found", filename))?
         .contents_utf8()
-        .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in prompt template '{}'", filename))?;
-
-    let replacements_map = match data {
-        PromptData::Tell(tell_data) => {
-            let mut map = HashMap::new();
-            map.insert("username", tell_data.username);
-            map.insert("context", tell_data.context);
-            map.insert("tell", tell_data.tell);
-            map
-        }
+        .ok_or_else(|| anyhow::anyhow!("Invalid UTF-8 in prompt template '{}'", filename))
+}
+
+/// Splits a prompt file into its front-matter and the template body.
+fn parse_template(source: &str) -> anyhow::Result<(FrontMatter, &str)> {
+    let Some(rest) = source.strip_prefix("---\n") else {
+        return Ok((FrontMatter::default(), source));
     };
+    let (yaml, body) = rest
+        .split_once("\n---\n")
+        .ok_or_else(|| anyhow::anyhow!("Front-matter is not closed by a '---' line"))?;
+    Ok((serde_yaml::from_str(yaml)?, body))
+}
+
+/// Hex SHA-256 of a prompt file, front-matter included, as computed by `build.rs`.
+fn template_hash(filename: &str) -> anyhow::Result<&'static str> {
+    PROMPT_HASHES
+        .iter()
+        .find(|(name, _)| *name == filename)
+        .map(|(_, hash)| *hash)
+        .ok_or_else(|| anyhow::anyhow!("No hash for prompt template '{}'", filename))
+}
+
+/// The English prompt a localized path such as `es/tell.md` translates.
+fn base_filename(filename: &str) -> &str {
+    filename.rsplit('/').next().unwrap_or(filename)
+}
+
+/// Checks that `source` can replace the embedded prompt `filename`, or translate it when the path
+/// is localized (`es/tell.md`); see [`check_template`].
+pub fn check_override(filename: &str, source: &str) -> anyhow::Result<()> {
+    check_template(base_filename(filename), source)
+}
 
-    let mut prompt = template.to_string();
-    for (key, value) in &replacements_map {
-        prompt = prompt.replace(&format!("{{{}}}", key), value);
+/// Checks that `source` can stand in for the English prompt `prompt`: its front-matter is valid
+/// and its template takes exactly the same placeholders, so every value `create_prompt` supplies
+/// is used and none is missing.
+pub fn check_template(prompt: &str, source: &str) -> anyhow::Result<()> {
+    let embedded = template(prompt)
+        .map_err(|_| anyhow::anyhow!("There is no prompt '{}' to override", prompt))?;
+    let (_, embedded_body) = parse_template(embedded)?;
+    let (front_matter, body) = parse_template(source)?;
+    if front_matter.system_instruction.is_some() && front_matter.persona.is_some() {
+        return Err(anyhow::anyhow!(
+            "It declares both a system_instruction and a persona"
+        ));
     }
+
+    let expected = placeholders(embedded_body);
+    let found = placeholders(body);
+    let missing: Vec<&str> = expected
+        .iter()
+        .filter(|p| !found.contains(p))
+        .copied()
+        .collect();
+    let unknown: Vec<&str> = found
+        .iter()
+        .filter(|p| !expected.contains(p))
+        .copied()
+        .collect();
+    if !missing.is_empty() || !unknown.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Placeholders differ from the embedded prompt; missing: [{}], unknown: [{}]",
+            missing.join(", "),
+            unknown.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the embedded file `path`, an experiment variant of `prompt`, can stand in for it.
+pub fn check_variant(prompt: &str, path: &str) -> anyhow::Result<()> {
+    let source = template(path)?;
+    check_template(prompt, source)
+}
+
+/// Renders the prompt `data` is for, for a user of `locale`. The template is the first one found of
+/// the localized prompts (`prompts/es-mx/`, then `prompts/es/`) and finally the English one,
+/// each from its override if one is configured and valid, or else from the file embedded in
+/// `PROMPTS_DIR`. Every placeholder of the template must be supplied and every supplied value
+/// used; user text is inserted as is, never re-expanded.
+pub async fn create_prompt(locale: &Locale, data: PromptData<'_>) -> anyhow::Result<Prompt> {
+    create_prompt_with(locale, None, data, use_overrides().map(|o| o.as_ref())).await
+}
+
+/// Like [`create_prompt`], but renders the template of the experiment variant the user was
+/// assigned, if any. Variant templates are used as they are, neither localized nor overridden;
+/// the answer is still asked for in the user's language.
+pub async fn create_variant_prompt(
+    locale: &Locale,
+    assignment: Option<&Assignment>,
+    data: PromptData<'_>,
+) -> anyhow::Result<Prompt> {
+    create_prompt_with(
+        locale,
+        assignment,
+        data,
+        use_overrides().map(|o| o.as_ref()),
+    )
+    .await
+}
+
+/// The source and hash of the first template of `candidates` that exists.
+async fn find_template(
+    candidates: &[String],
+    overrides: Option<&CachedOverrides>,
+) -> Option<(Cow<'static, str>, Cow<'static, str>)> {
+    for candidate in candidates {
+        if let Some(o) = match overrides {
+            Some(overrides) => overrides.get(candidate).await,
+            None => None,
+        } {
+            return Some((o.source.clone().into(), o.hash.clone().into()));
+        }
+        if let (Ok(source), Ok(hash)) = (template(candidate), template_hash(candidate)) {
+            return Some((source.into(), hash.into()));
+        }
+    }
+    None
+}
+
+async fn create_prompt_with(
+    locale: &Locale,
+    assignment: Option<&Assignment>,
+    data: PromptData<'_>,
+    overrides: Option<&CachedOverrides>,
+) -> anyhow::Result<Prompt> {
+    let prompt_name = data.prompt_name();
+    let filename = prompt_name.as_str();
+    let found = match assignment.and_then(|a| a.template.as_ref()) {
+        Some(variant) => find_template(std::slice::from_ref(variant), None).await,
+        None => {
+            let mut candidates: Vec<String> = locale
+                .prompt_dirs()
+                .into_iter()
+                .map(|dir| format!("{}/{}", dir, filename))
+                .collect();
+            candidates.push(filename.to_string());
+            find_template(&candidates, overrides).await
+        }
+    };
+    let (source, hash) =
+        found.ok_or_else(|| anyhow::anyhow!("Prompt template '{}' not found", filename))?;
+    let (front_matter, body) = parse_template(&source)
+        .map_err(|e| anyhow::anyhow!("Invalid front-matter in prompt '{}': {}", filename, e))?;
+
+    let values = data.values();
+    let values: Vec<(&str, &str)> = values.iter().map(|(k, v)| (*k, v.as_str())).collect();
+    let text = render(body, &values)
+        .map_err(|e| anyhow::anyhow!("Failed to render prompt '{}': {}", filename, e))?;
+    let mut prompt = Prompt::new(filename, &hash, locale, text, front_matter)?;
+    prompt.experiment = assignment.cloned();
     Ok(prompt)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_all_lists_every_prompt() {
+        // A new prompt breaks this match until it is given the next position in `ALL`.
+        let position = |prompt_name: PromptName| match prompt_name {
+            PromptName::Tell => 0,
+            PromptName::Transcribe => 1,
+            PromptName::MemoryRollup => 2,
+            PromptName::WeeklyReflection => 3,
+            PromptName::TellTitle => 4,
+            PromptName::DailySuggestion => 5,
+        };
+        let positions: Vec<usize> = PromptName::ALL.into_iter().map(position).collect();
+        assert_eq!(positions, (0..PromptName::ALL.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_every_prompt_has_a_valid_asset() {
+        for prompt_name in PromptName::ALL {
+            let filename = prompt_name.as_str();
+            let source = template(filename).unwrap();
+            let (front_matter, _) =
+                parse_template(source).unwrap_or_else(|e| panic!("{}: {}", filename, e));
+            assert!(
+                front_matter.version.is_some(),
+                "{} has no version",
+                filename
+            );
+            assert!(template_hash(filename).is_ok(), "{} has no hash", filename);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_summary_prompts_list_entries() {
+        let entries = [
+            Entry {
+                date: chrono::NaiveDate::from_ymd_opt(2024, 5, 1).unwrap(),
+                mood: "tired",
+                summary: "Worked late.",
+            },
+            Entry {
+                date: chrono::NaiveDate::from_ymd_opt(2024, 5, 2).unwrap(),
+                mood: "calm",
+                summary: "Went hiking.",
+            },
+        ];
+        let prompt = create_prompt(
+            &Locale::default(),
+            PromptData::WeeklyReflection(WeeklyReflectionReplacements {
+                username: "Jane",
+                week_start: chrono::NaiveDate::from_ymd_opt(2024, 4, 29).unwrap(),
+                entries: &entries,
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(prompt.text.contains("week starting\n2024-04-29"));
+        assert!(prompt
+            .text
+            .contains("- 2024-05-01 (tired): Worked late.\n- 2024-05-02 (calm): Went hiking."));
+        assert_eq!(prompt.system_instruction, SystemInstruction::UserPersona);
+
+        let prompt = create_prompt(
+            &Locale::default(),
+            PromptData::DailySuggestion(DailySuggestionReplacements {
+                username: "Jane",
+                date: chrono::NaiveDate::from_ymd_opt(2024, 5, 3).unwrap(),
+                entries: &[],
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(prompt
+            .text
+            .contains("entries, oldest first:\n<user_entries>\n(none)\n</user_entries>"));
+    }
 
     #[test]
     fn test_prompt_name_as_str() {
         assert_eq!(PromptName::Tell.as_str(), "tell.md");
+        assert_eq!(PromptName::Transcribe.as_str(), "transcribe.md");
+        assert_eq!(PromptName::TellTitle.as_str(), "tell_title.md");
+    }
+
+    #[test]
+    fn test_parse_template() {
+        let (front_matter, body) = parse_template(
+            "---\ntemperature: 0.2\npersona: concise\noutput:\n  format: json\n  fields:\n    - name: answer\n---\nHi {username}",
+        )
+        .unwrap();
+        assert_eq!(front_matter.temperature, Some(0.2));
+        assert_eq!(front_matter.persona, Some(Persona::Concise));
+        assert_eq!(body, "Hi {username}");
+
+        let prompt = Prompt::new(
+            "x.md",
+            "",
+            &Locale::default(),
+            body.to_string(),
+            front_matter,
+        )
+        .unwrap();
+        assert_eq!(prompt.generation_config.temperature, 0.2);
+        assert_eq!(prompt.generation_config.max_output_tokens, 500);
+        assert_eq!(
+            prompt.output,
+            OutputFormat::Json(vec![OutputField {
+                name: "answer".to_string(),
+                description: String::new(),
+            }])
+        );
+        assert!(prompt
+            .instruction_for(Persona::Therapist)
+            .unwrap()
+            .contains(Persona::Concise.system_instruction().unwrap()));
+
+        let (front_matter, body) = parse_template("No header {tell}").unwrap();
+        assert!(front_matter.output.is_none());
+        assert_eq!(body, "No header {tell}");
+    }
+
+    #[test]
+    fn test_parse_template_errors() {
+        assert!(parse_template("---\ntemperature: 0.2\nHi").is_err());
+        assert!(parse_template("---\ntemprature: 0.2\n---\nHi").is_err());
+        let (front_matter, _) =
+            parse_template("---\nsystem_instruction: Hi\npersona: concise\n---\n").unwrap();
+        assert!(Prompt::new("x.md", "", &Locale::default(), String::new(), front_matter).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tell_prompt_declares_the_reply_schema() {
+        let prompt = create_prompt(
+            &Locale::default(),
+            PromptData::Tell(TellReplacements {
+                username: "jane",
+                context: "",
+                tell: "Hi",
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(prompt.system_instruction, SystemInstruction::UserPersona);
+        assert!(!prompt.text.starts_with("---"));
+
+        // The declared fields are exactly the keys the reply is parsed into.
+        let OutputFormat::Json(fields) = &prompt.output else {
+            panic!("tell.md must ask for JSON");
+        };
+        let reply = serde_json::to_value(crate::gemini::GeminiTellResponse {
+            answer: String::new(),
+            summary: String::new(),
+            user_state: String::new(),
+            mood: String::new(),
+        })
+        .unwrap();
+        let mut keys: Vec<&str> = reply
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect();
+        let mut declared: Vec<&str> = fields.iter().map(|f| f.name.as_str()).collect();
+        keys.sort();
+        declared.sort();
+        assert_eq!(declared, keys);
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_prompt_declares_its_instruction() {
+        let prompt = create_prompt(&Locale::default(), PromptData::Transcribe)
+            .await
+            .unwrap();
+        assert_eq!(prompt.output, OutputFormat::Text);
+        assert_eq!(prompt.generation_config.temperature, 0.0);
+        assert!(prompt
+            .instruction_for(Persona::Therapist)
+            .unwrap()
+            .starts_with("Transcribe the attached voice note"));
+    }
+
+    #[test]
+    fn test_template_hash_matches_the_embedded_file() {
+        for file in PROMPTS_DIR.files().chain(
+            PROMPTS_DIR
+                .dirs()
+                .flat_map(|d| d.files())
+                .collect::<Vec<_>>(),
+        ) {
+            let name = file.path().to_string_lossy().replace('\\', "/");
+            assert_eq!(
+                template_hash(&name).unwrap(),
+                format!("{:x}", Sha256::digest(file.contents())),
+                "{}",
+                name
+            );
+        }
+        assert!(template_hash("missing.md").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_version() {
+        let prompt = create_prompt(
+            &Locale::default(),
+            PromptData::Tell(TellReplacements {
+                username: "John",
+                context: "",
+                tell: "Hello",
+            }),
+        )
+        .await
+        .unwrap();
+        let version = prompt.version;
+        assert_eq!(version.version, 2);
+        assert_eq!(version.hash, template_hash("tell.md").unwrap());
+        assert_eq!(version.label(), format!("v2-{}", &version.hash[..8]));
+        assert_ne!(version.hash, template_hash("transcribe.md").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_create_prompt_prefers_a_valid_override() {
+        use crate::object_store::{FsObjectStore, ObjectStore};
+        use crate::prompt_overrides::StoreOverrides;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let root = std::env::temp_dir().join(format!("teal-prompts-{}", uuid::Uuid::new_v4()));
+        let store = Arc::new(FsObjectStore::new(&root));
+        for (key, source) in [
+            (
+                "tell_title.md",
+                "---\nversion: 2\ntemperature: 0.1\n---\nTitle: {tell}",
+            ),
+            ("transcribe.md", "Transcribe {audio}"),
+        ] {
+            store
+                .put(key, "text/markdown", bytes::Bytes::from(source))
+                .await
+                .unwrap();
+        }
+        let overrides = CachedOverrides::new(
+            Arc::new(StoreOverrides::new("dir", store, "")),
+            Duration::from_secs(60),
+        );
+
+        let prompt = create_prompt_with(
+            &Locale::default(),
+            None,
+            PromptData::TellTitle(TellTitleReplacements { tell: "Hi" }),
+            Some(&overrides),
+        )
+        .await
+        .unwrap();
+        assert_eq!(prompt.text, "Title: <user_tell>\nHi\n</user_tell>");
+        assert_eq!(prompt.version.version, 2);
+        assert_ne!(prompt.version.hash, template_hash("tell_title.md").unwrap());
+        assert_eq!(prompt.generation_config.temperature, 0.1);
+
+        // An override with a placeholder the code doesn't supply falls back to the embedded file.
+        let prompt = create_prompt_with(
+            &Locale::default(),
+            None,
+            PromptData::Transcribe,
+            Some(&overrides),
+        )
+        .await
+        .unwrap();
+        assert_eq!(prompt.version.hash, template_hash("transcribe.md").unwrap());
+
+        std::fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn test_check_override() {
+        assert!(
+            check_override("tell_title.md", "---\nversion: 7\n---\nTitle this: {tell}").is_ok()
+        );
+
+        let err = check_override("tell.md", "Hi {username}, {tell} {mood}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Placeholders differ from the embedded prompt; missing: [context], unknown: [mood]"
+        );
+        assert!(check_override("tell_title.md", "---\ntemprature: 1\n---\n{tell}").is_err());
+        assert!(check_override(
+            "tell_title.md",
+            "---\npersona: concise\nsystem_instruction: Hi\n---\n{tell}"
+        )
+        .is_err());
+        assert!(check_override("missing.md", "Hi").is_err());
+    }
+
+    #[test]
+    fn test_localized_prompts_translate_an_english_prompt() {
+        let dirs = PROMPTS_DIR
+            .dirs()
+            .filter(|d| !["personas", "experiments"].contains(&d.path().to_str().unwrap()));
+        for file in dirs.flat_map(|d| d.files()) {
+            let name = file.path().to_string_lossy().replace('\\', "/");
+            let source = file.contents_utf8().unwrap();
+            check_override(&name, source).unwrap_or_else(|e| panic!("{}: {}", name, e));
+
+            // Replies are parsed with the English field names whatever the language.
+            let field_names = |source| match parse_template(source).unwrap().0.output {
+                Some(OutputSpec::Json { fields }) => {
+                    fields.into_iter().map(|f| f.name).collect::<Vec<_>>()
+                }
+                _ => Vec::new(),
+            };
+            let (front_matter, _) = parse_template(source).unwrap();
+            assert!(front_matter.version.is_some(), "{} has no version", name);
+            assert_eq!(
+                field_names(source),
+                field_names(template(base_filename(&name)).unwrap()),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_prompt_for_a_locale() {
+        let tell = || {
+            PromptData::Tell(TellReplacements {
+                username: "Lucía",
+                context: "",
+                tell: "Hoy corrí una maratón",
+            })
+        };
+        let locale = Locale::parse("es-MX").unwrap();
+
+        let prompt = create_prompt(&locale, tell()).await.unwrap();
+        assert_eq!(prompt.name, "tell.md");
+        assert_eq!(prompt.version.hash, template_hash("es/tell.md").unwrap());
+        assert!(prompt.text.starts_with("Me llamo Lucía."));
+        assert!(prompt
+            .instruction_for(Persona::Therapist)
+            .unwrap()
+            .contains("Always answer in Spanish"));
+
+        // Prompts without a translation fall back to English, still answered in Spanish.
+        let prompt = create_prompt(
+            &locale,
+            PromptData::TellTitle(TellTitleReplacements { tell: "Hola" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(prompt.version.hash, template_hash("tell_title.md").unwrap());
+        assert_eq!(prompt.locale, locale);
+
+        let prompt = create_prompt(&Locale::default(), tell()).await.unwrap();
+        assert_eq!(prompt.version.hash, template_hash("tell.md").unwrap());
+        assert_eq!(
+            prompt.instruction_for(Persona::Therapist).unwrap(),
+            format!(
+                "{}\n\n{}",
+                Persona::Therapist.system_instruction().unwrap(),
+                GUARD_INSTRUCTION
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_variant_prompt() {
+        let tell = || {
+            PromptData::Tell(TellReplacements {
+                username: "Jane",
+                context: "",
+                tell: "Hi",
+            })
+        };
+        let variant = Assignment {
+            experiment: "spanish-first".to_string(),
+            variant: "spanish".to_string(),
+            template: Some("es/tell.md".to_string()),
+        };
+        let prompt = create_prompt_with(&Locale::default(), Some(&variant), tell(), None)
+            .await
+            .unwrap();
+        assert_eq!(prompt.name, "tell.md");
+        assert_eq!(prompt.version.hash, template_hash("es/tell.md").unwrap());
+        assert_eq!(prompt.experiment, Some(variant));
+
+        let control = Assignment {
+            experiment: "spanish-first".to_string(),
+            variant: "control".to_string(),
+            template: None,
+        };
+        let prompt = create_prompt_with(&Locale::default(), Some(&control), tell(), None)
+            .await
+            .unwrap();
+        assert_eq!(prompt.version.hash, template_hash("tell.md").unwrap());
+        assert_eq!(prompt.experiment.unwrap().variant, "control");
+    }
+
+    #[tokio::test]
+    async fn test_localized_overrides() {
+        use crate::prompt_overrides::OverrideSource;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        struct Localized;
+
+        #[async_trait::async_trait]
+        impl OverrideSource for Localized {
+            fn name(&self) -> &str {
+                "localized"
+            }
+
+            async fn get(&self, filename: &str) -> anyhow::Result<Option<String>> {
+                Ok((filename == "fr/tell_title.md").then(|| "Titre : {tell}".to_string()))
+            }
+        }
+
+        let overrides = CachedOverrides::new(Arc::new(Localized), Duration::from_secs(60));
+        let prompt = create_prompt_with(
+            &Locale::parse("fr").unwrap(),
+            None,
+            PromptData::TellTitle(TellTitleReplacements { tell: "Salut" }),
+            Some(&overrides),
+        )
+        .await
+        .unwrap();
+        assert_eq!(prompt.text, "Titre : <user_tell>\nSalut\n</user_tell>");
+
+        assert!(check_override("fr/tell_title.md", "Titre").is_err());
+        assert!(check_override("fr/missing.md", "Salut").is_err());
+    }
+
+    #[test]
+    fn test_every_persona_has_a_system_instruction() {
+        for persona in [
+            Persona::Therapist,
+            Persona::StoicCoach,
+            Persona::GentleFriend,
+            Persona::Concise,
+        ] {
+            let instruction = persona.system_instruction().unwrap();
+            assert!(!instruction.is_empty(), "{} is empty", persona.id());
+            assert!(instruction.contains("Do not ask questions"));
+        }
     }
 
     #[test]
-    fn test_get_templated_prompt_tell() {
+    fn test_persona_serialization() {
+        assert_eq!(
+            serde_json::to_string(&Persona::StoicCoach).unwrap(),
+            "\"stoic_coach\""
+        );
+        let persona: Persona = serde_json::from_str("\"gentle_friend\"").unwrap();
+        assert_eq!(persona, Persona::GentleFriend);
+        assert_eq!(Persona::default(), Persona::Therapist);
+        assert!(serde_json::from_str::<Persona>("\"pirate\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_templated_prompt_tell() {
         let tell_data = TellReplacements {
             username: "testuser",
             context: "User was feeling happy yesterday",
@@ -68,10 +988,10 @@ mod tests {
         };
         let data = PromptData::Tell(tell_data);
 
-        let result = create_prompt(PromptName::Tell, data);
+        let result = create_prompt(&Locale::default(), data).await;
         assert!(result.is_ok());
 
-        let prompt = result.unwrap();
+        let prompt = result.unwrap().text;
         assert!(prompt.contains("testuser"));
         assert!(prompt.contains("User was feeling happy yesterday"));
         assert!(prompt.contains("I had a great day today!"));
@@ -80,8 +1000,8 @@ mod tests {
         assert!(!prompt.contains("{tell}"));
     }
 
-    #[test]
-    fn test_get_templated_prompt_empty_values() {
+    #[tokio::test]
+    async fn test_get_templated_prompt_empty_values() {
         let tell_data = TellReplacements {
             username: "",
             context: "",
@@ -89,17 +1009,17 @@ mod tests {
         };
         let data = PromptData::Tell(tell_data);
 
-        let result = create_prompt(PromptName::Tell, data);
+        let result = create_prompt(&Locale::default(), data).await;
         assert!(result.is_ok());
 
-        let prompt = result.unwrap();
+        let prompt = result.unwrap().text;
         assert!(!prompt.contains("{username}"));
         assert!(!prompt.contains("{context}"));
         assert!(!prompt.contains("{tell}"));
     }
 
-    #[test]
-    fn test_get_templated_prompt_with_special_characters() {
+    #[tokio::test]
+    async fn test_get_templated_prompt_with_special_characters() {
         let tell_data = TellReplacements {
             username: "user@test.com",
             context: "User said: \"I'm feeling great!\"",
@@ -107,12 +1027,28 @@ mod tests {
         };
         let data = PromptData::Tell(tell_data);
 
-        let result = create_prompt(PromptName::Tell, data);
+        let result = create_prompt(&Locale::default(), data).await;
         assert!(result.is_ok());
 
-        let prompt = result.unwrap();
+        let prompt = result.unwrap().text;
         assert!(prompt.contains("user@test.com"));
         assert!(prompt.contains("User said: \"I'm feeling great!\""));
         assert!(prompt.contains("Today I achieved 100% on my test & I'm happy!"));
     }
+
+    #[tokio::test]
+    async fn test_get_templated_prompt_does_not_expand_user_text() {
+        let tell_data = TellReplacements {
+            username: "jane",
+            context: "Felt calm",
+            tell: "My diary says {context} and {username} and {tell}",
+        };
+
+        let prompt = create_prompt(&Locale::default(), PromptData::Tell(tell_data))
+            .await
+            .unwrap()
+            .text;
+        assert!(prompt.contains("My diary says {context} and {username} and {tell}"));
+        assert_eq!(prompt.matches("Felt calm").count(), 1);
+    }
 }
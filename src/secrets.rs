@@ -0,0 +1,512 @@
+use crate::http_client::{HttpClient, HttpRequest, ReqwestClient};
+use async_trait::async_trait;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+use aws_sigv4::sign::v4;
+use aws_smithy_runtime_api::client::identity::Identity;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+const DEFAULT_SECRETS_DIR: &str = "/run/secrets";
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Where API keys and other credentials come from.
+#[async_trait]
+pub trait SecretSource: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Returns `None` when the secret does not exist.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+}
+
+/// Like [`SecretSource::get`], but a missing secret is an error naming the secret and the source.
+pub async fn require_secret(source: &dyn SecretSource, key: &str) -> anyhow::Result<String> {
+    source
+        .get(key)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Secret '{}' is not set in {}", key, source.name()))
+}
+
+/// Reads secrets from environment variables of the same name.
+pub struct EnvSecrets;
+
+#[async_trait]
+impl SecretSource for EnvSecrets {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(std::env::var(key).ok().filter(|v| !v.is_empty()))
+    }
+}
+
+/// Reads each secret from a file named after it, e.g. `/run/secrets/GEMINI_API_KEY`, as mounted
+/// by Docker or Kubernetes.
+pub struct FileSecrets {
+    dir: PathBuf,
+}
+
+impl FileSecrets {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl SecretSource for FileSecrets {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        match tokio::fs::read_to_string(self.dir.join(key)).await {
+            Ok(value) => Ok(Some(value.trim_end().to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AwsSecretService {
+    ParameterStore,
+    SecretsManager,
+}
+
+impl AwsSecretService {
+    fn signing_name(&self) -> &'static str {
+        match self {
+            AwsSecretService::ParameterStore => "ssm",
+            AwsSecretService::SecretsManager => "secretsmanager",
+        }
+    }
+
+    fn target(&self) -> &'static str {
+        match self {
+            AwsSecretService::ParameterStore => "AmazonSSM.GetParameter",
+            AwsSecretService::SecretsManager => "secretsmanager.GetSecretValue",
+        }
+    }
+
+    fn request_body(&self, name: &str) -> serde_json::Value {
+        match self {
+            AwsSecretService::ParameterStore => {
+                serde_json::json!({ "Name": name, "WithDecryption": true })
+            }
+            AwsSecretService::SecretsManager => serde_json::json!({ "SecretId": name }),
+        }
+    }
+
+    fn not_found_error(&self) -> &'static str {
+        match self {
+            AwsSecretService::ParameterStore => "ParameterNotFound",
+            AwsSecretService::SecretsManager => "ResourceNotFoundException",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParameterResponse {
+    parameter: Parameter,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Parameter {
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSecretValueResponse {
+    secret_string: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AwsErrorResponse {
+    #[serde(rename = "__type")]
+    error_type: String,
+}
+
+/// Reads secrets from SSM Parameter Store or Secrets Manager through their JSON APIs, signed
+/// with SigV4. With prefix `/teal/`, the secret `GEMINI_API_KEY` is read from
+/// `/teal/GEMINI_API_KEY`.
+pub struct AwsSecrets {
+    service: AwsSecretService,
+    prefix: String,
+    region: String,
+    endpoint: String,
+    credentials: SharedCredentialsProvider,
+    http: Arc<dyn HttpClient>,
+}
+
+impl AwsSecrets {
+    pub fn new(
+        service: AwsSecretService,
+        prefix: &str,
+        region: &str,
+        endpoint: Option<&str>,
+        credentials: SharedCredentialsProvider,
+        http: Arc<dyn HttpClient>,
+    ) -> Self {
+        Self {
+            service,
+            prefix: prefix.to_string(),
+            region: region.to_string(),
+            endpoint: endpoint.map(str::to_string).unwrap_or_else(|| {
+                format!(
+                    "https://{}.{}.amazonaws.com/",
+                    service.signing_name(),
+                    region
+                )
+            }),
+            credentials,
+            http,
+        }
+    }
+
+    async fn signed_request(&self, body: serde_json::Value) -> anyhow::Result<HttpRequest> {
+        let identity: Identity = self.credentials.provide_credentials().await?.into();
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name(self.service.signing_name())
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()?
+            .into();
+
+        let payload = serde_json::to_vec(&body)?;
+        let headers = [
+            ("content-type", "application/x-amz-json-1.1"),
+            ("x-amz-target", self.service.target()),
+        ];
+        let signable = SignableRequest::new(
+            "POST",
+            self.endpoint.as_str(),
+            headers.into_iter(),
+            SignableBody::Bytes(&payload),
+        )?;
+        let (instructions, _) = sign(signable, &params)?.into_parts();
+
+        let mut request = HttpRequest::post(&self.endpoint, body);
+        for (name, value) in headers.into_iter().chain(instructions.headers()) {
+            request = request.header(name, value);
+        }
+        Ok(request)
+    }
+}
+
+#[async_trait]
+impl SecretSource for AwsSecrets {
+    fn name(&self) -> &str {
+        self.service.signing_name()
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let name = format!("{}{}", self.prefix, key);
+        let request = self
+            .signed_request(self.service.request_body(&name))
+            .await?;
+        let res = self.http.send(&request).await?;
+
+        let not_found = !res.is_success()
+            && res
+                .json::<AwsErrorResponse>()
+                .is_ok_and(|e| e.error_type.ends_with(self.service.not_found_error()));
+        if not_found {
+            return Ok(None);
+        }
+        let res = res.error_for_status()?;
+
+        Ok(match self.service {
+            AwsSecretService::ParameterStore => {
+                Some(res.json::<GetParameterResponse>()?.parameter.value)
+            }
+            AwsSecretService::SecretsManager => res.json::<GetSecretValueResponse>()?.secret_string,
+        })
+    }
+}
+
+struct CachedSecret {
+    value: String,
+    fetched_at: Instant,
+}
+
+/// Caches found secrets for `ttl`, then fetches them again so rotated keys are picked up. If a
+/// refresh fails, the last known value is served and the error logged.
+pub struct CachedSecrets {
+    source: Arc<dyn SecretSource>,
+    ttl: Duration,
+    cache: RwLock<HashMap<String, CachedSecret>>,
+}
+
+impl CachedSecrets {
+    pub fn new(source: Arc<dyn SecretSource>, ttl: Duration) -> Self {
+        Self {
+            source,
+            ttl,
+            cache: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn cached(&self, key: &str, fresh_only: bool) -> Option<String> {
+        let cache = self.cache.read().expect("Secret cache lock poisoned");
+        cache
+            .get(key)
+            .filter(|s| !fresh_only || s.fetched_at.elapsed() < self.ttl)
+            .map(|s| s.value.clone())
+    }
+}
+
+#[async_trait]
+impl SecretSource for CachedSecrets {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        if let Some(value) = self.cached(key, true) {
+            return Ok(Some(value));
+        }
+
+        match self.source.get(key).await {
+            Ok(Some(value)) => {
+                let mut cache = self.cache.write().expect("Secret cache lock poisoned");
+                cache.insert(
+                    key.to_string(),
+                    CachedSecret {
+                        value: value.clone(),
+                        fetched_at: Instant::now(),
+                    },
+                );
+                Ok(Some(value))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => match self.cached(key, false) {
+                Some(stale) => {
+                    eprintln!("Failed to refresh secret '{}': {:?}", key, e);
+                    Ok(Some(stale))
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+static SECRETS: OnceLock<Arc<dyn SecretSource>> = OnceLock::new();
+
+pub fn init_global_secrets(source: Arc<dyn SecretSource>) {
+    SECRETS.set(source).ok();
+}
+
+pub fn use_secrets() -> &'static Arc<dyn SecretSource> {
+    SECRETS.get().expect("Secret source not initialized")
+}
+
+/// Sets up the source selected by `TEAL_SECRETS`: `env` (default), `file`, `ssm` or
+/// `secretsmanager`, cached for `TEAL_SECRETS_TTL_SECS` (default 300).
+pub async fn initialize_secrets() -> anyhow::Result<()> {
+    let source: Arc<dyn SecretSource> = match std::env::var("TEAL_SECRETS")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "" | "env" => Arc::new(EnvSecrets),
+        "file" => Arc::new(FileSecrets::new(
+            std::env::var("TEAL_SECRETS_DIR").unwrap_or_else(|_| DEFAULT_SECRETS_DIR.to_string()),
+        )),
+        "ssm" => Arc::new(aws_secrets_from_env(AwsSecretService::ParameterStore).await?),
+        "secretsmanager" => Arc::new(aws_secrets_from_env(AwsSecretService::SecretsManager).await?),
+        other => return Err(anyhow::anyhow!("Unknown secret source '{}'", other)),
+    };
+    let ttl = match std::env::var("TEAL_SECRETS_TTL_SECS") {
+        Ok(value) => Duration::from_secs(value.parse()?),
+        Err(_) => DEFAULT_TTL,
+    };
+
+    init_global_secrets(Arc::new(CachedSecrets::new(source, ttl)));
+    Ok(())
+}
+
+/// Uses the Lambda's AWS credentials and region. `TEAL_SECRETS_PREFIX` is prepended to every
+/// secret name and `TEAL_SECRETS_ENDPOINT_URL` points at a local stand-in such as LocalStack.
+/// Secret traffic never goes through the recording HTTP client.
+async fn aws_secrets_from_env(service: AwsSecretService) -> anyhow::Result<AwsSecrets> {
+    let config = aws_config::load_from_env().await;
+    let region = config
+        .region()
+        .ok_or_else(|| anyhow::anyhow!("AWS region must be set to read secrets from AWS"))?;
+    let credentials = config
+        .credentials_provider()
+        .ok_or_else(|| anyhow::anyhow!("AWS credentials are required to read secrets from AWS"))?;
+
+    Ok(AwsSecrets::new(
+        service,
+        &std::env::var("TEAL_SECRETS_PREFIX").unwrap_or_default(),
+        region.as_ref(),
+        std::env::var("TEAL_SECRETS_ENDPOINT_URL").ok().as_deref(),
+        credentials,
+        Arc::new(ReqwestClient::default()),
+    ))
+}
+
+/// A fixed set of secrets for tests.
+#[cfg(test)]
+pub struct StaticSecrets(pub HashMap<String, String>);
+
+#[cfg(test)]
+impl StaticSecrets {
+    pub fn with(key: &str, value: &str) -> Arc<Self> {
+        Arc::new(Self(HashMap::from([(key.to_string(), value.to_string())])))
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl SecretSource for StaticSecrets {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    async fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.0.get(key).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::HttpResponse;
+    use aws_credential_types::Credentials;
+    use bytes::Bytes;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A local stand-in for SSM Parameter Store. It only answers signed `GetParameter` calls.
+    struct LocalParameterStore {
+        parameters: HashMap<String, String>,
+    }
+
+    #[async_trait]
+    impl HttpClient for LocalParameterStore {
+        async fn send(&self, request: &HttpRequest) -> anyhow::Result<HttpResponse> {
+            let header = |name: &str| {
+                request
+                    .headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.as_str())
+            };
+            assert_eq!(header("x-amz-target"), Some("AmazonSSM.GetParameter"));
+            assert!(header("authorization").is_some_and(|v| v.starts_with("AWS4-HMAC-SHA256")));
+
+            let name = request.body["Name"].as_str().unwrap_or_default();
+            let (status, body) = match self.parameters.get(name) {
+                Some(value) => (
+                    200,
+                    serde_json::json!({
+                        "Parameter": { "Name": name, "Type": "SecureString", "Value": value }
+                    }),
+                ),
+                None => (
+                    400,
+                    serde_json::json!({ "__type": "ParameterNotFound", "message": "" }),
+                ),
+            };
+            Ok(HttpResponse {
+                status,
+                body: Bytes::from(body.to_string()),
+            })
+        }
+    }
+
+    fn local_ssm() -> AwsSecrets {
+        let credentials = Credentials::new("AKIDTEST", "secret", None, None, "test");
+        AwsSecrets::new(
+            AwsSecretService::ParameterStore,
+            "/teal/",
+            "eu-west-1",
+            Some("http://localhost:4566/"),
+            SharedCredentialsProvider::new(credentials),
+            Arc::new(LocalParameterStore {
+                parameters: HashMap::from([(
+                    "/teal/GEMINI_API_KEY".to_string(),
+                    "ssm-key".to_string(),
+                )]),
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_ssm_secrets_from_local_stand_in() {
+        let ssm = local_ssm();
+        assert_eq!(
+            ssm.get("GEMINI_API_KEY").await.unwrap(),
+            Some("ssm-key".to_string())
+        );
+        assert_eq!(ssm.get("OPENAI_API_KEY").await.unwrap(), None);
+
+        let err = require_secret(&ssm, "OPENAI_API_KEY").await.err().unwrap();
+        assert_eq!(err.to_string(), "Secret 'OPENAI_API_KEY' is not set in ssm");
+    }
+
+    #[tokio::test]
+    async fn test_file_secrets() {
+        let dir = std::env::temp_dir().join(format!("teal-secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("GEMINI_API_KEY"), "file-key\n").unwrap();
+
+        let secrets = FileSecrets::new(&dir);
+        assert_eq!(
+            secrets.get("GEMINI_API_KEY").await.unwrap(),
+            Some("file-key".to_string())
+        );
+        assert_eq!(secrets.get("OPENAI_API_KEY").await.unwrap(), None);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Returns a new value on every call, or fails once `fail` is set.
+    struct RotatingSource {
+        calls: AtomicU32,
+        fail: std::sync::atomic::AtomicBool,
+    }
+
+    #[async_trait]
+    impl SecretSource for RotatingSource {
+        fn name(&self) -> &str {
+            "rotating"
+        }
+
+        async fn get(&self, _key: &str) -> anyhow::Result<Option<String>> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("source unavailable"));
+            }
+            let call = self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(format!("key-{}", call)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_secrets_refresh() {
+        let source = Arc::new(RotatingSource {
+            calls: AtomicU32::new(0),
+            fail: Default::default(),
+        });
+
+        let cached = CachedSecrets::new(source.clone(), Duration::from_secs(60));
+        assert_eq!(cached.get("KEY").await.unwrap(), Some("key-0".to_string()));
+        assert_eq!(cached.get("KEY").await.unwrap(), Some("key-0".to_string()));
+
+        // With a zero TTL every read refreshes, and a failed refresh serves the last value.
+        let cached = CachedSecrets::new(source.clone(), Duration::ZERO);
+        assert_eq!(cached.get("KEY").await.unwrap(), Some("key-1".to_string()));
+        assert_eq!(cached.get("KEY").await.unwrap(), Some("key-2".to_string()));
+        source.fail.store(true, Ordering::SeqCst);
+        assert_eq!(cached.get("KEY").await.unwrap(), Some("key-2".to_string()));
+        assert!(cached.get("OTHER").await.is_err());
+    }
+}
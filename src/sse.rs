@@ -0,0 +1,78 @@
+use bytes::Bytes;
+
+/// Incrementally decodes a Server-Sent Events byte stream into the `data` payload of each event.
+/// Bytes are buffered until a full line arrives, so multi-byte characters split across network
+/// chunks are handled correctly.
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseDecoder {
+    /// Feeds a chunk of bytes and returns the payloads of every event completed by it.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data
+                    .push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+        }
+        events
+    }
+}
+
+/// Encodes a single named event. The payload is JSON, so it never contains raw newlines.
+pub fn encode_event(event: &str, data: &serde_json::Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_splits_events() {
+        let mut decoder = SseDecoder::default();
+        let events = decoder.push(b"data: {\"a\":1}\r\n\r\ndata: {\"b\":2}\n\n");
+        assert_eq!(events, vec!["{\"a\":1}", "{\"b\":2}"]);
+    }
+
+    #[test]
+    fn test_decoder_buffers_partial_events() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"a\"").is_empty());
+        assert!(decoder.push(b":1}\n").is_empty());
+        assert_eq!(decoder.push(b"\n"), vec!["{\"a\":1}"]);
+    }
+
+    #[test]
+    fn test_decoder_handles_split_utf8() {
+        let mut decoder = SseDecoder::default();
+        let bytes = "data: café\n\n".as_bytes();
+        let (first, second) = bytes.split_at(10);
+        assert!(decoder.push(first).is_empty());
+        assert_eq!(decoder.push(second), vec!["café"]);
+    }
+
+    #[test]
+    fn test_encode_event() {
+        let bytes = encode_event("answer", &serde_json::json!({ "text": "hi\nthere" }));
+        assert_eq!(
+            bytes,
+            Bytes::from("event: answer\ndata: {\"text\":\"hi\\nthere\"}\n\n")
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use crate::http_handler::{function_handler, parse_tell_request, ResponseBody};
+use crate::llm::AnswerEvent;
+use crate::sse::encode_event;
+use crate::tell::{tell_streaming, TellInput};
+use crate::usage::is_over_quota;
+use bytes::Bytes;
+use futures::StreamExt;
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, StreamBody};
+use lambda_http::{http, Error, Request, Response};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+pub(crate) type StreamingBody = UnsyncBoxBody<Bytes, Error>;
+
+/// Entry point when the function runs in Lambda response-streaming mode. `POST /tell` requests
+/// that accept `text/event-stream` get the answer as Server-Sent Events; every other request is
+/// served by the regular [`function_handler`], which also reports validation and quota errors.
+pub(crate) async fn streaming_handler(event: Request) -> Result<Response<StreamingBody>, Error> {
+    if wants_event_stream(&event) {
+        if let Ok((username, input)) = parse_tell_request(&event).await {
+            if !is_over_quota(&username).await? {
+                return post_tell_stream(username, input);
+            }
+        }
+    }
+
+    let res = function_handler(event).await?;
+    Ok(res.map(|body| body.map_err(Into::into).boxed_unsync()))
+}
+
+fn wants_event_stream(event: &Request) -> bool {
+    event.method() == http::Method::POST
+        && event.uri().path() == "/tell"
+        && event
+            .headers()
+            .get(http::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/event-stream"))
+}
+
+/// Streams `answer` events while the model generates, then a final `done` or `error` event once
+/// the tell has been persisted. A `restart` event tells the client to discard the answer received
+/// so far, e.g. when a truncated answer is regenerated.
+fn post_tell_stream(username: String, input: TellInput) -> Result<Response<StreamingBody>, Error> {
+    let (tx, rx) = mpsc::unbounded_channel::<Bytes>();
+
+    tokio::spawn(async move {
+        let answer_tx = tx.clone();
+        let result = tell_streaming(&username, input, None, move |event| {
+            let frame = match event {
+                AnswerEvent::Delta(delta) => {
+                    encode_event("answer", &serde_json::json!({ "text": delta }))
+                }
+                AnswerEvent::Restart => encode_event("restart", &serde_json::json!({})),
+            };
+            answer_tx.send(frame).ok();
+        })
+        .await;
+
+        let (event, data) = match result {
+            Ok(_) => (
+                "done",
+                ResponseBody {
+                    success: true,
+                    error_message: None,
+                },
+            ),
+            Err(e) => {
+                eprintln!("Streaming tell failed: {:?}", e);
+                (
+                    "error",
+                    ResponseBody {
+                        success: false,
+                        error_message: Some(
+                            "Oops! An error occurred when telling your story.".to_string(),
+                        ),
+                    },
+                )
+            }
+        };
+        if let Ok(data) = serde_json::to_value(&data) {
+            tx.send(encode_event(event, &data)).ok();
+        }
+    });
+
+    let frames = UnboundedReceiverStream::new(rx).map(|b| Ok(http_body::Frame::data(b)));
+    Ok(Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", "text/event-stream")
+        .header("cache-control", "no-cache")
+        .body(StreamBody::new(frames).boxed_unsync())
+        .map_err(Box::new)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_http::{http::Method, Body};
+
+    fn create_test_request(method: Method, path: &str, accept: &str, body: Body) -> Request {
+        let mut req = Request::new(body);
+        *req.method_mut() = method;
+        *req.uri_mut() = path.parse().unwrap();
+        req.headers_mut()
+            .insert(http::header::ACCEPT, accept.parse().unwrap());
+        req
+    }
+
+    #[test]
+    fn test_wants_event_stream() {
+        let req = create_test_request(Method::POST, "/tell", "text/event-stream", Body::Empty);
+        assert!(wants_event_stream(&req));
+
+        let req = create_test_request(Method::POST, "/tell", "application/json", Body::Empty);
+        assert!(!wants_event_stream(&req));
+
+        let req = create_test_request(Method::GET, "/tells", "text/event-stream", Body::Empty);
+        assert!(!wants_event_stream(&req));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_stream_request_falls_back_to_json_error() {
+        let request = create_test_request(
+            Method::POST,
+            "/tell?username=testuser",
+            "text/event-stream",
+            Body::Empty,
+        );
+
+        let response = streaming_handler(request).await.unwrap();
+        assert_eq!(response.status(), 422);
+
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let body: ResponseBody = serde_json::from_slice(&bytes).unwrap();
+        assert!(!body.success);
+        assert_eq!(
+            body.error_message,
+            Some("Request body required".to_string())
+        );
+    }
+}
@@ -1,26 +1,97 @@
+use crate::attachments::{store_attachments, Attachment, AttachmentRef};
+use crate::context_budget::{
+    context_tokens, estimate_tokens, TokenBudget, MESSAGE_OVERHEAD_TOKENS,
+};
 use crate::dynamo::{use_db, TELLS_TABLE_NAME};
-use crate::gemini::{ask_gemini, GeminiTellResponse};
-use crate::prompts;
+use crate::experiments::{assign, log_outcome, use_experiments, Assignment, ExperimentOutcome};
+use crate::gemini::GeminiTellResponse;
+use crate::injection::flag_injection;
+use crate::insights::title_tell;
+use crate::llm::{
+    ask_llm, ask_llm_streaming, use_llm, AnswerEvent, LlmFailure, LlmProvider, Message, TellReply,
+};
+use crate::locale::Locale;
+use crate::memory::{use_index, EmbeddingItem};
+use crate::mood::normalize_mood;
+use crate::object_store::use_store;
+use crate::prompts::{self, Persona, Prompt, PromptName};
+use crate::tools::{TellTools, ToolCallRecord, ToolExecutor};
+use crate::traces::{save_traces, LlmTrace};
+use crate::transcribe::{use_transcriber, Transcriber, Transcript};
+use crate::usage::{cost_usd, record_trace_usage, TokenUsage};
+use crate::users::get_user_by_name;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use serde_json::to_value;
 use std::fmt;
 use uuid::Uuid;
 
+/// Number of past tell/answer pairs replayed to the model as conversation turns.
+const HISTORY_EXCHANGES: usize = 5;
+/// Number of older summaries included in the context besides the latest one.
+const SUMMARY_HISTORY: usize = 10;
+/// Number of semantically similar past tells added to the history besides the recent ones.
+const RELEVANT_TELLS: usize = 3;
+
+/// A past tell and Teal's answer to it.
+pub struct Exchange {
+    pub tell: String,
+    pub answer: String,
+}
+
+/// What the user told Teal: the text, any attached images and an optional voice clip.
+#[derive(Default)]
+pub struct TellInput {
+    pub text: String,
+    pub images: Vec<Attachment>,
+    pub audio: Option<Attachment>,
+    /// What was said in `audio`, once transcribed.
+    pub transcript: Option<Transcript>,
+}
+
+impl TellInput {
+    /// Transcribes the voice clip, if any, and appends the transcript to the typed text so the
+    /// rest of the pipeline handles a voice tell like a written one.
+    pub async fn transcribe(&mut self, transcriber: &dyn Transcriber) -> anyhow::Result<()> {
+        let Some(audio) = &self.audio else {
+            return Ok(());
+        };
+        let transcript = transcriber.transcribe(audio).await?;
+        self.text = match self.text.trim() {
+            "" => transcript.text.clone(),
+            text => format!("{}\n\n{}", text, transcript.text),
+        };
+        self.transcript = Some(transcript);
+        Ok(())
+    }
+}
+
 pub struct Context {
     pub mood: String,
     pub summary: String,
     pub summary_history: Vec<String>,
-    pub tell_history: Vec<String>,
+    /// Past exchanges, oldest first. These are sent as user/model turns rather than through
+    /// `Display`, so the model can tell the user's words apart from its own.
+    pub history: Vec<Exchange>,
+}
+
+impl Context {
+    pub fn messages(&self) -> Vec<Message> {
+        self.history
+            .iter()
+            .flat_map(|e| [Message::user(&e.tell), Message::model(&e.answer)])
+            .collect()
+    }
 }
 
 impl fmt::Display for Context {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "My current mood: {}. My current situation: {}. My past situations: {}. My past tells to you: {}.",
-        self.mood,
-        self.summary,
-        self.summary_history.join(", "),
-        self.tell_history.join(", "),
+        write!(
+            f,
+            "My current mood: {}. My current situation: {}. My past situations: {}.",
+            self.mood,
+            self.summary,
+            self.summary_history.join(", "),
         )
     }
 }
@@ -32,38 +103,364 @@ pub struct TellItem {
     pub tell: String,
     pub answer: String,
     pub user_state: String,
+    /// Lowercase English whatever language the tell was in; see [`normalize_mood`].
     pub mood: String,
     pub created_at: chrono::DateTime<Utc>,
     pub summary: Option<String>,
+    /// The provider that answered, e.g. `gemini` or `openai` when a fallback took over.
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    /// Id of the persona that answered, e.g. `therapist`.
+    pub persona: Option<String>,
+    pub usage: Option<TokenUsage>,
+    pub cost_usd: Option<f64>,
+    /// Tools the model called before answering, with their results.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCallRecord>,
+    /// Images attached to the tell, in the object store.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<AttachmentRef>,
+    /// The voice clip the tell was recorded as, in the object store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AttachmentRef>,
+    /// The transcript of `audio`. It is also part of `tell`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
+    /// A few words naming the tell; best-effort, so it may be missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Version of the `tell.md` prompt the answer came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_version: Option<u32>,
+    /// SHA-256 of the `tell.md` prompt file, telling apart edits made without a version bump.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_hash: Option<String>,
+    /// The prompt experiment the user was enrolled in, and the variant that answered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    /// Prompt injection patterns found in `tell`, e.g. `ignore_instructions`; see
+    /// [`detect_injection`](crate::injection::detect_injection). Flagged tells are still answered.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub injection_flags: Vec<String>,
+}
+
+impl TellItem {
+    /// Adds the usage of an LLM call made for this tell, and its cost at `model`'s price, so the
+    /// monthly usage and quota count every call rather than only the answer.
+    pub fn add_usage(&mut self, model: &str, usage: Option<TokenUsage>) {
+        self.usage = TokenUsage::merge(self.usage, usage);
+        if let Some(cost) = usage.as_ref().and_then(|usage| cost_usd(model, usage)) {
+            self.cost_usd = Some(self.cost_usd.unwrap_or_default() + cost);
+        }
+    }
 }
 
 /// Tells Teal what the user is feeling, and Teal will return with a very benevolent response–
-/// like the color teal! Optionally takes `context` for now, but this shouldn't be needed in most
-/// cases.
+/// like the color teal! Attached images are shown to the model and stored with the tell, and a
+/// voice clip is transcribed first. Optionally takes `context`; when omitted it is built from the
+/// user's stored tells.
 pub async fn tell(
     username: &str,
-    user_message: &str,
+    input: TellInput,
+    context: Option<Context>,
+) -> anyhow::Result<String> {
+    tell_with(username, input, context, None).await
+}
+
+/// Streaming counterpart of [`tell`]. `on_answer` receives the answer text as it is generated;
+/// the tell is persisted once the model has finished.
+pub async fn tell_streaming(
+    username: &str,
+    input: TellInput,
+    context: Option<Context>,
+    mut on_answer: impl FnMut(AnswerEvent<'_>) + Send,
+) -> anyhow::Result<String> {
+    tell_with(username, input, context, Some(&mut on_answer)).await
+}
+
+/// The pipeline behind [`tell`] and [`tell_streaming`], which streams the answer when given
+/// `on_answer`.
+async fn tell_with(
+    username: &str,
+    mut input: TellInput,
     context: Option<Context>,
+    on_answer: Option<&mut (dyn FnMut(AnswerEvent<'_>) + Send)>,
 ) -> anyhow::Result<String> {
-    let context_string = context.unwrap_or_else(get_context).to_string();
+    input.transcribe(use_transcriber().as_ref()).await?;
+    let input = &input;
+    let preferences = user_preferences(username).await;
+    let assignment = assign(use_experiments(), PromptName::Tell, username);
+    let embedding = embed_tell(&input.text).await;
+    let context = resolve_context(username, context, embedding.as_deref()).await?;
+    let reply = answer_tell(
+        use_llm().as_ref(),
+        &preferences,
+        assignment.as_ref(),
+        username,
+        input,
+        context.as_ref(),
+        on_answer,
+    )
+    .await;
+    let reply = log_failure(username, input, assignment.as_ref(), reply).await?;
+
+    save_tell(username, input, &preferences, &reply, embedding).await?;
+    Ok(reply.response.answer)
+}
+
+/// Renders the tell prompt and asks `provider` for a reply, replaying `context` as conversation
+/// history. The answer is streamed to `on_answer` if given.
+async fn answer_tell(
+    provider: &dyn LlmProvider,
+    preferences: &Preferences,
+    assignment: Option<&Assignment>,
+    username: &str,
+    input: &TellInput,
+    context: Option<&Context>,
+    on_answer: Option<&mut (dyn FnMut(AnswerEvent<'_>) + Send)>,
+) -> Result<TellReply, LlmFailure> {
+    let prompt = tell_prompt(username, &preferences.locale, assignment, input, context).await?;
+    let instruction = prompt.instruction_for(preferences.persona)?;
+    let history = context.map(|c| c.messages()).unwrap_or_default();
+    let tools = tell_tools(username);
+    let tools = tools.as_ref().map(|t| t as &dyn ToolExecutor);
+    match on_answer {
+        Some(on_answer) => {
+            ask_llm_streaming(provider, tools, &instruction, &history, &prompt, on_answer).await
+        }
+        None => ask_llm(provider, tools, &instruction, &history, &prompt).await,
+    }
+}
+
+/// The lookups the model may make while answering, unless disabled with `LLM_TOOLS=off`.
+fn tell_tools(username: &str) -> Option<TellTools> {
+    match std::env::var("LLM_TOOLS").as_deref() {
+        Ok("off") => None,
+        _ => Some(TellTools::new(username)),
+    }
+}
+
+/// Passes a reply on, or records why the tell failed. A failed tell is never stored, so the
+/// traces of its calls are kept without a tell and their tokens go to the usage ledger, still
+/// counting towards the quota. For a user enrolled in an experiment an outcome is logged if the
+/// reply could not be parsed.
+async fn log_failure(
+    username: &str,
+    input: &TellInput,
+    assignment: Option<&Assignment>,
+    reply: Result<TellReply, LlmFailure>,
+) -> anyhow::Result<TellReply> {
+    let failure = match reply {
+        Ok(reply) => return Ok(reply),
+        Err(failure) => failure,
+    };
+    if let Some(assignment) = assignment {
+        if let Some(outcome) = ExperimentOutcome::failed(assignment, username, &failure.error) {
+            log_outcome(outcome).await;
+        }
+    }
+    let mut traces: Vec<_> = input
+        .transcript
+        .iter()
+        .filter_map(|t| t.trace.clone())
+        .collect();
+    traces.extend(failure.traces);
+    record_trace_usage(username, &traces).await;
+    save_traces(username, None, traces).await;
+    Err(failure.error)
+}
+
+/// What the user chose in their profile: the voice and the language Teal answers in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preferences {
+    pub persona: Persona,
+    pub locale: Locale,
+}
+
+/// The preferences in the user's profile. Users without a profile or a choice, or whose profile
+/// can't be read, get the default persona in English.
+pub async fn user_preferences(username: &str) -> Preferences {
+    match get_user_by_name(username).await {
+        Ok(user) => user
+            .map(|u| Preferences {
+                persona: u.persona.unwrap_or_default(),
+                locale: u.locale.unwrap_or_default(),
+            })
+            .unwrap_or_default(),
+        Err(e) => {
+            eprintln!("Failed to read user profile: {:?}", e);
+            Preferences::default()
+        }
+    }
+}
+
+/// Embeds the new tell. Semantic memory is best-effort, so failures are logged and the tell
+/// proceeds with recent history only. The call is unmetered; see [`LlmProvider::embed`].
+async fn embed_tell(user_message: &str) -> Option<Vec<f32>> {
+    match use_llm().embed(user_message).await {
+        Ok(vector) => Some(vector),
+        Err(e) => {
+            eprintln!("Failed to embed tell: {:?}", e);
+            None
+        }
+    }
+}
+
+async fn resolve_context(
+    username: &str,
+    context: Option<Context>,
+    embedding: Option<&[f32]>,
+) -> anyhow::Result<Option<Context>> {
+    if context.is_some() {
+        return Ok(context);
+    }
+
+    let tells = get_user_tells(username).await?;
+    let relevant = match embedding {
+        Some(vector) => match use_index().search(username, vector, RELEVANT_TELLS).await {
+            Ok(scored) => scored.into_iter().map(|s| s.tid).collect(),
+            Err(e) => {
+                eprintln!("Failed to search past tells: {:?}", e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+    Ok(build_context(&tells, &relevant, context_tokens()))
+}
+
+/// The final user turn: the rendered tell prompt with the attached images.
+async fn tell_prompt(
+    username: &str,
+    locale: &Locale,
+    assignment: Option<&Assignment>,
+    input: &TellInput,
+    context: Option<&Context>,
+) -> anyhow::Result<Prompt> {
+    let prompt = build_tell_prompt(username, locale, assignment, &input.text, context).await?;
+    Ok(prompt.with_media(input.images.iter().map(Attachment::media).collect()))
+}
+
+async fn build_tell_prompt(
+    username: &str,
+    locale: &Locale,
+    assignment: Option<&Assignment>,
+    user_message: &str,
+    context: Option<&Context>,
+) -> anyhow::Result<Prompt> {
+    let context_string = context.map(|c| c.to_string()).unwrap_or_default();
     let prompt_data = prompts::PromptData::Tell(prompts::TellReplacements {
         username,
         context: &context_string,
         tell: user_message,
     });
 
-    let prompt = prompts::create_prompt(prompts::PromptName::Tell, prompt_data)?;
-    let response = ask_gemini(&prompt).await?;
+    prompts::create_variant_prompt(locale, assignment, prompt_data).await
+}
+
+/// Whether stored tells get a title, which takes an extra LLM call per tell. Enabled with
+/// `TEAL_TELL_TITLES=true`.
+fn titles_enabled() -> bool {
+    std::env::var("TEAL_TELL_TITLES").is_ok_and(|v| v == "true")
+}
+
+/// Titles the tell and adds the usage of the call, even a failed one, to it. A failed title is
+/// logged and the tell is stored without one. Returns the traces of the call.
+async fn add_title(tell_record: &mut TellItem, locale: &Locale, text: &str) -> Vec<LlmTrace> {
+    match title_tell(use_llm().as_ref(), locale, text).await {
+        Ok((title, trace)) => {
+            tell_record.title = Some(title.title);
+            tell_record.add_usage(&trace.model, trace.usage);
+            vec![trace]
+        }
+        Err(failure) => {
+            eprintln!("Failed to title tell: {:?}", failure.error);
+            for trace in &failure.traces {
+                tell_record.add_usage(&trace.model, trace.usage);
+            }
+            failure.traces
+        }
+    }
+}
+
+async fn save_tell(
+    username: &str,
+    input: &TellInput,
+    preferences: &Preferences,
+    reply: &TellReply,
+    embedding: Option<Vec<f32>>,
+) -> anyhow::Result<()> {
+    let mut tell_record = build_tell_record(username, &input.text, &reply.response);
+    tell_record.attachments =
+        store_attachments(use_store().as_ref(), &tell_record.tid, &input.images).await?;
+    tell_record.audio = store_attachments(
+        use_store().as_ref(),
+        &tell_record.tid,
+        input.audio.as_slice(),
+    )
+    .await?
+    .pop();
+    tell_record.transcript = input.transcript.as_ref().map(|t| t.text.clone());
+    tell_record.injection_flags = flag_injection(&input.text);
+    tell_record.persona = Some(preferences.persona.id().to_string());
+    tell_record.provider = Some(reply.provider.clone());
+    tell_record.model = Some(reply.model.clone());
+    if let Some(trace) = input.transcript.as_ref().and_then(|t| t.trace.as_ref()) {
+        tell_record.add_usage(&trace.model, trace.usage);
+    }
+    tell_record.add_usage(&reply.model, reply.usage);
+    tell_record.tool_calls = reply.tool_calls.clone();
+    if let Some(version) = &reply.prompt_version {
+        tell_record.prompt_version = Some(version.version);
+        tell_record.prompt_hash = Some(version.hash.clone());
+    }
+    if let Some(assignment) = &reply.experiment {
+        tell_record.experiment = Some(assignment.experiment.clone());
+        tell_record.variant = Some(assignment.variant.clone());
+    }
+
+    let title_traces = if titles_enabled() {
+        add_title(&mut tell_record, &preferences.locale, &input.text).await
+    } else {
+        Vec::new()
+    };
 
-    let tell_record = build_tell_record(username, user_message, &response);
+    let tid = tell_record.tid.clone();
     let db = use_db();
     db.put(TELLS_TABLE_NAME, to_value(tell_record)?).await?;
 
-    Ok(response.answer)
+    let mut traces: Vec<_> = input
+        .transcript
+        .iter()
+        .filter_map(|t| t.trace.clone())
+        .collect();
+    traces.extend(reply.traces.iter().cloned());
+    traces.extend(title_traces);
+    save_traces(username, Some(&tid), traces).await;
+    if let Some(assignment) = &reply.experiment {
+        log_outcome(ExperimentOutcome::answered(
+            assignment, username, &tid, reply,
+        ))
+        .await;
+    }
+
+    if let Some(vector) = embedding {
+        let item = EmbeddingItem {
+            tid,
+            username: username.to_string(),
+            vector,
+        };
+        if let Err(e) = use_index().upsert(item).await {
+            eprintln!("Failed to store tell embedding: {:?}", e);
+        }
+    }
+    Ok(())
 }
 
-/// Creates a TellItem from user input and AI response data. This is a pure function that can be
-/// easily unit tested.
+/// Creates a TellItem from user input and AI response data, with the mood normalized. This is a
+/// pure function that can be easily unit tested.
 pub fn build_tell_record(
     username: &str,
     user_message: &str,
@@ -75,9 +472,24 @@ pub fn build_tell_record(
         tell: user_message.to_string(),
         answer: ai_response.answer.clone(),
         user_state: ai_response.user_state.clone(),
-        mood: ai_response.mood.clone(),
+        mood: normalize_mood(&ai_response.mood),
         created_at: Utc::now(),
         summary: Some(ai_response.summary.clone()),
+        provider: None,
+        model: None,
+        persona: None,
+        usage: None,
+        cost_usd: None,
+        tool_calls: Vec::new(),
+        attachments: Vec::new(),
+        audio: None,
+        transcript: None,
+        title: None,
+        prompt_version: None,
+        prompt_hash: None,
+        experiment: None,
+        variant: None,
+        injection_flags: Vec::new(),
     }
 }
 
@@ -88,50 +500,387 @@ pub async fn get_user_tells(username: &str) -> anyhow::Result<Vec<TellItem>> {
     let mut tells: Vec<TellItem> = db.scan(TELLS_TABLE_NAME, "username", username).await?;
 
     // Sort by creation date, newest first (business logic)
-    tells.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+    tells.sort_by_key(|t| std::cmp::Reverse(t.created_at));
 
     Ok(tells)
 }
 
-/// Generate a Context object to be passed into tell() from the database.
-// TODO: Adjust to new tell structure and optimize storage.
-fn get_context() -> Context {
-    Context {
-        mood: "satisfied".to_string(),
-        summary:
-            "User shares job search frustrations but has new potential opportunity through family."
-                .to_string(),
-        summary_history: vec![
-            "Hopeful, determined, but anxious about not messing up the opportunity.".to_string(),
-            "User was feeling overwhelmed about work-life balance".to_string(),
-            "User expressed excitement about a new project but worried about time management"
-                .to_string(),
-            "User felt confident after completing a challenging task".to_string(),
-        ],
-        tell_history: vec![
-            "Another day of no job. But my uncle just sent me a text that his company may be hiring new engineers, and it may be a senior role. This time, I have to be strong. There is no way I can fumble this up.".to_string(),
-            "I think while growth come with doubt, I'm feeling happy and there will be some potential interviews I'll be going this week.".to_string(),
-            "You've successfully handled similar challenges before. A job will come to you if you truly believe in your own work.".to_string(),
-            "It's getting tough. I'm confident and I know I can deliver, but why am I not getting jobs? It's becoming tough, to be fair.".to_string(),
-        ],
-    }
+/// Builds a Context from a user's tells, newest first, that takes at most `max_tokens` as
+/// estimated by [`estimate_tokens`]. Sections are filled by priority: the current mood, then the
+/// latest summaries, then past exchanges, those whose `tid` is in `relevant` before the most
+/// recent ones. What doesn't fit whole is truncated, and what comes after is dropped. The
+/// history is replayed in chronological order. Returns `None` for a user without tells, i.e. a
+/// first conversation.
+pub fn build_context(
+    tells: &[TellItem],
+    relevant: &[String],
+    max_tokens: usize,
+) -> Option<Context> {
+    let latest = tells.first()?;
+    let mut context = Context {
+        mood: String::new(),
+        summary: String::new(),
+        summary_history: Vec::new(),
+        history: Vec::new(),
+    };
+    let mut budget = TokenBudget::new(max_tokens);
+    budget.reserve(estimate_tokens(&context.to_string()));
+
+    context.mood = budget.take_whole(&latest.mood, 0).unwrap_or_default();
+    context.summary = budget
+        .take(latest.summary.as_deref().unwrap_or_default(), 0)
+        .unwrap_or_default();
+    // Older summaries are joined with a comma, counted as a token each.
+    context.summary_history = tells
+        .iter()
+        .skip(1)
+        .filter_map(|t| t.summary.as_deref())
+        .take(SUMMARY_HISTORY)
+        .map_while(|summary| budget.take(summary, 1))
+        .collect();
+
+    let recent = 0..tells.len().min(HISTORY_EXCHANGES);
+    let mut ranked: Vec<usize> = relevant
+        .iter()
+        .filter_map(|tid| tells.iter().position(|t| &t.tid == tid))
+        .collect();
+    ranked.extend(recent.filter(|i| !relevant.contains(&tells[*i].tid)));
+    let mut kept: Vec<(usize, Exchange)> = ranked
+        .into_iter()
+        .map_while(|i| {
+            let (tell, answer) =
+                budget.take_pair(&tells[i].tell, &tells[i].answer, MESSAGE_OVERHEAD_TOKENS)?;
+            Some((i, Exchange { tell, answer }))
+        })
+        .collect();
+    kept.sort_by_key(|(i, _)| std::cmp::Reverse(*i));
+    context.history = kept.into_iter().map(|(_, exchange)| exchange).collect();
+    Some(context)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn tell_item(
+        tell: &str,
+        answer: &str,
+        summary: &str,
+        mood: &str,
+        minutes_ago: i64,
+    ) -> TellItem {
+        let mut item = build_tell_record(
+            "testuser",
+            tell,
+            &GeminiTellResponse {
+                answer: answer.to_string(),
+                summary: summary.to_string(),
+                user_state: "state".to_string(),
+                mood: mood.to_string(),
+            },
+        );
+        item.created_at = Utc::now() - chrono::Duration::minutes(minutes_ago);
+        item
+    }
+
+    #[test]
+    fn test_build_context() {
+        let tells = vec![
+            tell_item(
+                "Interview went well",
+                "Great work!",
+                "User aced interview",
+                "hopeful",
+                1,
+            ),
+            tell_item(
+                "I'm job hunting",
+                "Keep going.",
+                "User is job hunting",
+                "anxious",
+                10,
+            ),
+        ];
+
+        let context = build_context(&tells, &[], usize::MAX).unwrap();
+        assert_eq!(context.mood, "hopeful");
+        assert_eq!(context.summary, "User aced interview");
+        assert_eq!(context.summary_history, vec!["User is job hunting"]);
+
+        // History is replayed oldest first
+        assert_eq!(context.history.len(), 2);
+        assert_eq!(context.history[0].tell, "I'm job hunting");
+        assert_eq!(context.history[1].answer, "Great work!");
+    }
+
+    #[test]
+    fn test_build_context_limits_history() {
+        let tells: Vec<TellItem> = (0..20)
+            .map(|i| tell_item(&format!("tell {}", i), "answer", "summary", "calm", i))
+            .collect();
+
+        let context = build_context(&tells, &[], usize::MAX).unwrap();
+        assert_eq!(context.history.len(), HISTORY_EXCHANGES);
+        assert_eq!(context.history.last().unwrap().tell, "tell 0");
+        assert_eq!(context.summary_history.len(), SUMMARY_HISTORY);
+    }
+
+    #[test]
+    fn test_build_context_includes_relevant_tells() {
+        let tells: Vec<TellItem> = (0..20)
+            .map(|i| tell_item(&format!("tell {}", i), "answer", "summary", "calm", i))
+            .collect();
+        let relevant = vec![tells[15].tid.clone(), tells[2].tid.clone()];
+
+        let context = build_context(&tells, &relevant, usize::MAX).unwrap();
+        let history: Vec<&str> = context.history.iter().map(|e| e.tell.as_str()).collect();
+        assert_eq!(
+            history,
+            vec!["tell 15", "tell 4", "tell 3", "tell 2", "tell 1", "tell 0"]
+        );
+    }
+
+    fn estimated_tokens(context: &Context) -> usize {
+        estimate_tokens(&context.to_string())
+            + context
+                .messages()
+                .iter()
+                .map(|m| estimate_tokens(&m.text) + MESSAGE_OVERHEAD_TOKENS)
+                .sum::<usize>()
+    }
+
+    #[test]
+    fn test_build_context_fits_the_budget() {
+        let long_tell = |i| format!("tell {} {}", i, "word ".repeat(40));
+        let tells: Vec<TellItem> = (0..20)
+            .map(|i| tell_item(&long_tell(i), "answer", "User had a long day", "calm", i))
+            .collect();
+        let relevant = vec![tells[15].tid.clone()];
+
+        // Summaries come first, then the relevant tell, then as much of the latest as fits.
+        let context = build_context(&tells, &relevant, 180).unwrap();
+        assert!(estimated_tokens(&context) <= 180);
+        assert_eq!(context.mood, "calm");
+        assert_eq!(context.summary, "User had a long day");
+        assert_eq!(context.summary_history.len(), SUMMARY_HISTORY);
+        assert_eq!(context.history.len(), 2);
+        assert_eq!(context.history[0].tell, long_tell(15));
+        assert!(context.history[1].tell.starts_with("tell 0 word"));
+        assert!(context.history[1].tell.ends_with('…'));
+        assert_eq!(context.history[1].answer, "answer");
+
+        let context = build_context(&tells, &relevant, 25).unwrap();
+        assert!(estimated_tokens(&context) <= 25);
+        assert_eq!(context.mood, "calm");
+        assert!(context.summary_history.is_empty());
+        assert!(context.history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_relevant_tells_from_in_memory_index() {
+        use crate::memory::{InMemoryIndex, VectorIndex};
+
+        let tells = vec![
+            tell_item(
+                "Slept badly again",
+                "Rest matters.",
+                "Poor sleep",
+                "tired",
+                1,
+            ),
+            tell_item(
+                "My sister called",
+                "Family is a gift.",
+                "Sister call",
+                "warm",
+                2,
+            ),
+            tell_item(
+                "Got rejected by a company",
+                "Keep going.",
+                "Job rejection",
+                "sad",
+                3,
+            ),
+        ];
+        let index = InMemoryIndex::default();
+        for (tell, vector) in tells
+            .iter()
+            .zip([[0.0, 0.0, 1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]])
+        {
+            index
+                .upsert(EmbeddingItem {
+                    tid: tell.tid.clone(),
+                    username: "testuser".to_string(),
+                    vector: vector.to_vec(),
+                })
+                .await
+                .unwrap();
+        }
+
+        // A new job-related tell should surface the job rejection first
+        let scored = index.search("testuser", &[0.9, 0.1, 0.0], 1).await.unwrap();
+        assert_eq!(scored[0].tid, tells[2].tid);
+
+        let relevant: Vec<String> = scored.into_iter().map(|s| s.tid).collect();
+        let context = build_context(&tells, &relevant, usize::MAX).unwrap();
+        assert_eq!(context.history[0].tell, "Got rejected by a company");
+    }
+
+    #[tokio::test]
+    async fn test_tell_pipeline_with_recorded_gemini() {
+        use crate::gemini::GeminiProvider;
+        use crate::http_client::{fixture, ReplayClient};
+        use crate::memory::{InMemoryIndex, VectorIndex};
+        use crate::secrets::StaticSecrets;
+        use std::sync::Arc;
+
+        let http = ReplayClient::load(&fixture("gemini_tell_pipeline.json")).unwrap();
+        let secrets = StaticSecrets::with("GEMINI_API_KEY", "test-key");
+        let provider = GeminiProvider::new("gemini-2.0-flash", secrets, Arc::new(http));
+
+        let tells = vec![
+            tell_item(
+                "Slept badly again",
+                "Rest matters.",
+                "Poor sleep",
+                "tired",
+                1,
+            ),
+            tell_item(
+                "Got rejected by a company",
+                "Keep going.",
+                "Job rejection",
+                "sad",
+                30,
+            ),
+        ];
+        let index = InMemoryIndex::default();
+        for (tell, vector) in tells.iter().zip([[0.0, 0.0, 1.0], [1.0, 0.0, 0.0]]) {
+            index
+                .upsert(EmbeddingItem {
+                    tid: tell.tid.clone(),
+                    username: "testuser".to_string(),
+                    vector: vector.to_vec(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let message = "I got a job offer today!";
+        let embedding = provider.embed(message).await.unwrap();
+        let relevant: Vec<String> = index
+            .search("testuser", &embedding, 1)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| s.tid)
+            .collect();
+        let context = build_context(&tells, &relevant, usize::MAX).unwrap();
+        let reply = answer_tell(
+            &provider,
+            &Preferences::default(),
+            None,
+            "testuser",
+            &TellInput {
+                text: message.to_string(),
+                ..TellInput::default()
+            },
+            Some(&context),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let record = build_tell_record("testuser", message, &reply.response);
+        assert_eq!(
+            record.answer,
+            "After the rejection last month, this offer shows your persistence paid off."
+        );
+        assert_eq!(record.summary, Some("User got a job offer".to_string()));
+        assert_eq!(record.mood, "happy");
+        assert_eq!(reply.provider, "gemini");
+        assert_eq!(reply.usage.unwrap().total_tokens, 362);
+    }
+
+    /// Returns a fixed transcript without looking at the audio.
+    struct MockTranscriber(&'static str);
+
+    #[async_trait::async_trait]
+    impl Transcriber for MockTranscriber {
+        fn name(&self) -> &str {
+            "mock"
+        }
+
+        async fn transcribe(&self, _audio: &Attachment) -> anyhow::Result<Transcript> {
+            Ok(Transcript {
+                text: self.0.to_string(),
+                trace: None,
+            })
+        }
+    }
+
+    fn voice_input(text: &str) -> TellInput {
+        use crate::attachments::WAV_BYTES;
+
+        TellInput {
+            text: text.to_string(),
+            audio: Some(
+                Attachment::audio(None, bytes::Bytes::from_static(WAV_BYTES), 1024).unwrap(),
+            ),
+            ..TellInput::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transcribe_voice_tell() {
+        let transcriber = MockTranscriber("I ran my first marathon today");
+
+        let mut input = voice_input("");
+        input.transcribe(&transcriber).await.unwrap();
+        assert_eq!(input.text, "I ran my first marathon today");
+        assert_eq!(
+            input.transcript.map(|t| t.text),
+            Some("I ran my first marathon today".to_string())
+        );
+
+        let mut input = voice_input("Big news!");
+        input.transcribe(&transcriber).await.unwrap();
+        assert_eq!(input.text, "Big news!\n\nI ran my first marathon today");
+
+        let mut input = TellInput {
+            text: "Just text".to_string(),
+            ..TellInput::default()
+        };
+        input.transcribe(&transcriber).await.unwrap();
+        assert_eq!(input.text, "Just text");
+        assert_eq!(input.transcript, None);
+    }
+
     #[test]
-    fn test_get_context() {
-        let context = get_context();
+    fn test_build_context_first_conversation() {
+        assert!(build_context(&[], &[], usize::MAX).is_none());
+    }
 
-        assert_eq!(context.mood, "satisfied");
-        assert!(context.summary.contains("job search frustrations"));
-        assert_eq!(context.summary_history.len(), 4);
-        assert_eq!(context.tell_history.len(), 4);
+    #[test]
+    fn test_context_messages() {
+        let context = Context {
+            mood: "calm".to_string(),
+            summary: String::new(),
+            summary_history: vec![],
+            history: vec![Exchange {
+                tell: "I'm job hunting".to_string(),
+                answer: "You will find something.".to_string(),
+            }],
+        };
 
-        assert!(context.summary_history[0].contains("Hopeful, determined"));
-        assert!(context.tell_history[0].contains("Another day of no job"));
+        assert_eq!(
+            context.messages(),
+            vec![
+                Message::user("I'm job hunting"),
+                Message::model("You will find something.")
+            ]
+        );
     }
 
     #[test]
@@ -143,17 +892,18 @@ mod tests {
                 "Was looking for work".to_string(),
                 "Had interviews".to_string(),
             ],
-            tell_history: vec![
-                "I'm job hunting".to_string(),
-                "Interview went well".to_string(),
-            ],
+            history: vec![Exchange {
+                tell: "I'm job hunting".to_string(),
+                answer: "You will find something.".to_string(),
+            }],
         };
 
         let display = format!("{}", context);
         assert!(display.contains("My current mood: excited"));
         assert!(display.contains("My current situation: User got a new job"));
         assert!(display.contains("Was looking for work, Had interviews"));
-        assert!(display.contains("I'm job hunting, Interview went well"));
+        // Past tells are sent as conversation turns, not stuffed into the context
+        assert!(!display.contains("I'm job hunting"));
     }
 
     #[test]
@@ -162,14 +912,13 @@ mod tests {
             mood: "calm".to_string(),
             summary: "First conversation".to_string(),
             summary_history: vec![],
-            tell_history: vec![],
+            history: vec![],
         };
 
         let display = format!("{}", context);
         assert!(display.contains("My current mood: calm"));
         assert!(display.contains("My current situation: First conversation"));
         assert!(display.contains("My past situations: "));
-        assert!(display.contains("My past tells to you: "));
     }
 
     #[test]
@@ -178,12 +927,11 @@ mod tests {
             mood: "hopeful".to_string(),
             summary: "User shared good news".to_string(),
             summary_history: vec!["Previous summary".to_string()],
-            tell_history: vec!["My single tell".to_string()],
+            history: vec![],
         };
 
         let display = format!("{}", context);
         assert!(display.contains("Previous summary"));
-        assert!(display.contains("My single tell"));
         assert!(!display.contains(", "));
     }
 
@@ -244,4 +992,42 @@ mod tests {
         assert_eq!(tell_item.mood, "");
         assert_eq!(tell_item.summary, Some("".to_string()));
     }
+
+    #[test]
+    fn test_add_usage_sums_calls_and_costs() {
+        use crate::gemini::GeminiTellResponse;
+
+        let ai_response = GeminiTellResponse {
+            answer: "Well done!".to_string(),
+            summary: "User ran a marathon".to_string(),
+            user_state: "proud".to_string(),
+            mood: "proud".to_string(),
+        };
+        let mut tell_item = build_tell_record("testuser", "I ran a marathon", &ai_response);
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            candidate_tokens: 0,
+            total_tokens: 1_000_000,
+        };
+
+        tell_item.add_usage("gemini-2.0-flash", Some(usage));
+        tell_item.add_usage("local-model", Some(usage));
+        tell_item.add_usage("gemini-2.0-flash", None);
+        assert_eq!(tell_item.usage.unwrap().total_tokens, 2_000_000);
+        assert!((tell_item.cost_usd.unwrap() - 0.10).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_build_tell_record_normalizes_the_mood() {
+        let ai_response = GeminiTellResponse {
+            answer: "Qué bien que hayas descansado.".to_string(),
+            summary: "El usuario durmió bien.".to_string(),
+            user_state: "Descansado.".to_string(),
+            mood: "Tranquila".to_string(),
+        };
+
+        let tell_item = build_tell_record("lucia", "Hoy dormí diez horas", &ai_response);
+        assert_eq!(tell_item.mood, "calm");
+        assert_eq!(tell_item.answer, "Qué bien que hayas descansado.");
+    }
 }
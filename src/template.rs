@@ -0,0 +1,310 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// A piece of a parsed template. Placeholders are `{name}`, where `name` is an ASCII identifier;
+/// every other brace is literal text, so templates can show JSON examples as they are.
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+    Literal(&'a str),
+    Placeholder(&'a str),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateError {
+    /// Placeholders without a supplied value, in order of first appearance.
+    Missing(Vec<String>),
+    /// Supplied values that no placeholder uses.
+    Unused(Vec<String>),
+    /// A value supplied more than once.
+    Duplicate(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateError::Missing(names) => {
+                write!(f, "No value for placeholders: {}", names.join(", "))
+            }
+            TemplateError::Unused(names) => {
+                write!(f, "Values not used by the template: {}", names.join(", "))
+            }
+            TemplateError::Duplicate(name) => write!(f, "Value '{}' supplied twice", name),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+fn parse(template: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    while let Some(offset) = template[i..].find('{') {
+        let start = i + offset;
+        match placeholder_len(&template[start + 1..]) {
+            Some(len) => {
+                if literal_start < start {
+                    segments.push(Segment::Literal(&template[literal_start..start]));
+                }
+                segments.push(Segment::Placeholder(&template[start + 1..start + 1 + len]));
+                i = start + len + 2;
+                literal_start = i;
+            }
+            None => i = start + 1,
+        }
+    }
+    if literal_start < template.len() {
+        segments.push(Segment::Literal(&template[literal_start..]));
+    }
+    segments
+}
+
+/// Length of the identifier at the start of `rest` if it is closed by `}`.
+fn placeholder_len(rest: &str) -> Option<usize> {
+    let bytes = rest.as_bytes();
+    let len = bytes
+        .iter()
+        .take_while(|b| b.is_ascii_alphanumeric() || **b == b'_')
+        .count();
+    let starts_with_letter = bytes
+        .first()
+        .is_some_and(|b| b.is_ascii_alphabetic() || *b == b'_');
+    (starts_with_letter && bytes.get(len) == Some(&b'}')).then_some(len)
+}
+
+/// The placeholder names of `template`, each once, in order of first appearance.
+pub fn placeholders(template: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for segment in parse(template) {
+        if let Segment::Placeholder(name) = segment {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Renders `template` in a single pass. Values are inserted verbatim and never scanned for
+/// placeholders themselves, so user text containing e.g. `{context}` stays as it is. Every
+/// placeholder needs a value and every value has to be used.
+pub fn render(template: &str, values: &[(&str, &str)]) -> Result<String, TemplateError> {
+    let mut supplied = HashMap::with_capacity(values.len());
+    for (name, value) in values {
+        if supplied.insert(*name, *value).is_some() {
+            return Err(TemplateError::Duplicate(name.to_string()));
+        }
+    }
+
+    let mut rendered =
+        String::with_capacity(template.len() + values.iter().map(|(_, v)| v.len()).sum::<usize>());
+    let mut used = HashSet::new();
+    let mut missing: Vec<String> = Vec::new();
+    for segment in parse(template) {
+        match segment {
+            Segment::Literal(text) => rendered.push_str(text),
+            Segment::Placeholder(name) => match supplied.get(name) {
+                Some(value) => {
+                    rendered.push_str(value);
+                    used.insert(name);
+                }
+                None if !missing.iter().any(|m| m == name) => missing.push(name.to_string()),
+                None => {}
+            },
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(TemplateError::Missing(missing));
+    }
+    let unused: Vec<String> = values
+        .iter()
+        .filter(|(name, _)| !used.contains(name))
+        .map(|(name, _)| name.to_string())
+        .collect();
+    if !unused.is_empty() {
+        return Err(TemplateError::Unused(unused));
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse("Hi {name}! {\n  \"a\": 1\n} {not closed {x1}"),
+            vec![
+                Segment::Literal("Hi "),
+                Segment::Placeholder("name"),
+                Segment::Literal("! {\n  \"a\": 1\n} {not closed "),
+                Segment::Placeholder("x1"),
+            ]
+        );
+        assert_eq!(
+            parse("{1x} {} { a }"),
+            vec![Segment::Literal("{1x} {} { a }")]
+        );
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(placeholders("{b} {a} {\"json\": 1} {b}"), vec!["b", "a"]);
+        assert!(placeholders("no placeholders {}").is_empty());
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("{a} and {b}, {a} again", &[("b", "B"), ("a", "A")]),
+            Ok("A and B, A again".to_string())
+        );
+    }
+
+    #[test]
+    fn test_render_errors() {
+        assert_eq!(
+            render("{a} {b} {c} {b}", &[("a", "A")]),
+            Err(TemplateError::Missing(vec![
+                "b".to_string(),
+                "c".to_string()
+            ]))
+        );
+        assert_eq!(
+            render("{a}", &[("a", "A"), ("z", "Z")]),
+            Err(TemplateError::Unused(vec!["z".to_string()]))
+        );
+        assert_eq!(
+            render("{a}", &[("a", "A"), ("a", "B")]),
+            Err(TemplateError::Duplicate("a".to_string()))
+        );
+        assert_eq!(
+            TemplateError::Missing(vec!["b".to_string(), "c".to_string()]).to_string(),
+            "No value for placeholders: b, c"
+        );
+    }
+
+    const NAMES: [&str; 3] = ["username", "context", "tell"];
+
+    /// Text that looks like template syntax as often as it looks like prose.
+    fn adversarial_text() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                Just("{username}".to_string()),
+                Just("{context}".to_string()),
+                Just("{tell}".to_string()),
+                Just("{".to_string()),
+                Just("}".to_string()),
+                Just("{{tell}}".to_string()),
+                any::<String>(),
+            ],
+            0..8,
+        )
+        .prop_map(|parts| parts.concat())
+    }
+
+    #[derive(Clone, Debug)]
+    enum Part {
+        Literal(String),
+        Placeholder(usize),
+    }
+
+    /// Templates made of brace-free literals and placeholders drawn from `NAMES`.
+    fn template_parts() -> impl Strategy<Value = Vec<Part>> {
+        prop::collection::vec(
+            prop_oneof![
+                "[^{}]*".prop_map(Part::Literal),
+                (0..NAMES.len()).prop_map(Part::Placeholder),
+            ],
+            0..10,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_values_are_inserted_verbatim(
+            parts in template_parts(),
+            values in prop::collection::vec(adversarial_text(), NAMES.len()),
+        ) {
+            let template: String = parts
+                .iter()
+                .map(|part| match part {
+                    Part::Literal(text) => text.clone(),
+                    Part::Placeholder(i) => format!("{{{}}}", NAMES[*i]),
+                })
+                .collect();
+            let expected: String = parts
+                .iter()
+                .map(|part| match part {
+                    Part::Literal(text) => text.as_str(),
+                    Part::Placeholder(i) => values[*i].as_str(),
+                })
+                .collect();
+            let supplied: Vec<(&str, &str)> = NAMES
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| parts.iter().any(|p| matches!(p, Part::Placeholder(j) if j == i)))
+                .map(|(i, name)| (*name, values[i].as_str()))
+                .collect();
+
+            prop_assert_eq!(render(&template, &supplied), Ok(expected));
+        }
+
+        #[test]
+        fn prop_tell_prompt_never_reexpands_user_content(
+            username in adversarial_text(),
+            context in adversarial_text(),
+            tell in adversarial_text(),
+        ) {
+            let template = "My name is {username}. {context}\n{\n  \"tell\": \"{tell}\"\n}";
+            let rendered = render(
+                template,
+                &[("username", &username), ("context", &context), ("tell", &tell)],
+            )
+            .unwrap();
+            let expected = format!(
+                "My name is {}. {}\n{{\n  \"tell\": \"{}\"\n}}",
+                username, context, tell
+            );
+            prop_assert_eq!(rendered, expected);
+        }
+
+        #[test]
+        fn prop_value_order_does_not_matter(
+            values in prop::collection::vec(adversarial_text(), NAMES.len()),
+            seed in any::<u64>(),
+        ) {
+            let template = "{tell} / {context} / {username}";
+            let mut supplied: Vec<(&str, &str)> = NAMES
+                .iter()
+                .zip(&values)
+                .map(|(name, value)| (*name, value.as_str()))
+                .collect();
+            let expected = render(template, &supplied).unwrap();
+            supplied.rotate_left((seed % NAMES.len() as u64) as usize);
+            supplied.swap(0, (seed as usize / 7) % NAMES.len());
+            prop_assert_eq!(render(template, &supplied), Ok(expected));
+        }
+
+        #[test]
+        fn prop_every_placeholder_needs_a_value(
+            values in prop::collection::vec(adversarial_text(), NAMES.len()),
+            dropped in 0..NAMES.len(),
+        ) {
+            let supplied: Vec<(&str, &str)> = NAMES
+                .iter()
+                .zip(&values)
+                .enumerate()
+                .filter(|(i, _)| *i != dropped)
+                .map(|(_, (name, value))| (*name, value.as_str()))
+                .collect();
+            prop_assert_eq!(
+                render("{username} {context} {tell}", &supplied),
+                Err(TemplateError::Missing(vec![NAMES[dropped].to_string()]))
+            );
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use crate::llm::{use_llm, ToolCall, ToolDeclaration};
+use crate::memory::use_index;
+use crate::metrics;
+use crate::tell::{get_user_tells, TellItem};
+use crate::users::{get_user_by_name, User};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::Instant;
+
+pub const SEARCH_PAST_TELLS: &str = "search_past_tells";
+pub const GET_MOOD_HISTORY: &str = "get_mood_history";
+pub const GET_USER_PROFILE: &str = "get_user_profile";
+
+/// Number of past tells returned by `search_past_tells`.
+const SEARCH_RESULTS: usize = 5;
+/// Longest period `get_mood_history` looks back over.
+const MAX_MOOD_HISTORY_DAYS: i64 = 90;
+
+/// Runs the functions offered to the model.
+#[async_trait]
+pub trait ToolExecutor: Send + Sync {
+    fn declarations(&self) -> Vec<ToolDeclaration>;
+
+    /// Runs `call` and returns its result as a JSON value.
+    async fn call(&self, call: &ToolCall) -> anyhow::Result<Value>;
+}
+
+/// A tool call as it happened, kept on the tell for debugging.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ToolCallRecord {
+    pub name: String,
+    pub args: Value,
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl ToolCallRecord {
+    /// What the model is told about the call. Failures are reported rather than aborting the
+    /// tell, so the model can answer without the data.
+    pub fn content(&self) -> Value {
+        match (&self.result, &self.error) {
+            (Some(result), _) => result.clone(),
+            (None, error) => json!({ "error": error }),
+        }
+    }
+}
+
+/// Runs `call` with `executor`, logging and recording the outcome.
+pub async fn execute(executor: Option<&dyn ToolExecutor>, call: &ToolCall) -> ToolCallRecord {
+    let started = Instant::now();
+    let outcome = match executor {
+        Some(executor) => executor.call(call).await,
+        None => Err(anyhow::anyhow!("No tools are available")),
+    };
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    let (result, error) = match outcome {
+        Ok(result) => {
+            println!(
+                "Tool call {}({}) succeeded in {}ms",
+                call.name, call.args, duration_ms
+            );
+            (Some(result), None)
+        }
+        Err(e) => {
+            eprintln!("Tool call {}({}) failed: {:?}", call.name, call.args, e);
+            (None, Some(e.to_string()))
+        }
+    };
+    let outcome = if error.is_none() { "ok" } else { "error" };
+    metrics::count("ToolCall", &[("Tool", &call.name), ("Outcome", outcome)]);
+
+    ToolCallRecord {
+        name: call.name.clone(),
+        args: call.args.clone(),
+        result,
+        error,
+        duration_ms,
+    }
+}
+
+/// The tools offered when answering a tell.
+pub fn tell_tool_declarations() -> Vec<ToolDeclaration> {
+    vec![
+        ToolDeclaration {
+            name: SEARCH_PAST_TELLS,
+            description: "Finds the user's past tells that relate to a topic, with Teal's answers, best match first.",
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "What to look for, e.g. \"job interviews\".",
+                    },
+                },
+                "required": ["query"],
+            })),
+        },
+        ToolDeclaration {
+            name: GET_MOOD_HISTORY,
+            description: "Lists the moods the user reported over the last days, oldest first.",
+            parameters: Some(json!({
+                "type": "object",
+                "properties": {
+                    "days": {
+                        "type": "integer",
+                        "description": "How many days to look back, at most 90.",
+                    },
+                },
+                "required": ["days"],
+            })),
+        },
+        ToolDeclaration {
+            name: GET_USER_PROFILE,
+            description: "Returns the user's profile: name, chosen persona, current mood and how long they have used Teal.",
+            parameters: None,
+        },
+    ]
+}
+
+/// The tell tools of one user, backed by the tells table, the vector index and the users table.
+pub struct TellTools {
+    username: String,
+}
+
+impl TellTools {
+    pub fn new(username: &str) -> Self {
+        Self {
+            username: username.to_string(),
+        }
+    }
+
+    /// Ranks past tells semantically, falling back to keyword matching when the query can't be
+    /// embedded. Like the tell's own embedding, the query embedding is not metered.
+    async fn search_past_tells(&self, query: &str) -> anyhow::Result<Value> {
+        let tells = get_user_tells(&self.username).await?;
+        let tids = match use_llm().embed(query).await {
+            Ok(vector) => use_index()
+                .search(&self.username, &vector, SEARCH_RESULTS)
+                .await?
+                .into_iter()
+                .map(|s| s.tid)
+                .collect(),
+            Err(e) => {
+                eprintln!("Failed to embed tool query, matching keywords: {:?}", e);
+                keyword_matches(&tells, query, SEARCH_RESULTS)
+            }
+        };
+        Ok(json!(past_tells(&tells, &tids)))
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for TellTools {
+    fn declarations(&self) -> Vec<ToolDeclaration> {
+        tell_tool_declarations()
+    }
+
+    async fn call(&self, call: &ToolCall) -> anyhow::Result<Value> {
+        match call.name.as_str() {
+            SEARCH_PAST_TELLS => {
+                self.search_past_tells(string_arg(&call.args, "query")?)
+                    .await
+            }
+            GET_MOOD_HISTORY => {
+                let days = days_arg(&call.args)?;
+                let tells = get_user_tells(&self.username).await?;
+                Ok(json!(mood_history(&tells, days, Utc::now())))
+            }
+            GET_USER_PROFILE => {
+                let user = get_user_by_name(&self.username).await?;
+                let tells = get_user_tells(&self.username).await?;
+                Ok(user_profile(&self.username, user.as_ref(), &tells))
+            }
+            other => Err(anyhow::anyhow!("Unknown tool '{}'", other)),
+        }
+    }
+}
+
+fn string_arg<'a>(args: &'a Value, name: &str) -> anyhow::Result<&'a str> {
+    args.get(name)
+        .and_then(Value::as_str)
+        .filter(|value| !value.trim().is_empty())
+        .ok_or_else(|| anyhow::anyhow!("Missing argument '{}'", name))
+}
+
+/// Reads `days`, which models send as either a number or a string, clamped to
+/// `1..=MAX_MOOD_HISTORY_DAYS`.
+fn days_arg(args: &Value) -> anyhow::Result<i64> {
+    let days = match args.get("days") {
+        Some(Value::Number(n)) => n.as_f64().map(|n| n as i64),
+        Some(Value::String(s)) => s.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow::anyhow!("Missing argument 'days'"))?;
+    Ok(days.clamp(1, MAX_MOOD_HISTORY_DAYS))
+}
+
+/// Tids of the tells sharing the most words with `query`, best match first.
+fn keyword_matches(tells: &[TellItem], query: &str, k: usize) -> Vec<String> {
+    let words: Vec<String> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 2)
+        .map(str::to_lowercase)
+        .collect();
+
+    let mut scored: Vec<(usize, &TellItem)> = tells
+        .iter()
+        .map(|tell| {
+            let text = format!(
+                "{} {}",
+                tell.tell,
+                tell.summary.as_deref().unwrap_or_default()
+            )
+            .to_lowercase();
+            (
+                words.iter().filter(|w| text.contains(w.as_str())).count(),
+                tell,
+            )
+        })
+        .filter(|(hits, _)| *hits > 0)
+        .collect();
+    // Stable, so ties keep the newest-first order of `tells`.
+    scored.sort_by_key(|(hits, _)| std::cmp::Reverse(*hits));
+    scored
+        .into_iter()
+        .take(k)
+        .map(|(_, t)| t.tid.clone())
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PastTell {
+    date: String,
+    tell: String,
+    answer: String,
+    mood: String,
+}
+
+/// The tells listed in `tids`, in that order.
+fn past_tells(tells: &[TellItem], tids: &[String]) -> Vec<PastTell> {
+    tids.iter()
+        .filter_map(|tid| tells.iter().find(|t| &t.tid == tid))
+        .map(|t| PastTell {
+            date: t.created_at.format("%Y-%m-%d").to_string(),
+            tell: t.tell.clone(),
+            answer: t.answer.clone(),
+            mood: t.mood.clone(),
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct MoodEntry {
+    date: String,
+    mood: String,
+}
+
+/// Moods of the tells from the last `days` days, oldest first.
+fn mood_history(tells: &[TellItem], days: i64, now: DateTime<Utc>) -> Vec<MoodEntry> {
+    let since = now - Duration::days(days);
+    let mut entries: Vec<&TellItem> = tells.iter().filter(|t| t.created_at >= since).collect();
+    entries.sort_by_key(|t| t.created_at);
+    entries
+        .into_iter()
+        .map(|t| MoodEntry {
+            date: t.created_at.format("%Y-%m-%d").to_string(),
+            mood: t.mood.clone(),
+        })
+        .collect()
+}
+
+/// The profile fields worth knowing when answering. The email address is left out.
+fn user_profile(username: &str, user: Option<&User>, tells: &[TellItem]) -> Value {
+    json!({
+        "name": username,
+        "persona": user.and_then(|u| u.persona).unwrap_or_default().id(),
+        "current_mood": user
+            .and_then(|u| u.current_mood.clone())
+            .or_else(|| tells.first().map(|t| t.mood.clone())),
+        "member_since": user.map(|u| u.created_at.clone()),
+        "tell_count": tells.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemini::GeminiTellResponse;
+    use crate::prompts::Persona;
+    use crate::tell::build_tell_record;
+
+    fn tell_item(tell: &str, summary: &str, mood: &str, days_ago: i64) -> TellItem {
+        let mut item = build_tell_record(
+            "testuser",
+            tell,
+            &GeminiTellResponse {
+                answer: "answer".to_string(),
+                summary: summary.to_string(),
+                user_state: "state".to_string(),
+                mood: mood.to_string(),
+            },
+        );
+        item.tid = format!("tid-{}", days_ago);
+        item.created_at = Utc::now() - Duration::days(days_ago);
+        item
+    }
+
+    fn tells() -> Vec<TellItem> {
+        vec![
+            tell_item("Slept badly again", "Poor sleep", "tired", 1),
+            tell_item("My interview went well", "Job interview", "hopeful", 5),
+            tell_item(
+                "Got rejected after the interview",
+                "Job rejection",
+                "sad",
+                40,
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_declarations() {
+        let names: Vec<&str> = tell_tool_declarations().iter().map(|d| d.name).collect();
+        assert_eq!(
+            names,
+            vec![SEARCH_PAST_TELLS, GET_MOOD_HISTORY, GET_USER_PROFILE]
+        );
+    }
+
+    #[test]
+    fn test_keyword_matches() {
+        let tells = tells();
+        assert_eq!(
+            keyword_matches(&tells, "job interview", 5),
+            vec!["tid-5", "tid-40"]
+        );
+        assert_eq!(keyword_matches(&tells, "interview", 1), vec!["tid-5"]);
+        assert!(keyword_matches(&tells, "a to", 5).is_empty());
+    }
+
+    #[test]
+    fn test_past_tells_keep_ranking_order() {
+        let tells = tells();
+        let found = past_tells(&tells, &["tid-40".to_string(), "tid-1".to_string()]);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].tell, "Got rejected after the interview");
+        assert_eq!(found[1].mood, "tired");
+    }
+
+    #[test]
+    fn test_mood_history() {
+        let history = mood_history(&tells(), 7, Utc::now());
+        let moods: Vec<&str> = history.iter().map(|e| e.mood.as_str()).collect();
+        assert_eq!(moods, vec!["hopeful", "tired"]);
+    }
+
+    #[test]
+    fn test_days_arg() {
+        assert_eq!(days_arg(&json!({ "days": 7 })).unwrap(), 7);
+        assert_eq!(days_arg(&json!({ "days": "14" })).unwrap(), 14);
+        assert_eq!(days_arg(&json!({ "days": 1000 })).unwrap(), 90);
+        assert!(days_arg(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_user_profile() {
+        let user = User {
+            tid: "u1".to_string(),
+            name: "testuser".to_string(),
+            email: "test@example.com".to_string(),
+            current_mood: None,
+            created_at: "2025-01-01".to_string(),
+            persona: Some(Persona::StoicCoach),
+            locale: None,
+        };
+
+        let profile = user_profile("testuser", Some(&user), &tells());
+        assert_eq!(profile["persona"], "stoic_coach");
+        assert_eq!(profile["current_mood"], "tired");
+        assert_eq!(profile["tell_count"], 3);
+        assert!(!profile.to_string().contains("test@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_records_failures() {
+        let call = ToolCall {
+            id: "call-1".to_string(),
+            name: GET_USER_PROFILE.to_string(),
+            args: json!({}),
+        };
+
+        let record = execute(None, &call).await;
+        assert_eq!(record.name, GET_USER_PROFILE);
+        assert_eq!(record.error.as_deref(), Some("No tools are available"));
+        assert_eq!(
+            record.content(),
+            json!({ "error": "No tools are available" })
+        );
+    }
+}
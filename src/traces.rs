@@ -0,0 +1,265 @@
+use crate::dynamo::{use_db, TRACES_TABLE_NAME};
+use crate::llm::{FinishReason, LlmRequest, LlmResponse};
+use crate::prompts::Prompt;
+use crate::usage::TokenUsage;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// What became of a call's output.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ParseOutcome {
+    /// Parsed into a reply that meets the response contract.
+    Parsed,
+    /// Not the JSON object the prompt declares, e.g. with keys a user's text asked for; asked
+    /// again once, then failed.
+    SchemaViolation,
+    /// Parsed, but broke the response contract and was regenerated or repaired.
+    ContractViolation,
+    /// The model asked for tool calls instead of answering.
+    ToolCalls,
+    /// Cut off at the output token limit and retried.
+    Truncated,
+    /// Blocked by safety filters; the fallback reply was used instead.
+    Blocked,
+    /// Used as plain text, e.g. a transcript.
+    Text,
+}
+
+/// One LLM call, stored in the `teal-llm-traces` table to debug answers after the fact.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LlmTrace {
+    /// Id of the trace itself, the table key.
+    pub tid: String,
+    /// The tell the call was made for.
+    pub tell_tid: Option<String>,
+    pub username: Option<String>,
+    /// When the call finished.
+    pub created_at: chrono::DateTime<Utc>,
+    pub provider: String,
+    pub model: String,
+    /// File name of the prompt template, e.g. `tell.md`.
+    pub template: Option<String>,
+    /// Version declared in the template's front-matter.
+    #[serde(default)]
+    pub template_version: Option<u32>,
+    pub template_hash: Option<String>,
+    /// Characters sent: the system instruction plus the text of every message.
+    pub prompt_chars: usize,
+    pub latency_ms: u64,
+    pub usage: Option<TokenUsage>,
+    /// Calls made before this one for the same reply: truncation retries, tool rounds and
+    /// contract corrections.
+    pub retries: u32,
+    pub finish_reason: Option<String>,
+    pub outcome: ParseOutcome,
+}
+
+impl LlmTrace {
+    pub fn new(
+        request: &LlmRequest,
+        res: &LlmResponse,
+        latency: Duration,
+        retries: usize,
+        outcome: ParseOutcome,
+    ) -> Self {
+        let prompt_chars = request.system_instruction.chars().count()
+            + request
+                .messages
+                .iter()
+                .map(|m| m.text.chars().count())
+                .sum::<usize>();
+        Self {
+            tid: Uuid::new_v4().to_string(),
+            tell_tid: None,
+            username: None,
+            created_at: Utc::now(),
+            provider: res.provider.clone(),
+            model: res.model.clone(),
+            template: None,
+            template_version: None,
+            template_hash: None,
+            prompt_chars,
+            latency_ms: latency.as_millis() as u64,
+            usage: res.usage,
+            retries: retries as u32,
+            finish_reason: res.finish_reason.as_ref().map(finish_reason_label),
+            outcome,
+        }
+    }
+
+    /// Records the rendered prompt the call was made with.
+    pub fn set_prompt(&mut self, prompt: &Prompt) {
+        self.template = Some(prompt.name.to_string());
+        self.template_version = Some(prompt.version.version);
+        self.template_hash = Some(prompt.version.hash.clone());
+    }
+}
+
+fn finish_reason_label(reason: &FinishReason) -> String {
+    match reason {
+        FinishReason::Stop => "stop".to_string(),
+        FinishReason::MaxTokens => "max_tokens".to_string(),
+        FinishReason::Blocked(reason) => format!("blocked: {}", reason),
+        FinishReason::Other(reason) => reason.to_lowercase(),
+    }
+}
+
+/// Links the traces to their user and tell, if they were made for one, and stores them. Tracing
+/// is best-effort, so failures are logged and the request carries on.
+pub async fn save_traces(username: &str, tell_tid: Option<&str>, traces: Vec<LlmTrace>) {
+    for mut trace in traces {
+        trace.tell_tid = tell_tid.map(str::to_string);
+        trace.username = Some(username.to_string());
+        let item = match to_value(trace) {
+            Ok(item) => item,
+            Err(e) => {
+                eprintln!("Failed to serialize LLM trace: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = use_db().put(TRACES_TABLE_NAME, item).await {
+            eprintln!("Failed to store LLM trace: {:?}", e);
+        }
+    }
+}
+
+/// Which traces to list.
+#[derive(Debug, PartialEq)]
+pub enum TraceFilter {
+    User(String),
+    Tell(String),
+}
+
+/// Lists the matching traces, newest first.
+pub async fn get_traces(filter: &TraceFilter) -> anyhow::Result<Vec<LlmTrace>> {
+    let (key, value) = match filter {
+        TraceFilter::User(username) => ("username", username),
+        TraceFilter::Tell(tid) => ("tell_tid", tid),
+    };
+    let mut traces: Vec<LlmTrace> = use_db().scan(TRACES_TABLE_NAME, key, value).await?;
+    sort_traces(&mut traces);
+    Ok(traces)
+}
+
+/// Newest first; calls of the same tell by their position in the exchange.
+fn sort_traces(traces: &mut [LlmTrace]) {
+    traces.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then(b.retries.cmp(&a.retries))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{GenerationConfig, Message, OutputFormat};
+    use crate::prompts::PromptVersion;
+
+    fn response(finish_reason: Option<FinishReason>) -> LlmResponse {
+        LlmResponse {
+            text: "{}".to_string(),
+            provider: "gemini".to_string(),
+            model: "gemini-2.0-flash".to_string(),
+            usage: Some(TokenUsage {
+                prompt_tokens: 10,
+                candidate_tokens: 5,
+                total_tokens: 15,
+            }),
+            finish_reason,
+            tool_calls: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_trace_from_call() {
+        let request = LlmRequest {
+            system_instruction: "Be kind.".to_string(),
+            messages: vec![Message::user("Hello"), Message::model("Hi")],
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Json(Vec::new()),
+            tools: Vec::new(),
+        };
+
+        let mut trace = LlmTrace::new(
+            &request,
+            &response(Some(FinishReason::MaxTokens)),
+            Duration::from_millis(1250),
+            1,
+            ParseOutcome::Truncated,
+        );
+        let mut prompt = Prompt::json("Hello");
+        prompt.name = "tell.md";
+        prompt.version = PromptVersion {
+            version: 1,
+            hash: "ab".repeat(32),
+        };
+        trace.set_prompt(&prompt);
+
+        assert_eq!(trace.provider, "gemini");
+        assert_eq!(trace.model, "gemini-2.0-flash");
+        assert_eq!(trace.prompt_chars, 15);
+        assert_eq!(trace.latency_ms, 1250);
+        assert_eq!(trace.usage.unwrap().total_tokens, 15);
+        assert_eq!(trace.retries, 1);
+        assert_eq!(trace.finish_reason, Some("max_tokens".to_string()));
+        assert_eq!(trace.template, Some("tell.md".to_string()));
+        assert_eq!(trace.template_version, Some(1));
+        assert_eq!(trace.template_hash.as_ref().unwrap().len(), 64);
+
+        let value = to_value(&trace).unwrap();
+        assert_eq!(value["outcome"], "truncated");
+    }
+
+    #[test]
+    fn test_finish_reason_label() {
+        assert_eq!(finish_reason_label(&FinishReason::Stop), "stop");
+        assert_eq!(
+            finish_reason_label(&FinishReason::Blocked("SAFETY".to_string())),
+            "blocked: SAFETY"
+        );
+        assert_eq!(
+            finish_reason_label(&FinishReason::Other("RECITATION".to_string())),
+            "recitation"
+        );
+    }
+
+    #[test]
+    fn test_sort_traces() {
+        let request = LlmRequest {
+            system_instruction: String::new(),
+            messages: Vec::new(),
+            generation_config: GenerationConfig::default(),
+            output: OutputFormat::Json(Vec::new()),
+            tools: Vec::new(),
+        };
+        let trace = |retries, minutes_ago| {
+            let mut trace = LlmTrace::new(
+                &request,
+                &response(None),
+                Duration::ZERO,
+                retries,
+                ParseOutcome::Parsed,
+            );
+            trace.created_at = Utc::now() - chrono::Duration::minutes(minutes_ago);
+            trace
+        };
+
+        let mut traces = vec![trace(0, 10), trace(0, 1), trace(1, 10)];
+        sort_traces(&mut traces);
+        let order: Vec<(u32, bool)> = traces
+            .iter()
+            .map(|t| {
+                (
+                    t.retries,
+                    t.created_at > Utc::now() - chrono::Duration::minutes(5),
+                )
+            })
+            .collect();
+        assert_eq!(order, vec![(0, true), (1, false), (0, false)]);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::attachments::Attachment;
+use crate::llm::{use_llm, LlmProvider, LlmRequest};
+use crate::locale::Locale;
+use crate::prompts::{create_prompt, Persona, PromptData};
+use crate::traces::{LlmTrace, ParseOutcome};
+use async_trait::async_trait;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+/// What was said in a voice clip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transcript {
+    pub text: String,
+    /// The LLM call that produced the transcript, if one was made.
+    pub trace: Option<LlmTrace>,
+}
+
+/// Turns a voice clip into the text of a tell.
+#[async_trait]
+pub trait Transcriber: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn transcribe(&self, audio: &Attachment) -> anyhow::Result<Transcript>;
+}
+
+/// Sends the clip to an LLM provider as multimodal audio input and asks for a verbatim
+/// transcript.
+pub struct LlmTranscriber {
+    provider: Arc<dyn LlmProvider>,
+}
+
+impl LlmTranscriber {
+    pub fn new(provider: Arc<dyn LlmProvider>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl Transcriber for LlmTranscriber {
+    fn name(&self) -> &str {
+        self.provider.name()
+    }
+
+    async fn transcribe(&self, audio: &Attachment) -> anyhow::Result<Transcript> {
+        // Transcripts stay in the language spoken, so the prompt is never localized.
+        let prompt = create_prompt(&Locale::default(), PromptData::Transcribe)
+            .await?
+            .with_media(vec![audio.media()]);
+        let request =
+            LlmRequest::from_prompt(&prompt.instruction_for(Persona::default())?, &[], &prompt);
+        let started = Instant::now();
+        let res = self.provider.generate(&request).await?;
+        res.check_finish(&request)?;
+
+        let transcript = res.text.trim();
+        if transcript.is_empty() {
+            return Err(anyhow::anyhow!("No speech found in the audio clip"));
+        }
+        let mut trace = LlmTrace::new(&request, &res, started.elapsed(), 0, ParseOutcome::Text);
+        trace.set_prompt(&prompt);
+        Ok(Transcript {
+            text: transcript.to_string(),
+            trace: Some(trace),
+        })
+    }
+}
+
+static TRANSCRIBER: OnceLock<Arc<dyn Transcriber>> = OnceLock::new();
+
+pub fn init_global_transcriber(transcriber: Arc<dyn Transcriber>) {
+    TRANSCRIBER.set(transcriber).ok();
+}
+
+pub fn use_transcriber() -> &'static Arc<dyn Transcriber> {
+    TRANSCRIBER.get().expect("Transcriber not initialized")
+}
+
+/// Sets up the transcriber selected by `TEAL_TRANSCRIBER`. Only `llm` (the default), which reuses
+/// the configured LLM provider, is available so far. Must run after `initialize_llm`.
+pub fn initialize_transcriber() -> anyhow::Result<()> {
+    let transcriber: Arc<dyn Transcriber> = match std::env::var("TEAL_TRANSCRIBER")
+        .unwrap_or_default()
+        .as_str()
+    {
+        "" | "llm" => Arc::new(LlmTranscriber::new(use_llm().clone())),
+        other => return Err(anyhow::anyhow!("Unknown transcriber '{}'", other)),
+    };
+    println!("Using transcriber {}", transcriber.name());
+    init_global_transcriber(transcriber);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachments::WAV_BYTES;
+    use crate::llm::{FinishReason, LlmResponse};
+    use bytes::Bytes;
+    use std::sync::Mutex;
+
+    /// Answers with a fixed transcript and keeps the requests it received.
+    struct TranscribingProvider {
+        text: &'static str,
+        requests: Mutex<Vec<(String, bool, Vec<String>)>>,
+    }
+
+    #[async_trait]
+    impl LlmProvider for TranscribingProvider {
+        fn name(&self) -> &str {
+            "transcribing"
+        }
+
+        async fn generate(&self, request: &LlmRequest) -> anyhow::Result<LlmResponse> {
+            self.requests.lock().unwrap().push((
+                request.system_instruction.clone(),
+                request.output.is_json(),
+                request.messages[0]
+                    .media
+                    .iter()
+                    .map(|m| m.mime_type.clone())
+                    .collect(),
+            ));
+            Ok(LlmResponse {
+                text: self.text.to_string(),
+                provider: "transcribing".to_string(),
+                model: "transcribing-model".to_string(),
+                usage: None,
+                finish_reason: Some(FinishReason::Stop),
+                tool_calls: Vec::new(),
+            })
+        }
+    }
+
+    fn provider(text: &'static str) -> Arc<TranscribingProvider> {
+        Arc::new(TranscribingProvider {
+            text,
+            requests: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn wav() -> Attachment {
+        Attachment::audio(None, Bytes::from_static(WAV_BYTES), 1024).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_llm_transcriber_sends_audio() {
+        let provider = provider("  I finally finished the marathon!\n");
+        let transcriber = LlmTranscriber::new(provider.clone());
+
+        let transcript = transcriber.transcribe(&wav()).await.unwrap();
+        assert_eq!(transcript.text, "I finally finished the marathon!");
+        let trace = transcript.trace.unwrap();
+        assert_eq!(trace.template, Some("transcribe.md".to_string()));
+        assert_eq!(trace.outcome, ParseOutcome::Text);
+
+        let requests = provider.requests.lock().unwrap();
+        let (instruction, json, media) = &requests[0];
+        assert!(instruction.starts_with("Transcribe the attached voice note"));
+        assert!(!json);
+        assert_eq!(media, &vec!["audio/wav".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_llm_transcriber_rejects_silence() {
+        let transcriber = LlmTranscriber::new(provider("   "));
+        assert!(transcriber.transcribe(&wav()).await.is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use crate::dynamo::{use_db, USAGE_TABLE_NAME};
+use crate::tell::{get_user_tells, TellItem};
+use crate::traces::LlmTrace;
+use chrono::{Datelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::to_value;
+use std::fmt;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+/// Token counts reported by the provider for a single LLM call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub candidate_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl TokenUsage {
+    /// Adds up usage across several calls, e.g. retries of the same tell.
+    pub fn merge(a: Option<TokenUsage>, b: Option<TokenUsage>) -> Option<TokenUsage> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(TokenUsage {
+                prompt_tokens: a.prompt_tokens + b.prompt_tokens,
+                candidate_tokens: a.candidate_tokens + b.candidate_tokens,
+                total_tokens: a.total_tokens + b.total_tokens,
+            }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// USD price per million tokens.
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Known model prices. Models are matched by prefix, so versioned names such as
+/// `gemini-2.0-flash-001` resolve to their family; the longest matching prefix wins.
+const MODEL_PRICES: &[(&str, ModelPrice)] = &[
+    (
+        "gemini-2.0-flash",
+        ModelPrice {
+            input: 0.10,
+            output: 0.40,
+        },
+    ),
+    (
+        "gemini-2.0-flash-lite",
+        ModelPrice {
+            input: 0.075,
+            output: 0.30,
+        },
+    ),
+    (
+        "gemini-1.5-flash",
+        ModelPrice {
+            input: 0.075,
+            output: 0.30,
+        },
+    ),
+    (
+        "gemini-2.5-flash",
+        ModelPrice {
+            input: 0.30,
+            output: 2.50,
+        },
+    ),
+    (
+        "gemini-2.5-pro",
+        ModelPrice {
+            input: 1.25,
+            output: 10.00,
+        },
+    ),
+];
+
+pub fn price_for(model: &str) -> Option<&'static ModelPrice> {
+    MODEL_PRICES
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, price)| price)
+}
+
+/// Cost of a call in USD, or `None` for models without a known price (e.g. local models).
+pub fn cost_usd(model: &str, usage: &TokenUsage) -> Option<f64> {
+    price_for(model).map(|price| {
+        (usage.prompt_tokens as f64 * price.input + usage.candidate_tokens as f64 * price.output)
+            / 1_000_000.0
+    })
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct MonthlyUsage {
+    pub month: String,
+    pub tells: u32,
+    pub prompt_tokens: u64,
+    pub candidate_tokens: u64,
+    pub total_tokens: u64,
+    pub cost_usd: f64,
+}
+
+/// A calendar month in `YYYY-MM` form.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Month {
+    pub year: i32,
+    pub month: u32,
+}
+
+impl Month {
+    pub fn current() -> Self {
+        let now = Utc::now();
+        Self {
+            year: now.year(),
+            month: now.month(),
+        }
+    }
+
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let (year, month) = value
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("Month must be formatted as YYYY-MM"))?;
+        let year: i32 = year.parse()?;
+        let month: u32 = month.parse()?;
+        if !(1..=12).contains(&month) {
+            return Err(anyhow::anyhow!("Month must be between 01 and 12"));
+        }
+        Ok(Self { year, month })
+    }
+
+    fn contains(&self, date: &chrono::DateTime<Utc>) -> bool {
+        date.year() == self.year && date.month() == self.month
+    }
+}
+
+impl fmt::Display for Month {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}", self.year, self.month)
+    }
+}
+
+/// Usage of an LLM call made outside a tell, such as a memory rollup, stored in the
+/// `teal-usage` table so it counts towards the user's monthly totals and quota.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UsageEntry {
+    pub tid: String,
+    pub username: String,
+    /// What the call was for: its prompt file, e.g. `memory_rollup.md`.
+    pub source: String,
+    pub model: String,
+    pub usage: TokenUsage,
+    pub cost_usd: Option<f64>,
+    pub created_at: chrono::DateTime<Utc>,
+}
+
+impl UsageEntry {
+    pub fn new(username: &str, source: &str, model: &str, usage: TokenUsage) -> Self {
+        Self {
+            tid: Uuid::new_v4().to_string(),
+            username: username.to_string(),
+            source: source.to_string(),
+            model: model.to_string(),
+            usage,
+            cost_usd: cost_usd(model, &usage),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+/// Stores the usage of a call made outside a tell. Calls whose provider reported no usage are
+/// skipped. Like tracing, this is best-effort: failures are logged and the request carries on.
+async fn record_usage(username: &str, source: &str, model: &str, usage: Option<TokenUsage>) {
+    let Some(usage) = usage else {
+        return;
+    };
+    let item = match to_value(UsageEntry::new(username, source, model, usage)) {
+        Ok(item) => item,
+        Err(e) => {
+            eprintln!("Failed to serialize usage entry: {:?}", e);
+            return;
+        }
+    };
+    if let Err(e) = use_db().put(USAGE_TABLE_NAME, item).await {
+        eprintln!("Failed to store usage entry: {:?}", e);
+    }
+}
+
+/// Records the usage of traced calls that no stored tell accounts for, such as insights or the
+/// calls of a failed tell, under their prompt file.
+pub async fn record_trace_usage(username: &str, traces: &[LlmTrace]) {
+    for trace in traces {
+        let source = trace.template.as_deref().unwrap_or("unknown");
+        record_usage(username, source, &trace.model, trace.usage).await;
+    }
+}
+
+fn add(summary: &mut MonthlyUsage, usage: &TokenUsage, cost: Option<f64>) {
+    summary.prompt_tokens += usage.prompt_tokens as u64;
+    summary.candidate_tokens += usage.candidate_tokens as u64;
+    summary.total_tokens += usage.total_tokens as u64;
+    summary.cost_usd += cost.unwrap_or_default();
+}
+
+/// Sums the usage of every tell and every other call made in the given month.
+pub fn summarize_month(tells: &[TellItem], entries: &[UsageEntry], month: Month) -> MonthlyUsage {
+    let mut summary = MonthlyUsage {
+        month: month.to_string(),
+        ..Default::default()
+    };
+
+    for tell in tells.iter().filter(|t| month.contains(&t.created_at)) {
+        summary.tells += 1;
+        if let Some(usage) = &tell.usage {
+            add(&mut summary, usage, None);
+        }
+        summary.cost_usd += tell.cost_usd.unwrap_or_default();
+    }
+    for entry in entries.iter().filter(|e| month.contains(&e.created_at)) {
+        add(&mut summary, &entry.usage, entry.cost_usd);
+    }
+    summary
+}
+
+pub async fn get_monthly_usage(username: &str, month: Month) -> anyhow::Result<MonthlyUsage> {
+    let tells = get_user_tells(username).await?;
+    let entries: Vec<UsageEntry> = use_db()
+        .scan(USAGE_TABLE_NAME, "username", username)
+        .await?;
+    Ok(summarize_month(&tells, &entries, month))
+}
+
+static MONTHLY_TOKEN_QUOTA: OnceLock<Option<u64>> = OnceLock::new();
+
+/// Parses a `TEAL_MONTHLY_TOKEN_QUOTA` value; unset or blank means no quota.
+fn parse_quota(value: Option<&str>) -> anyhow::Result<Option<u64>> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Invalid TEAL_MONTHLY_TOKEN_QUOTA '{}': {}", value, e)),
+    }
+}
+
+/// Reads the per-user monthly token quota from `TEAL_MONTHLY_TOKEN_QUOTA`. An invalid value fails
+/// startup rather than leaving the quota off.
+pub fn initialize_quota() -> anyhow::Result<()> {
+    let quota = parse_quota(std::env::var("TEAL_MONTHLY_TOKEN_QUOTA").ok().as_deref())?;
+    match quota {
+        Some(quota) => println!("Monthly token quota: {} tokens per user", quota),
+        None => println!("No monthly token quota"),
+    }
+    MONTHLY_TOKEN_QUOTA.set(quota).ok();
+    Ok(())
+}
+
+/// Whether the user has used up their token quota for the current month.
+pub async fn is_over_quota(username: &str) -> anyhow::Result<bool> {
+    let Some(quota) = MONTHLY_TOKEN_QUOTA.get().copied().flatten() else {
+        return Ok(false);
+    };
+    let usage = get_monthly_usage(username, Month::current()).await?;
+    Ok(usage.total_tokens >= quota)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gemini::GeminiTellResponse;
+    use crate::tell::build_tell_record;
+    use chrono::TimeZone;
+
+    fn tell_at(year: i32, month: u32, usage: Option<TokenUsage>, cost: Option<f64>) -> TellItem {
+        let mut tell = build_tell_record(
+            "testuser",
+            "tell",
+            &GeminiTellResponse {
+                answer: "answer".to_string(),
+                summary: String::new(),
+                user_state: "state".to_string(),
+                mood: "calm".to_string(),
+            },
+        );
+        tell.created_at = Utc.with_ymd_and_hms(year, month, 15, 12, 0, 0).unwrap();
+        tell.usage = usage;
+        tell.cost_usd = cost;
+        tell
+    }
+
+    #[test]
+    fn test_price_for_matches_longest_prefix() {
+        assert_eq!(price_for("gemini-2.0-flash").unwrap().input, 0.10);
+        assert_eq!(price_for("gemini-2.0-flash-001").unwrap().input, 0.10);
+        assert_eq!(price_for("gemini-2.0-flash-lite").unwrap().input, 0.075);
+        assert!(price_for("llama-3.1-8b").is_none());
+    }
+
+    #[test]
+    fn test_cost_usd() {
+        let usage = TokenUsage {
+            prompt_tokens: 1_000_000,
+            candidate_tokens: 500_000,
+            total_tokens: 1_500_000,
+        };
+        let cost = cost_usd("gemini-2.0-flash", &usage).unwrap();
+        assert!((cost - 0.30).abs() < 1e-9);
+        assert!(cost_usd("local-model", &usage).is_none());
+    }
+
+    #[test]
+    fn test_parse_quota() {
+        assert_eq!(parse_quota(None).unwrap(), None);
+        assert_eq!(parse_quota(Some(" ")).unwrap(), None);
+        assert_eq!(parse_quota(Some("500000")).unwrap(), Some(500_000));
+        let err = parse_quota(Some("500k")).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Invalid TEAL_MONTHLY_TOKEN_QUOTA '500k'"));
+    }
+
+    #[test]
+    fn test_month_parse_and_display() {
+        let month = Month::parse("2025-03").unwrap();
+        assert_eq!(
+            month,
+            Month {
+                year: 2025,
+                month: 3
+            }
+        );
+        assert_eq!(month.to_string(), "2025-03");
+        assert!(Month::parse("2025-13").is_err());
+        assert!(Month::parse("March").is_err());
+    }
+
+    #[test]
+    fn test_summarize_month() {
+        let usage = TokenUsage {
+            prompt_tokens: 100,
+            candidate_tokens: 50,
+            total_tokens: 150,
+        };
+        let tells = vec![
+            tell_at(2025, 3, Some(usage), Some(0.01)),
+            tell_at(2025, 3, Some(usage), Some(0.02)),
+            tell_at(2025, 3, None, None),
+            tell_at(2025, 4, Some(usage), Some(0.04)),
+        ];
+
+        let mut rollup = UsageEntry::new("testuser", "memory_rollup.md", "local-model", usage);
+        rollup.created_at = Utc.with_ymd_and_hms(2025, 3, 20, 12, 0, 0).unwrap();
+        let mut suggestion = UsageEntry::new("testuser", "daily_suggestion.md", "model", usage);
+        suggestion.created_at = Utc.with_ymd_and_hms(2025, 4, 1, 12, 0, 0).unwrap();
+        suggestion.cost_usd = Some(0.08);
+
+        let summary = summarize_month(
+            &tells,
+            &[rollup, suggestion],
+            Month::parse("2025-03").unwrap(),
+        );
+        assert_eq!(summary.month, "2025-03");
+        assert_eq!(summary.tells, 3);
+        assert_eq!(summary.prompt_tokens, 300);
+        assert_eq!(summary.candidate_tokens, 150);
+        assert_eq!(summary.total_tokens, 450);
+        assert!((summary.cost_usd - 0.03).abs() < 1e-9);
+    }
+}
@@ -1,16 +1,24 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::to_value;
 
 use crate::dynamo::{use_db, USERS_TABLE_NAME};
+use crate::locale::Locale;
+use crate::prompts::Persona;
 
 // TODO: Storing OAuth2.0 credentials
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct User {
     pub tid: String,
     pub name: String,
     pub email: String,
     pub current_mood: Option<String>,
     pub created_at: String, // TODO: Use chrono::DateTime<Utc>
+    /// The voice Teal answers this user in. `None` means the default persona.
+    #[serde(default)]
+    pub persona: Option<Persona>,
+    /// The language Teal answers this user in. `None` means English.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<Locale>,
 }
 
 pub async fn create_user(data: &User) -> anyhow::Result<bool> {
@@ -18,6 +26,31 @@ pub async fn create_user(data: &User) -> anyhow::Result<bool> {
     db.put(USERS_TABLE_NAME, to_value(data)?).await
 }
 
+/// Looks up a user by name, which is what tells are keyed by.
+pub async fn get_user_by_name(name: &str) -> anyhow::Result<Option<User>> {
+    let db = use_db();
+    let users: Vec<User> = db.scan(USERS_TABLE_NAME, "name", name).await?;
+    Ok(users.into_iter().next())
+}
+
+/// Stores the user's chosen persona. Returns `false` if there is no such user.
+pub async fn set_user_persona(name: &str, persona: Persona) -> anyhow::Result<bool> {
+    let Some(mut user) = get_user_by_name(name).await? else {
+        return Ok(false);
+    };
+    user.persona = Some(persona);
+    create_user(&user).await
+}
+
+/// Stores the user's language. Returns `false` if there is no such user.
+pub async fn set_user_locale(name: &str, locale: Locale) -> anyhow::Result<bool> {
+    let Some(mut user) = get_user_by_name(name).await? else {
+        return Ok(false);
+    };
+    user.locale = Some(locale);
+    create_user(&user).await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -30,12 +63,16 @@ mod tests {
             email: "john@example.com".to_string(),
             current_mood: Some("happy".to_string()),
             created_at: "2024-01-01T00:00:00Z".to_string(),
+            persona: Some(Persona::StoicCoach),
+            locale: Some(Locale::parse("es").unwrap()),
         };
 
         let json = serde_json::to_string(&user).unwrap();
         assert!(json.contains("John Doe"));
+        assert!(json.contains("stoic_coach"));
         assert!(json.contains("john@example.com"));
         assert!(json.contains("happy"));
+        assert!(json.contains("\"locale\":\"es\""));
     }
 
     #[test]
@@ -46,10 +83,27 @@ mod tests {
             email: "jane@test.com".to_string(),
             current_mood: None,
             created_at: "2024-01-01T00:00:00Z".to_string(),
+            persona: None,
+            locale: None,
         };
 
         let json = serde_json::to_string(&user).unwrap();
         assert!(json.contains("Jane"));
         assert!(json.contains("null") || !json.contains("current_mood"));
     }
+
+    #[test]
+    fn test_user_without_persona_deserializes() {
+        let json = r#"{
+            "tid": "123",
+            "name": "Jane",
+            "email": "jane@test.com",
+            "current_mood": null,
+            "created_at": "2024-01-01T00:00:00Z"
+        }"#;
+
+        let user: User = serde_json::from_str(json).unwrap();
+        assert_eq!(user.persona, None);
+        assert_eq!(user.locale, None);
+    }
 }